@@ -1,152 +1,368 @@
 use anyhow::Result;
-use yahoo_finance_api::YahooConnector;
+use serde::{Deserialize, Serialize};
 
-use crate::safe_money::USD;
+use crate::price_source::PriceSource;
+
+/// Kind of asset, which decides the price source it is looked up on.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum AssetClass {
+    #[default]
+    Stock,
+    Crypto,
+}
+
+impl std::fmt::Display for AssetClass {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            AssetClass::Stock => write!(f, "stock"),
+            AssetClass::Crypto => write!(f, "crypto"),
+        }
+    }
+}
+
+/// Quantities an asset can be traded in.
+#[derive(Debug, Clone, Copy, PartialEq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum QuantityRule {
+    /// Any quantity.
+    #[default]
+    Fractional,
+    /// Whole units only.
+    WholeShares,
+    /// Multiples of a lot, e.g. 100 shares.
+    Lot(f64),
+    /// Quantities with at most this many decimals, e.g. 8 for bitcoin.
+    Decimals(u32),
+}
+
+impl QuantityRule {
+    /// Smallest quantity that can be traded, none when any quantity can.
+    pub fn step(&self) -> Option<f64> {
+        match self {
+            QuantityRule::Fractional => None,
+            QuantityRule::WholeShares => Some(1.0),
+            QuantityRule::Lot(size) => Some(*size),
+            QuantityRule::Decimals(decimals) => Some(10f64.powi(-(*decimals as i32))),
+        }
+    }
+
+    /// Whether `quantity` can be traded.
+    pub fn allows(&self, quantity: f64) -> bool {
+        match self.step() {
+            Some(step) => {
+                let steps = quantity / step;
+                // past a billion steps a float can't get closer than a few ulps
+                let tolerance = (steps.abs() * f64::EPSILON * 4.0).max(1e-6);
+                (steps - steps.round()).abs() < tolerance
+            }
+            None => true,
+        }
+    }
+
+    /// Largest tradable quantity not above `quantity`, which may be negative.
+    pub fn round_down(&self, quantity: f64) -> f64 {
+        match self.step() {
+            Some(step) => {
+                // a few ulps of nudge keep 0.3 / 0.1 = 2.9999999999999996 from
+                // rounding down to 2 steps, the clamp keeps 3 steps of 0.1 from
+                // coming out above 0.3
+                let steps = quantity / step;
+                let nudge = steps.abs() * f64::EPSILON * 4.0;
+                ((steps + nudge).floor() * step).min(quantity)
+            }
+            None => quantity,
+        }
+    }
+}
+
+impl std::fmt::Display for QuantityRule {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            QuantityRule::Fractional => write!(f, "any quantity"),
+            QuantityRule::WholeShares => write!(f, "whole shares"),
+            QuantityRule::Lot(size) => write!(f, "lots of {}", size),
+            QuantityRule::Decimals(decimals) => write!(f, "{} decimals", decimals),
+        }
+    }
+}
 
 pub trait Asset {
-    fn last_price(&self) -> USD;
+    /// Last traded price, in units of `currency()`.
+    fn last_price(&self) -> f64;
+    fn set_last_price(&mut self, price: f64);
+    /// Code of the currency the asset is quoted in, e.g. "USD" or "EUR".
+    fn currency(&self) -> String;
     fn amount_held(&self) -> f64;
+    fn set_amount_held(&mut self, amount: f64);
     fn ticker(&self) -> String;
+    fn class(&self) -> AssetClass;
+    fn quantity_rule(&self) -> QuantityRule;
+    fn set_quantity_rule(&mut self, rule: QuantityRule);
+
+    /// Id the price source knows the asset by, the ticker unless overridden.
+    fn price_id(&self) -> String {
+        self.ticker()
+    }
+
+    /// Value of the holding at the last price, in units of `currency()`.
+    fn market_value(&self) -> f64 {
+        self.last_price() * self.amount_held()
+    }
 }
 
 impl Asset for Stock {
-    fn last_price(&self) -> USD {
+    fn last_price(&self) -> f64 {
         self.last_price
     }
+    fn set_last_price(&mut self, price: f64) {
+        self.last_price = price;
+    }
+    fn currency(&self) -> String {
+        self.currency.clone()
+    }
     fn amount_held(&self) -> f64 {
         self.amount_held
     }
+    fn set_amount_held(&mut self, amount: f64) {
+        self.amount_held = amount;
+    }
     fn ticker(&self) -> String {
         self.ticker.clone()
     }
+    fn class(&self) -> AssetClass {
+        AssetClass::Stock
+    }
+    fn quantity_rule(&self) -> QuantityRule {
+        self.quantity_rule
+    }
+    fn set_quantity_rule(&mut self, rule: QuantityRule) {
+        self.quantity_rule = rule;
+    }
 }
 
 impl Asset for Crypto {
-    fn last_price(&self) -> USD {
-        USD::new(self.last_price)
+    fn last_price(&self) -> f64 {
+        self.last_price
+    }
+    fn set_last_price(&mut self, price: f64) {
+        self.last_price = price;
+    }
+    fn currency(&self) -> String {
+        self.currency.clone()
     }
     fn amount_held(&self) -> f64 {
         self.amount_held
     }
+    fn set_amount_held(&mut self, amount: f64) {
+        self.amount_held = amount;
+    }
     fn ticker(&self) -> String {
         self.token.clone()
     }
+    fn class(&self) -> AssetClass {
+        AssetClass::Crypto
+    }
+    fn quantity_rule(&self) -> QuantityRule {
+        self.quantity_rule
+    }
+    fn set_quantity_rule(&mut self, rule: QuantityRule) {
+        self.quantity_rule = rule;
+    }
+    fn price_id(&self) -> String {
+        self.name.clone()
+    }
+}
+
+/// A holding of any kind of asset, so the portfolio can keep them in one list.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "class", rename_all = "lowercase")]
+pub enum Position {
+    Stock(Stock),
+    Crypto(Crypto),
+}
+
+impl Position {
+    pub fn asset(&self) -> &dyn Asset {
+        match self {
+            Position::Stock(stock) => stock,
+            Position::Crypto(crypto) => crypto,
+        }
+    }
+
+    pub fn asset_mut(&mut self) -> &mut dyn Asset {
+        match self {
+            Position::Stock(stock) => stock,
+            Position::Crypto(crypto) => crypto,
+        }
+    }
+
+    /// Updates the last price from `source`, which must know the asset by `price_id()`.
+    pub async fn fetch_price(&mut self, source: &dyn PriceSource) -> Result<()> {
+        let price = source.latest_quote(&self.price_id()).await?.close;
+        self.set_last_price(price);
+        Ok(())
+    }
+}
+
+impl From<Stock> for Position {
+    fn from(stock: Stock) -> Self {
+        Position::Stock(stock)
+    }
 }
+
+impl From<Crypto> for Position {
+    fn from(crypto: Crypto) -> Self {
+        Position::Crypto(crypto)
+    }
+}
+
+impl Asset for Position {
+    fn last_price(&self) -> f64 {
+        self.asset().last_price()
+    }
+    fn set_last_price(&mut self, price: f64) {
+        self.asset_mut().set_last_price(price)
+    }
+    fn currency(&self) -> String {
+        self.asset().currency()
+    }
+    fn amount_held(&self) -> f64 {
+        self.asset().amount_held()
+    }
+    fn set_amount_held(&mut self, amount: f64) {
+        self.asset_mut().set_amount_held(amount)
+    }
+    fn ticker(&self) -> String {
+        self.asset().ticker()
+    }
+    fn class(&self) -> AssetClass {
+        self.asset().class()
+    }
+    fn quantity_rule(&self) -> QuantityRule {
+        self.asset().quantity_rule()
+    }
+    fn set_quantity_rule(&mut self, rule: QuantityRule) {
+        self.asset_mut().set_quantity_rule(rule)
+    }
+    fn price_id(&self) -> String {
+        self.asset().price_id()
+    }
+}
+#[derive(Clone, PartialEq, Serialize, Deserialize)]
 pub struct Stock {
     pub ticker: String,
     pub amount_held: f64,
-    pub client: YahooConnector,
-    pub last_price: USD,
-    pub name: String,
+    // in units of `currency`
+    pub last_price: f64,
+    pub currency: String,
+    #[serde(default)]
+    pub quantity_rule: QuantityRule,
 }
 
 impl std::fmt::Debug for Stock {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         write!(
             f,
-            "Asset {{ ticker: {}, name: {}, last_price: {} }}",
-            self.ticker, self.name, self.last_price
+            "Asset {{ ticker: {}, last_price: {} {} }}",
+            self.ticker, self.last_price, self.currency
         )
     }
 }
 
 #[allow(dead_code)]
 impl Stock {
-    pub async fn new(ticker: &str, ammount: f64) -> Result<Self> {
-        let client = YahooConnector::new();
-        let res = client.get_latest_quotes(ticker, "1d").await?;
-        let currency = res.metadata().unwrap().currency;
-        let last_price = USD::new(res.last_quote()?.close);
+    pub async fn new(ticker: &str, ammount: f64, source: &dyn PriceSource) -> Result<Self> {
+        let currency = source.metadata(ticker).await?.currency;
+        let last_price = source.latest_quote(ticker).await?.close;
 
         Ok(Self {
             amount_held: ammount,
             ticker: ticker.to_string(),
-            client,
-            name: currency,
             last_price,
+            currency,
+            quantity_rule: QuantityRule::Fractional,
         })
     }
 
     #[allow(dead_code)]
-    pub async fn fetch_price(&mut self) -> Result<()> {
-        let res = self.client.get_latest_quotes(&self.ticker, "1d").await?;
-        self.last_price = USD::new(res.last_quote()?.close);
+    pub async fn fetch_price(&mut self, source: &dyn PriceSource) -> Result<()> {
+        self.last_price = source.latest_quote(&self.ticker).await?.close;
         Ok(())
     }
 }
 
+#[derive(Clone, PartialEq, Serialize, Deserialize)]
 pub struct Crypto {
     pub name: String,
     pub amount_held: f64,
     pub last_price: f64,
     pub token: String,
+    pub currency: String,
+    #[serde(default)]
+    pub quantity_rule: QuantityRule,
 }
 
 impl std::fmt::Debug for Crypto {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         write!(
             f,
-            "{{ name: {}, last_price: {} }}",
-            self.name, self.last_price
+            "{{ name: {}, last_price: {} {} }}",
+            self.name, self.last_price, self.currency
         )
     }
 }
 
 #[allow(dead_code)]
 impl Crypto {
-    pub async fn new(name: &str, token: &str, ammount: f64) -> Result<Self> {
+    pub async fn new(
+        name: &str,
+        token: &str,
+        ammount: f64,
+        source: &dyn PriceSource,
+    ) -> Result<Self> {
         let mut s = Self {
             name: name.to_owned(),
             amount_held: ammount,
             last_price: 0.0,
             token: token.to_owned(),
+            currency: source.metadata(name).await?.currency,
+            quantity_rule: QuantityRule::Fractional,
         };
 
-        let last_price = s.fetch_price().await?;
+        let last_price = s.fetch_price(source).await?;
 
         Ok(Self { last_price, ..s })
     }
 
-    pub async fn fetch_price(&mut self) -> Result<f64> {
-        let res_owned_name = self.name.clone();
-        let res_owned_name_clone = res_owned_name.clone();
-        let res = tokio::task::spawn_blocking(move || {
-            rust_gecko::simple::price(vec![&res_owned_name], vec!["usd"], None, None, None, None)
-        })
-        .await?;
-        match res.json {
-            Some(json) => {
-                let eth_price = json
-                    .get(res_owned_name_clone)
-                    .and_then(|eth| eth.get("usd"))
-                    .unwrap();
-                let eth_price = eth_price.as_f64().unwrap_or(0.0);
-                Ok(eth_price)
-            }
-            None => Err(anyhow::Error::msg("No data received")),
-        }
+    // `name` is the id the crypto source knows the coin by
+    pub async fn fetch_price(&mut self, source: &dyn PriceSource) -> Result<f64> {
+        self.last_price = source.latest_quote(&self.name).await?.close;
+        Ok(self.last_price)
     }
 }
-pub async fn get_historical_daily_prices(number_of_days: i64, id: &str) -> Result<Vec<f64>> {
-    let id = id.to_string(); // Clone id here
-    let eth_historical_price = tokio::task::spawn_blocking(move || {
-        rust_gecko::coins::market_chart(
-            &id,
-            "usd",
-            (number_of_days - 1).to_string().as_str(),
-            Some("daily"),
-        )
-    })
-    .await?;
-    let json = eth_historical_price.json.clone().unwrap();
-    // can also parse the daily market caps and total volumes from this repsonse
-    let prices: Vec<_> = json
-        .get("prices")
-        .unwrap()
-        .as_array()
-        .unwrap()
-        .iter()
-        .map(|x| x.as_array().unwrap()[1].as_f64().unwrap())
-        .collect();
-    Ok(prices)
+pub async fn get_historical_daily_prices(
+    number_of_days: i64,
+    id: &str,
+    source: &dyn PriceSource,
+) -> Result<Vec<f64>> {
+    let history = source.historical_daily(id, number_of_days).await?;
+    Ok(history.into_iter().map(|q| q.close).collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_quantity_rules() {
+        let cents = QuantityRule::Decimals(2);
+        assert!(cents.allows(0.07));
+        assert!(!cents.allows(0.075));
+        assert_eq!(QuantityRule::WholeShares.round_down(2.9999999999), 2.0);
+        assert_eq!(QuantityRule::WholeShares.round_down(-1.5), -2.0);
+        assert_eq!(QuantityRule::Decimals(1).round_down(0.3), 0.3);
+        assert_eq!(QuantityRule::Lot(100.0).round_down(250.0), 200.0);
+        assert_eq!(QuantityRule::Fractional.round_down(0.3), 0.3);
+    }
 }
@@ -0,0 +1,665 @@
+use std::io::Write;
+use std::path::PathBuf;
+use std::sync::Arc;
+
+use anyhow::Result;
+use clap::{Args, Parser, Subcommand, ValueEnum};
+use serde::Serialize;
+
+use crate::fixture_source::{FixtureSource, RecordingSource};
+use crate::portfolio::{Portfolio, PortfolioBuilder};
+use crate::price_source::{CoinGeckoSource, PriceSource, Quote, YahooSource};
+use crate::rebalance::{Reason, RebalancePlan, Side};
+use crate::safe_money::{Currency, Discrete, USD};
+use crate::snapshot::PortfolioSnapshot;
+
+/// Exit code when everything went fine.
+pub const EXIT_OK: u8 = 0;
+/// Exit code for errors such as an invalid config or a price that can't be fetched.
+pub const EXIT_ERROR: u8 = 1;
+/// Exit code for bad arguments, reported by clap.
+pub const EXIT_USAGE: u8 = 2;
+/// Exit code of `drift`, `plan` and `rebalance --dry-run` when trades are due.
+pub const EXIT_REBALANCE_NEEDED: u8 = 3;
+
+/// Portfolio rebalancing with paper trades.
+#[derive(Debug, Parser)]
+#[command(name = "beta_balancing", version)]
+pub struct Cli {
+    #[command(flatten)]
+    pub options: Options,
+    #[command(subcommand)]
+    pub command: Command,
+}
+
+#[derive(Debug, Args)]
+pub struct Options {
+    /// Portfolio definition.
+    #[arg(long, global = true, default_value = "portfolio.toml")]
+    pub config: PathBuf,
+    /// Saved portfolio state, used instead of the config when the file exists.
+    ///
+    /// `rebalance --apply` writes the result back to it.
+    #[arg(long, global = true)]
+    pub state: Option<PathBuf>,
+    #[arg(long, global = true, value_enum, default_value_t = Format::Table)]
+    pub format: Format,
+    /// Read prices from the fixtures directory instead of the network.
+    #[arg(long, global = true)]
+    pub offline: bool,
+    /// Where `--offline` reads prices from.
+    #[arg(long, global = true, default_value = "fixtures")]
+    pub fixtures: PathBuf,
+    /// Save every price fetched from the network into this directory.
+    #[arg(long, global = true, conflicts_with = "offline")]
+    pub record: Option<PathBuf>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum Format {
+    Table,
+    Json,
+    Csv,
+}
+
+#[derive(Debug, Subcommand)]
+pub enum Command {
+    /// Positions, weights and value.
+    Show,
+    /// Actual weights against target weights.
+    Drift,
+    /// Trades a rebalance would make, without making them.
+    Plan,
+    /// Rebalance the portfolio with paper trades.
+    Rebalance {
+        /// Only show the positions a rebalance would leave (the default).
+        #[arg(long, conflicts_with = "apply")]
+        dry_run: bool,
+        /// Make the trades, and save the result to `--state` if given.
+        #[arg(long)]
+        apply: bool,
+    },
+    /// Daily closes of a ticker.
+    History {
+        ticker: String,
+        #[arg(long, default_value_t = 30)]
+        days: i64,
+        /// Look the ticker up as a coin id on the crypto price source.
+        #[arg(long)]
+        crypto: bool,
+    },
+    /// Replay past prices, holding against rebalancing every few days.
+    ///
+    /// FX rates stay at their current values.
+    Backtest {
+        #[arg(long, default_value_t = 90)]
+        days: i64,
+        /// Days between checks of the rebalance rule.
+        #[arg(long, default_value_t = 30)]
+        every: usize,
+    },
+}
+
+#[derive(Debug, Serialize)]
+struct PositionRow {
+    ticker: String,
+    class: String,
+    quantity: f64,
+    price: f64,
+    currency: String,
+    value: Discrete<USD>,
+    weight: f64,
+}
+
+#[derive(Debug, Serialize)]
+struct DriftRow {
+    ticker: String,
+    target: f64,
+    actual: f64,
+    drift: f64,
+    outside_band: bool,
+}
+
+#[derive(Debug, Serialize)]
+struct TradeRow {
+    ticker: String,
+    side: Side,
+    quantity: f64,
+    price: f64,
+    notional: Discrete<USD>,
+    cost: Discrete<USD>,
+    reason: Reason,
+    pre_weight: f64,
+    post_weight: f64,
+}
+
+#[derive(Debug, Serialize)]
+struct BacktestRow {
+    timestamp: i64,
+    buy_and_hold: Discrete<USD>,
+    rebalanced: Discrete<USD>,
+}
+
+/// Runs the command and returns the process exit code.
+pub async fn run(cli: &Cli, out: &mut dyn Write) -> Result<u8> {
+    let options = &cli.options;
+    match &cli.command {
+        Command::Show => {
+            let mut portfolio = load_portfolio(options).await?;
+            write_rows(&position_rows(&mut portfolio)?, options.format, out)?;
+            Ok(EXIT_OK)
+        }
+        Command::Drift => {
+            let mut portfolio = load_portfolio(options).await?;
+            let rows = drift_rows(&mut portfolio)?;
+            write_rows(&rows, options.format, out)?;
+            Ok(exit_code(rows.iter().any(|row| row.outside_band)))
+        }
+        Command::Plan => {
+            let portfolio = load_portfolio(options).await?;
+            if !portfolio.should_rebalance(portfolio.now()?)? {
+                write_rows::<TradeRow>(&[], options.format, out)?;
+                return Ok(EXIT_OK);
+            }
+            let plan = portfolio.plan_rebalance()?;
+            write_rows(&trade_rows(&portfolio, &plan)?, options.format, out)?;
+            if options.format == Format::Table && !plan.is_empty() {
+                write!(
+                    out,
+                    "turnover {}, cash left {}",
+                    plan.summary.turnover, plan.summary.cash_residual
+                )?;
+                if portfolio.tax_policy.is_some() {
+                    write!(out, ", estimated tax {}", plan.summary.estimated_tax)?;
+                }
+                writeln!(out)?;
+            }
+            Ok(exit_code(!plan.is_empty()))
+        }
+        Command::Rebalance { apply, .. } => {
+            let mut portfolio = load_portfolio(options).await?;
+            let trades_due = !portfolio.rebalance_at(portfolio.now()?)?.is_empty();
+            write_rows(&position_rows(&mut portfolio)?, options.format, out)?;
+            if !apply {
+                return Ok(exit_code(trades_due));
+            }
+            if let Some(path) = &options.state {
+                portfolio.snapshot().save(path)?;
+            }
+            Ok(EXIT_OK)
+        }
+        Command::History {
+            ticker,
+            days,
+            crypto,
+        } => {
+            let (stock_source, crypto_source) = sources(options);
+            let source = if *crypto { crypto_source } else { stock_source };
+            let quotes: Vec<Quote> = source.historical_daily(ticker, *days).await?;
+            write_rows(&quotes, options.format, out)?;
+            Ok(EXIT_OK)
+        }
+        Command::Backtest { days, every } => {
+            let portfolio = load_portfolio(options).await?;
+            write_rows(
+                &backtest(portfolio, *days, *every).await?,
+                options.format,
+                out,
+            )?;
+            Ok(EXIT_OK)
+        }
+    }
+}
+
+fn exit_code(rebalance_needed: bool) -> u8 {
+    if rebalance_needed {
+        EXIT_REBALANCE_NEEDED
+    } else {
+        EXIT_OK
+    }
+}
+
+// (stock source, crypto source) picked by `--offline` and `--record`
+fn sources(options: &Options) -> (Arc<dyn PriceSource>, Arc<dyn PriceSource>) {
+    let stocks: Arc<dyn PriceSource> = Arc::new(YahooSource::new());
+    let crypto: Arc<dyn PriceSource> = Arc::new(CoinGeckoSource::default());
+    if options.offline {
+        let source: Arc<dyn PriceSource> = Arc::new(FixtureSource::new(&options.fixtures));
+        (source.clone(), source)
+    } else if let Some(dir) = &options.record {
+        (
+            Arc::new(RecordingSource::new(stocks, dir)),
+            Arc::new(RecordingSource::new(crypto, dir)),
+        )
+    } else {
+        (stocks, crypto)
+    }
+}
+
+/// The saved state if `--state` exists, the config otherwise, at current prices.
+async fn load_portfolio(options: &Options) -> Result<Portfolio> {
+    let (stock_source, crypto_source) = sources(options);
+    match &options.state {
+        Some(path) if path.exists() => {
+            let mut portfolio = Portfolio::from_snapshot(PortfolioSnapshot::load(path)?)?;
+            portfolio.stock_source = stock_source;
+            portfolio.crypto_source = crypto_source;
+            portfolio.update_prices().await?;
+            Ok(portfolio)
+        }
+        _ => {
+            PortfolioBuilder::from_config(&options.config)?
+                .stock_source(stock_source)
+                .crypto_source(crypto_source)
+                .build()
+                .await
+        }
+    }
+}
+
+fn position_rows(portfolio: &mut Portfolio) -> Result<Vec<PositionRow>> {
+    let weights = portfolio.get_actual_weights()?;
+    let mut rows = Vec::new();
+    for asset in portfolio.assets() {
+        rows.push(PositionRow {
+            ticker: asset.ticker(),
+            class: asset.class().to_string(),
+            quantity: asset.amount_held(),
+            price: asset.last_price(),
+            currency: asset.currency(),
+            value: portfolio.position_value_in::<USD>(asset)?,
+            weight: weights[&asset.ticker()],
+        });
+    }
+    rows.push(PositionRow {
+        ticker: "CASH".to_string(),
+        class: "cash".to_string(),
+        quantity: portfolio.cash.to_f64(),
+        price: 1.0,
+        currency: USD::symbol().to_string(),
+        value: portfolio.cash,
+        weight: weights["CASH"],
+    });
+    rows.push(PositionRow {
+        ticker: "TOTAL".to_string(),
+        class: String::new(),
+        quantity: 0.0,
+        price: 0.0,
+        currency: USD::symbol().to_string(),
+        value: portfolio.get_portfolio_value()?,
+        weight: 1.0,
+    });
+    Ok(rows)
+}
+
+fn drift_rows(portfolio: &mut Portfolio) -> Result<Vec<DriftRow>> {
+    let actual = portfolio.get_actual_weights()?;
+    let bands = portfolio.rebalance_type.bands();
+
+    let mut tickers: Vec<&String> = portfolio.target_weights.keys().collect();
+    tickers.extend(
+        actual
+            .keys()
+            .filter(|t| !portfolio.target_weights.contains_key(*t)),
+    );
+    tickers.sort();
+
+    Ok(tickers
+        .into_iter()
+        .map(|ticker| {
+            let target = portfolio.target_weights.get(ticker).copied().unwrap_or(0.0);
+            let actual = actual.get(ticker).copied().unwrap_or(0.0);
+            let drift = actual - target;
+            DriftRow {
+                ticker: ticker.clone(),
+                target,
+                actual,
+                drift,
+                // tickers without a target are left alone by the rebalancer
+                outside_band: portfolio.target_weights.contains_key(ticker)
+                    && drift.abs() > bands.map_or(0.0, |b| b.width(ticker, target)) + 1e-9,
+            }
+        })
+        .collect())
+}
+
+fn trade_rows(portfolio: &Portfolio, plan: &RebalancePlan) -> Result<Vec<TradeRow>> {
+    let mut rows = Vec::new();
+    for order in &plan.orders {
+        let price = portfolio.price_in::<USD>(portfolio.position(&order.ticker)?)?;
+        rows.push(TradeRow {
+            ticker: order.ticker.clone(),
+            side: order.side,
+            quantity: order.quantity,
+            price: price.amount(),
+            notional: order.notional,
+            cost: order.cost,
+            reason: order.reason,
+            pre_weight: order.pre_weight,
+            post_weight: order.post_weight,
+        });
+    }
+    Ok(rows)
+}
+
+async fn backtest(portfolio: Portfolio, days: i64, every: usize) -> Result<Vec<BacktestRow>> {
+    if every == 0 {
+        return Err(anyhow::Error::msg(
+            "Rebalance interval must be at least a day",
+        ));
+    }
+    // histories in position order
+    let mut histories = Vec::new();
+    for asset in portfolio.assets() {
+        let source = portfolio.source_for(asset.class());
+        histories.push(source.historical_daily(&asset.price_id(), days).await?);
+    }
+    // align the histories on their most recent day
+    let length = histories.iter().map(|h| h.len()).min().unwrap_or(0);
+    let histories: Vec<&[Quote]> = histories.iter().map(|h| &h[h.len() - length..]).collect();
+
+    let mut held = portfolio.clone();
+    let mut rebalanced = portfolio;
+    // the schedule restarts with the replay
+    rebalanced.last_rebalanced = None;
+    let mut rows = Vec::new();
+    let tickers: Vec<String> = held.assets().map(|asset| asset.ticker()).collect();
+    for day in 0..length {
+        let timestamp = histories.first().map_or(0, |h| h[day].timestamp);
+        for p in [&mut held, &mut rebalanced] {
+            p.clock = Some(timestamp);
+            for (ticker, history) in tickers.iter().zip(&histories) {
+                p.set_price(ticker, history[day].close)?;
+            }
+        }
+        if day > 0 && day.is_multiple_of(every) {
+            rebalanced.rebalance_at(timestamp)?;
+        }
+        rows.push(BacktestRow {
+            timestamp,
+            buy_and_hold: held.get_portfolio_value()?,
+            rebalanced: rebalanced.get_portfolio_value()?,
+        });
+    }
+    Ok(rows)
+}
+
+fn write_rows<T: Serialize>(rows: &[T], format: Format, out: &mut dyn Write) -> Result<()> {
+    match format {
+        Format::Json => {
+            serde_json::to_writer_pretty(&mut *out, rows)?;
+            writeln!(out)?;
+        }
+        Format::Csv => {
+            let mut writer = csv::Writer::from_writer(out);
+            for row in rows {
+                writer.serialize(row)?;
+            }
+            writer.flush()?;
+        }
+        Format::Table => write_table(rows, out)?,
+    }
+    Ok(())
+}
+
+// lays the csv records out in aligned columns, numbers right aligned to four decimals
+fn write_table<T: Serialize>(rows: &[T], out: &mut dyn Write) -> Result<()> {
+    if rows.is_empty() {
+        writeln!(out, "(none)")?;
+        return Ok(());
+    }
+    let mut writer = csv::Writer::from_writer(Vec::new());
+    for row in rows {
+        writer.serialize(row)?;
+    }
+    let csv = writer.into_inner()?;
+    let mut reader = csv::ReaderBuilder::new()
+        .has_headers(false)
+        .from_reader(csv.as_slice());
+    let mut cells: Vec<Vec<(String, bool)>> = Vec::new();
+    for (i, record) in reader.records().enumerate() {
+        cells.push(record?.iter().map(|cell| table_cell(cell, i > 0)).collect());
+    }
+
+    let columns = cells[0].len();
+    let widths: Vec<usize> = (0..columns)
+        .map(|c| {
+            cells
+                .iter()
+                .map(|row| row[c].0.chars().count())
+                .max()
+                .unwrap_or(0)
+        })
+        .collect();
+    for row in &cells {
+        let line: Vec<String> = row
+            .iter()
+            .zip(&widths)
+            .map(|((cell, numeric), width)| {
+                if *numeric {
+                    format!("{:>width$}", cell, width = width)
+                } else {
+                    format!("{:<width$}", cell, width = width)
+                }
+            })
+            .collect();
+        writeln!(out, "{}", line.join("  ").trim_end())?;
+    }
+    Ok(())
+}
+
+fn table_cell(cell: &str, is_value: bool) -> (String, bool) {
+    match cell.parse::<f64>() {
+        Ok(number) if is_value => match cell.split_once('.') {
+            Some((_, decimals)) if decimals.len() > 4 => (format!("{:.4}", number), true),
+            _ => (cell.to_string(), true),
+        },
+        _ => (cell.to_string(), false),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::*;
+
+    // config and fixtures for a two stock portfolio, AAA at 100 (50 the day before) and BBB at 300
+    fn setup(target_aaa: f64) -> tempfile::TempDir {
+        let dir = tempfile::tempdir().unwrap();
+        fs::write(
+            dir.path().join("portfolio.toml"),
+            format!(
+                r#"
+                [rebalance]
+                type = "threshold"
+                threshold = 0.05
+
+                [target_weights]
+                AAA = {}
+                BBB = {}
+
+                [[accounts]]
+                name = "test"
+                holdings = [{{ ticker = "AAA", amount = 3.0 }}, {{ ticker = "BBB", amount = 1.0 }}]
+                "#,
+                target_aaa,
+                1.0 - target_aaa
+            ),
+        )
+        .unwrap();
+        let fixtures = dir.path().join("fixtures");
+        fs::create_dir(&fixtures).unwrap();
+        fs::write(
+            fixtures.join("AAA.csv"),
+            "ticker,timestamp,close\nAAA,1,100.0\nAAA,2,50.0\nAAA,3,100.0\n",
+        )
+        .unwrap();
+        fs::write(
+            fixtures.join("BBB.csv"),
+            "ticker,timestamp,close\nBBB,1,300.0\nBBB,2,300.0\nBBB,3,300.0\n",
+        )
+        .unwrap();
+        dir
+    }
+
+    async fn run_args(dir: &tempfile::TempDir, args: &[&str]) -> (u8, String) {
+        let config = dir.path().join("portfolio.toml");
+        let fixtures = dir.path().join("fixtures");
+        let mut argv = vec![
+            "beta_balancing",
+            "--offline",
+            "--config",
+            config.to_str().unwrap(),
+            "--fixtures",
+            fixtures.to_str().unwrap(),
+        ];
+        argv.extend_from_slice(args);
+        let cli = Cli::try_parse_from(argv).unwrap();
+        let mut out = Vec::new();
+        let code = run(&cli, &mut out).await.unwrap();
+        (code, String::from_utf8(out).unwrap())
+    }
+
+    #[test]
+    fn test_parse_arguments() {
+        let cli = Cli::try_parse_from(["beta_balancing", "drift", "--format", "json"]).unwrap();
+        assert!(matches!(cli.command, Command::Drift));
+        assert_eq!(cli.options.format, Format::Json);
+        assert_eq!(cli.options.config, PathBuf::from("portfolio.toml"));
+
+        for bad in [
+            vec!["beta_balancing"],
+            vec!["beta_balancing", "show", "--format", "xml"],
+            vec!["beta_balancing", "rebalance", "--dry-run", "--apply"],
+            vec!["beta_balancing", "show", "--offline", "--record", "dir"],
+        ] {
+            let err = Cli::try_parse_from(&bad).unwrap_err();
+            assert_eq!(err.exit_code(), EXIT_USAGE as i32, "{:?}", bad);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_show() {
+        let dir = setup(0.5);
+        let (code, out) = run_args(&dir, &["show", "--format", "csv"]).await;
+        assert_eq!(code, EXIT_OK);
+        let lines: Vec<&str> = out.lines().collect();
+        assert_eq!(
+            lines[0],
+            "ticker,class,quantity,price,currency,value,weight"
+        );
+        assert_eq!(lines[1], "AAA,stock,3.0,100.0,USD,300.00 USD,0.5");
+        assert_eq!(lines[4], "TOTAL,,0.0,0.0,USD,600.00 USD,1.0");
+    }
+
+    #[tokio::test]
+    async fn test_drift_exit_code() {
+        let (code, out) = run_args(&setup(0.5), &["drift"]).await;
+        assert_eq!(code, EXIT_OK, "{}", out);
+        assert!(out.starts_with("ticker"));
+
+        let (code, out) = run_args(&setup(0.8), &["drift", "--format", "json"]).await;
+        assert_eq!(code, EXIT_REBALANCE_NEEDED);
+        let rows: serde_json::Value = serde_json::from_str(&out).unwrap();
+        assert_eq!(rows[0]["ticker"], "AAA");
+        assert_eq!(rows[0]["outside_band"], true);
+    }
+
+    #[tokio::test]
+    async fn test_plan_does_not_trade() {
+        let dir = setup(0.8);
+        let (code, out) = run_args(&dir, &["plan", "--format", "json"]).await;
+        assert_eq!(code, EXIT_REBALANCE_NEEDED);
+        let rows: serde_json::Value = serde_json::from_str(&out).unwrap();
+        // sells come first
+        assert_eq!(rows[0]["ticker"], "BBB");
+        assert_eq!(rows[0]["side"], "sell");
+        assert_eq!(rows[0]["reason"], "overweight");
+        assert_eq!(rows[1]["ticker"], "AAA");
+        assert_eq!(rows[1]["side"], "buy");
+        assert_eq!(rows[1]["notional"], "180.00 USD");
+        assert_eq!(rows[1]["post_weight"], 0.8);
+
+        let (_, out) = run_args(&dir, &["plan"]).await;
+        assert!(
+            out.ends_with("turnover 360.00 USD, cash left 0.00 USD\n"),
+            "{}",
+            out
+        );
+
+        let (code, out) = run_args(&setup(0.5), &["plan"]).await;
+        assert_eq!((code, out.as_str()), (EXIT_OK, "(none)\n"));
+    }
+
+    #[tokio::test]
+    async fn test_rebalance_apply_saves_state() {
+        let dir = setup(0.8);
+        let state = dir.path().join("state.json");
+        let state = state.to_str().unwrap();
+
+        let (code, _) = run_args(&dir, &["--state", state, "rebalance"]).await;
+        assert_eq!(code, EXIT_REBALANCE_NEEDED);
+        assert!(!dir.path().join("state.json").exists());
+
+        let (code, _) = run_args(&dir, &["--state", state, "rebalance", "--apply"]).await;
+        assert_eq!(code, EXIT_OK);
+        let saved = PortfolioSnapshot::load(state).unwrap();
+        assert!(saved.last_rebalanced.is_some());
+        let (code, out) = run_args(&dir, &["--state", state, "drift"]).await;
+        assert_eq!(code, EXIT_OK, "{}", out);
+    }
+
+    #[tokio::test]
+    async fn test_history_and_backtest() {
+        let dir = setup(0.5);
+        let (_, out) = run_args(&dir, &["history", "AAA", "--days", "2", "--format", "csv"]).await;
+        assert_eq!(out, "ticker,timestamp,close\nAAA,2,50.0\nAAA,3,100.0\n");
+
+        let (code, out) = run_args(&dir, &["backtest", "--every", "1", "--format", "json"]).await;
+        assert_eq!(code, EXIT_OK);
+        let rows: serde_json::Value = serde_json::from_str(&out).unwrap();
+        // AAA halves on day two and the rebalanced portfolio buys the dip
+        assert_eq!(rows[0]["rebalanced"], "600.00 USD");
+        assert_eq!(rows[1]["buy_and_hold"], "450.00 USD");
+        assert_eq!(rows[1]["rebalanced"], "450.00 USD");
+        assert_eq!(rows[2]["buy_and_hold"], "600.00 USD");
+        assert_eq!(rows[2]["rebalanced"], "675.00 USD");
+    }
+
+    #[test]
+    fn test_table_layout() {
+        #[derive(Serialize)]
+        struct Row {
+            ticker: &'static str,
+            side: Side,
+            quantity: f64,
+            price: f64,
+            notional: Discrete<USD>,
+        }
+        let rows = vec![
+            Row {
+                ticker: "AAA",
+                side: Side::Buy,
+                quantity: 1.0 / 3.0,
+                price: 100.0,
+                notional: Discrete::new(3_333),
+            },
+            Row {
+                ticker: "LONGER",
+                side: Side::Sell,
+                quantity: 12.0,
+                price: 5.5,
+                notional: Discrete::new(6_600),
+            },
+        ];
+        let mut out = Vec::new();
+        write_rows(&rows, Format::Table, &mut out).unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "ticker  side  quantity  price  notional\n\
+             AAA     buy     0.3333  100.0  33.33 USD\n\
+             LONGER  sell      12.0    5.5  66.00 USD\n"
+        );
+    }
+}
@@ -0,0 +1,653 @@
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::Path;
+use std::sync::Arc;
+
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+
+use crate::assets::{AssetClass, QuantityRule};
+use crate::costs::TradingCosts;
+use crate::harvest::Harvest;
+use crate::portfolio::{Band, Bands, PortfolioBuilder, RebalanceTo, RebalanceType};
+use crate::rebalance::TradeLimits;
+use crate::safe_money::{Discrete, FxRates, USD};
+use crate::tax::{LotMethod, OpeningLot, TaxPolicy};
+
+// key in `target_weights` for the share of the portfolio kept in cash
+const CASH: &str = "CASH";
+
+/// Portfolio definition read from a TOML file:
+///
+/// ```toml
+/// cash = "1000.00 USD"
+///
+/// [rebalance]
+/// type = "threshold"
+/// threshold = 0.05
+/// bands = { BTC = 0.1 }
+///
+/// [target_weights]
+/// NVDA = 0.6
+/// BTC = 0.4
+///
+/// [[accounts]]
+/// name = "brokerage"
+/// holdings = [
+///     { ticker = "NVDA", amount = 2.0, quantity = "whole_shares" },
+///     { ticker = "BTC", class = "crypto", id = "bitcoin", amount = 0.1 },
+/// ]
+/// ```
+///
+/// Holdings of the same ticker in several accounts are added up, and so is the
+/// cash of each account. Target weights, including an optional `CASH` weight,
+/// must sum to 1 and may only name tickers that are held; add a holding with
+/// `amount = 0` to target an asset not bought yet.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct PortfolioConfig {
+    #[serde(default)]
+    pub cash: Discrete<USD>,
+    #[serde(default)]
+    pub rebalance: RebalanceConfig,
+    /// Fees and slippage of paper trades.
+    #[serde(default)]
+    pub costs: TradingCosts,
+    /// Lots sales are taken from: fifo, lifo or hifo.
+    #[serde(default)]
+    pub lot_method: LotMethod,
+    /// Tax rates, rebalancing is tax-aware when set.
+    #[serde(default)]
+    pub tax: Option<TaxPolicy>,
+    /// Losses to harvest and the substitutes bought instead.
+    #[serde(default)]
+    pub harvest: Harvest,
+    #[serde(default)]
+    pub target_weights: BTreeMap<String, f64>,
+    /// Fixed rates, used when the price source can't provide one.
+    #[serde(default)]
+    pub fx_rates: FxRates,
+    #[serde(default)]
+    pub accounts: Vec<AccountConfig>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct AccountConfig {
+    pub name: String,
+    #[serde(default)]
+    pub cash: Discrete<USD>,
+    #[serde(default)]
+    pub holdings: Vec<HoldingConfig>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct HoldingConfig {
+    pub ticker: String,
+    #[serde(default)]
+    pub class: AssetClass,
+    /// Id the crypto price source knows the coin by, e.g. "bitcoin" for BTC.
+    pub id: Option<String>,
+    pub amount: f64,
+    /// Quantities the account can trade, e.g. "whole_shares" or `{ lot = 100 }`.
+    #[serde(default)]
+    pub quantity: QuantityRule,
+    /// Dollars paid per unit, the price when the portfolio is built by default.
+    pub cost_basis: Option<f64>,
+    /// Unix timestamp the holding was bought at, the time the portfolio is built
+    /// by default.
+    pub acquired: Option<i64>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RebalanceKind {
+    #[default]
+    None,
+    Threshold,
+    Frequency,
+    ThresholdAndFrequency,
+}
+
+/// How `threshold` and `bands` read.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum BandKind {
+    /// Percentage points, 0.05 for five points either side of the target.
+    #[default]
+    Absolute,
+    /// Fraction of the target, 0.25 for a quarter of it either side.
+    Relative,
+}
+
+#[derive(Debug, Clone, PartialEq, Default, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RebalanceConfig {
+    #[serde(rename = "type", default)]
+    pub kind: RebalanceKind,
+    /// Weight drift that triggers a rebalance, 0.05 for five percentage points.
+    pub threshold: Option<f64>,
+    #[serde(default)]
+    pub band: BandKind,
+    /// Thresholds of single tickers, in place of `threshold`.
+    #[serde(default)]
+    pub bands: BTreeMap<String, f64>,
+    /// Trade positions out of their band back to the target or to the band edge.
+    #[serde(default)]
+    pub rebalance_to: RebalanceTo,
+    /// Days between rebalances.
+    pub frequency: Option<u32>,
+    /// Orders moving no more than this many dollars, costs included, are skipped.
+    pub min_trade: Option<f64>,
+    /// Trades costing more than this share of their notional are skipped.
+    pub drift_penalty: Option<f64>,
+    /// Orders moving fewer dollars are dropped.
+    pub min_order: Option<f64>,
+    pub max_orders: Option<usize>,
+    /// Dollars bought and sold together in one rebalance.
+    pub max_turnover: Option<f64>,
+}
+
+impl RebalanceConfig {
+    pub fn rebalance_type(&self) -> Result<RebalanceType> {
+        let band = |threshold: f64| {
+            if !(threshold > 0.0 && threshold <= 1.0) {
+                return Err(anyhow::anyhow!(
+                    "Rebalance threshold must be in (0, 1], got {}",
+                    threshold
+                ));
+            }
+            Ok(match self.band {
+                BandKind::Absolute => Band::Absolute(threshold),
+                BandKind::Relative => Band::Relative(threshold),
+            })
+        };
+        let threshold = || -> Result<Bands> {
+            let threshold = self.threshold.ok_or_else(|| {
+                anyhow::anyhow!("Rebalance type {:?} needs a threshold", self.kind)
+            })?;
+            let mut bands = Bands::new(band(threshold)?).rebalance_to(self.rebalance_to);
+            for (ticker, threshold) in &self.bands {
+                bands = bands.with_override(ticker, band(*threshold)?);
+            }
+            Ok(bands)
+        };
+        let frequency = || match self.frequency {
+            Some(days) if days > 0 => Ok(days),
+            Some(_) => Err(anyhow::Error::msg(
+                "Rebalance frequency must be at least a day",
+            )),
+            None => Err(anyhow::anyhow!(
+                "Rebalance type {:?} needs a frequency",
+                self.kind
+            )),
+        };
+        Ok(match self.kind {
+            RebalanceKind::None => RebalanceType::None,
+            RebalanceKind::Threshold => RebalanceType::Threshold(threshold()?),
+            RebalanceKind::Frequency => RebalanceType::Frequency(frequency()?),
+            RebalanceKind::ThresholdAndFrequency => {
+                RebalanceType::ThresholdAndFrequency(threshold()?, frequency()?)
+            }
+        })
+    }
+}
+
+impl PortfolioConfig {
+    /// Parses and validates a config.
+    pub fn from_toml(toml: &str) -> Result<Self> {
+        let config: Self = toml::from_str(toml)?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    pub fn load(path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref();
+        let contents = fs::read_to_string(path)
+            .with_context(|| format!("Failed to read {}", path.display()))?;
+        Self::from_toml(&contents).with_context(|| format!("Invalid config {}", path.display()))
+    }
+
+    pub fn holdings(&self) -> impl Iterator<Item = &HoldingConfig> {
+        self.accounts.iter().flat_map(|a| a.holdings.iter())
+    }
+
+    /// Checks amounts and weights, and that every target names a held ticker.
+    pub fn validate(&self) -> Result<()> {
+        let mut classes: BTreeMap<&str, AssetClass> = BTreeMap::new();
+        let mut rules: BTreeMap<&str, QuantityRule> = BTreeMap::new();
+        for holding in self.holdings() {
+            if !holding.amount.is_finite() || holding.amount < 0.0 {
+                return Err(anyhow::anyhow!(
+                    "Amount of {} must be a non-negative number, got {}",
+                    holding.ticker,
+                    holding.amount
+                ));
+            }
+            if holding
+                .cost_basis
+                .is_some_and(|cost| !cost.is_finite() || cost < 0.0)
+            {
+                return Err(anyhow::anyhow!(
+                    "Cost basis of {} must be a non-negative number, got {:?}",
+                    holding.ticker,
+                    holding.cost_basis
+                ));
+            }
+            if holding.class == AssetClass::Crypto && holding.id.is_none() {
+                return Err(anyhow::anyhow!(
+                    "Crypto holding {} needs the id its price source uses",
+                    holding.ticker
+                ));
+            }
+            match classes.insert(&holding.ticker, holding.class) {
+                Some(class) if class != holding.class => {
+                    return Err(anyhow::anyhow!(
+                        "{} is held both as {:?} and as {:?}",
+                        holding.ticker,
+                        class,
+                        holding.class
+                    ));
+                }
+                _ => {}
+            }
+            let valid_rule = match holding.quantity {
+                QuantityRule::Lot(size) => size.is_finite() && size > 0.0,
+                QuantityRule::Decimals(decimals) => decimals <= 18,
+                QuantityRule::Fractional | QuantityRule::WholeShares => true,
+            };
+            if !valid_rule {
+                return Err(anyhow::anyhow!(
+                    "Invalid quantity rule for {}: {:?}",
+                    holding.ticker,
+                    holding.quantity
+                ));
+            }
+            match rules.insert(&holding.ticker, holding.quantity) {
+                Some(rule) if rule != holding.quantity => {
+                    return Err(anyhow::anyhow!(
+                        "{} trades in {} in one account and in {} in another",
+                        holding.ticker,
+                        rule,
+                        holding.quantity
+                    ));
+                }
+                _ => {}
+            }
+        }
+
+        if !self.target_weights.is_empty() {
+            if let Some((ticker, weight)) = self
+                .target_weights
+                .iter()
+                .find(|(_, w)| !w.is_finite() || **w < 0.0)
+            {
+                return Err(anyhow::anyhow!(
+                    "Target weight of {} must be a non-negative number, got {}",
+                    ticker,
+                    weight
+                ));
+            }
+            let total: f64 = self.target_weights.values().sum();
+            if (total - 1.0).abs() > 1e-6 {
+                return Err(anyhow::anyhow!(
+                    "Target weights must sum to 1, got {}",
+                    total
+                ));
+            }
+        }
+        let unknown: Vec<&str> = self
+            .target_weights
+            .keys()
+            .map(|ticker| ticker.as_str())
+            .filter(|ticker| *ticker != CASH && !classes.contains_key(ticker))
+            .collect();
+        if !unknown.is_empty() {
+            return Err(anyhow::anyhow!(
+                "Target weights for tickers that are not held: {} (add them with amount = 0)",
+                unknown.join(", ")
+            ));
+        }
+        for (ticker, substitute) in &self.harvest.substitutes {
+            if ticker == substitute || !classes.contains_key(substitute.as_str()) {
+                return Err(anyhow::anyhow!(
+                    "Substitute {} for {} must be another holding (add it with amount = 0)",
+                    substitute,
+                    ticker
+                ));
+            }
+        }
+        if !self.harvest.min_loss.is_finite() || self.harvest.min_loss < 0.0 {
+            return Err(anyhow::anyhow!(
+                "Minimum loss to harvest must be a non-negative amount, got {}",
+                self.harvest.min_loss
+            ));
+        }
+
+        self.rebalance.rebalance_type()?;
+        let unknown: Vec<&str> = self
+            .rebalance
+            .bands
+            .keys()
+            .map(|ticker| ticker.as_str())
+            .filter(|ticker| !self.target_weights.contains_key(*ticker))
+            .collect();
+        if !unknown.is_empty() {
+            return Err(anyhow::anyhow!(
+                "Bands for tickers without a target weight: {}",
+                unknown.join(", ")
+            ));
+        }
+        if let Some(min_trade) = self.rebalance.min_trade {
+            if !min_trade.is_finite() || min_trade < 0.0 {
+                return Err(anyhow::anyhow!(
+                    "Minimum trade must be a non-negative amount, got {}",
+                    min_trade
+                ));
+            }
+        }
+        if let Some(penalty) = self.rebalance.drift_penalty {
+            if !penalty.is_finite() || penalty < 0.0 {
+                return Err(anyhow::anyhow!(
+                    "Drift penalty must be a non-negative share, got {}",
+                    penalty
+                ));
+            }
+        }
+        for (name, amount) in [
+            ("Minimum order", self.rebalance.min_order),
+            ("Maximum turnover", self.rebalance.max_turnover),
+        ] {
+            if let Some(amount) = amount {
+                if !amount.is_finite() || amount < 0.0 {
+                    return Err(anyhow::anyhow!(
+                        "{} must be a non-negative amount, got {}",
+                        name,
+                        amount
+                    ));
+                }
+            }
+        }
+        if let LotMethod::SpecificId(_) | LotMethod::TaxEfficient = self.lot_method {
+            return Err(anyhow::Error::msg(
+                "lot_method must be fifo, lifo or hifo, add a [tax] section for tax-aware sales",
+            ));
+        }
+        if let Some(tax) = self.tax.filter(|tax| !tax.is_valid()) {
+            return Err(anyhow::anyhow!(
+                "Tax rates must be between 0 and 1 and defer_short_term non-negative, got {:?}",
+                tax
+            ));
+        }
+        if !self.costs.is_valid() {
+            return Err(anyhow::anyhow!(
+                "Trading costs must be non-negative numbers, got {:?}",
+                self.costs
+            ));
+        }
+        Ok(())
+    }
+
+    /// Total cash across the config and its accounts.
+    pub fn total_cash(&self) -> Result<Discrete<USD>> {
+        let mut total = self.cash;
+        for account in &self.accounts {
+            total = total.checked_add(account.cash)?;
+        }
+        Ok(total)
+    }
+}
+
+impl PortfolioBuilder {
+    /// Builder set up from the config file at `path`, prices are fetched on `build`.
+    pub fn from_config(path: impl AsRef<Path>) -> Result<Self> {
+        Self::from_portfolio_config(&PortfolioConfig::load(path)?)
+    }
+
+    pub fn from_portfolio_config(config: &PortfolioConfig) -> Result<Self> {
+        config.validate()?;
+        let mut builder = PortfolioBuilder::new()
+            .cash(config.total_cash()?)
+            .rebalance_type(config.rebalance.rebalance_type()?)
+            .rebalance_threshold(config.rebalance.min_trade)
+            .drift_penalty(config.rebalance.drift_penalty)
+            .limits(TradeLimits {
+                min_notional: config.rebalance.min_order,
+                max_orders: config.rebalance.max_orders,
+                max_turnover: config.rebalance.max_turnover,
+            })
+            .cost_model(Arc::new(config.costs.clone()))
+            .lot_method(config.lot_method.clone())
+            .tax_policy(config.tax)
+            .harvest(config.harvest.clone());
+
+        // the same ticker held in several accounts is one position
+        let mut amounts: Vec<(&HoldingConfig, f64)> = Vec::new();
+        for holding in config.holdings() {
+            match amounts.iter_mut().find(|(h, _)| h.ticker == holding.ticker) {
+                Some((_, amount)) => *amount += holding.amount,
+                None => amounts.push((holding, holding.amount)),
+            }
+        }
+        // holdings with a cost or date are lots of their own
+        for holding in config.holdings() {
+            if holding.cost_basis.is_some() || holding.acquired.is_some() {
+                let lot = OpeningLot {
+                    quantity: holding.amount,
+                    cost_per_unit: holding.cost_basis,
+                    acquired: holding.acquired,
+                };
+                builder = builder.lot(&holding.ticker, lot);
+            }
+        }
+        for (holding, amount) in amounts {
+            builder = match (holding.class, &holding.id) {
+                (AssetClass::Crypto, Some(id)) => builder.add_crypto(id, &holding.ticker, amount),
+                _ => builder.add_asset(&holding.ticker, amount),
+            };
+            builder = builder.quantity_rule(&holding.ticker, holding.quantity);
+        }
+
+        for (ticker, weight) in &config.target_weights {
+            builder = builder.target_weight(ticker, *weight);
+        }
+        Ok(builder.fx_rates(config.fx_rates.clone()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assets::Asset;
+    use crate::price_source::InMemorySource;
+
+    const CONFIG: &str = r#"
+        cash = "100.00 USD"
+
+        [rebalance]
+        type = "threshold_and_frequency"
+        threshold = 0.05
+        frequency = 30
+        min_trade = 10.0
+        bands = { BTC = 0.1 }
+        drift_penalty = 0.01
+        min_order = 5.0
+        max_orders = 10
+        max_turnover = 50000.0
+
+        [costs]
+        flat_fee = 1.0
+        spread_bps = 5.0
+        impact = { square_root = { bps = 10.0, per = 1000000.0 } }
+
+        [tax]
+        short_term_rate = 0.35
+        long_term_rate = 0.15
+        defer_short_term = 0.02
+
+        [harvest]
+        min_loss = 25.0
+        substitutes = { AAA = "BTC" }
+
+        [target_weights]
+        AAA = 0.5
+        BTC = 0.3
+        CASH = 0.2
+
+        [[accounts]]
+        name = "brokerage"
+        cash = "50.00 USD"
+        holdings = [{ ticker = "AAA", amount = 2.0, cost_basis = 80.0, acquired = 1600000000 }]
+
+        [[accounts]]
+        name = "retirement"
+        holdings = [
+            { ticker = "AAA", amount = 1.0 },
+            { ticker = "BTC", class = "crypto", id = "bitcoin", amount = 0.5, quantity = { decimals = 8 } },
+        ]
+    "#;
+
+    #[tokio::test]
+    async fn test_build_from_config() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("portfolio.toml");
+        fs::write(&path, CONFIG).unwrap();
+        let source = InMemorySource::new()
+            .with_price("AAA", 100.0)
+            .with_price("bitcoin", 1_000.0);
+
+        let portfolio = PortfolioBuilder::from_config(&path)
+            .unwrap()
+            .price_source(Arc::new(source))
+            .build()
+            .await
+            .unwrap();
+
+        assert_eq!(portfolio.positions.len(), 2);
+        assert_eq!(portfolio.position("AAA").unwrap().amount_held(), 3.0);
+        // the brokerage shares keep their cost and date, the others cost the price
+        let lots = portfolio.lots("AAA");
+        assert_eq!(lots.len(), 2);
+        assert_eq!((lots[0].quantity, lots[0].cost_per_unit), (2.0, 80.0));
+        assert_eq!(lots[0].acquired, 1_600_000_000);
+        assert_eq!((lots[1].quantity, lots[1].cost_per_unit), (1.0, 100.0));
+        assert_eq!(
+            portfolio.position("BTC").unwrap().class(),
+            AssetClass::Crypto
+        );
+        assert_eq!(
+            portfolio.position("BTC").unwrap().quantity_rule(),
+            QuantityRule::Decimals(8)
+        );
+        assert_eq!(portfolio.cash, Discrete::new(15_000));
+        assert_eq!(
+            portfolio.rebalance_type,
+            RebalanceType::ThresholdAndFrequency(
+                Bands::absolute(0.05).with_override("BTC", Band::Absolute(0.1)),
+                30
+            )
+        );
+        assert_eq!(portfolio.rebalance_threshold, Some(10.0));
+        assert_eq!(portfolio.limits.min_notional, Some(5.0));
+        assert_eq!(portfolio.limits.max_orders, Some(10));
+        assert_eq!(portfolio.limits.max_turnover, Some(50_000.0));
+        assert_eq!(
+            portfolio.tax_policy.and_then(|tax| tax.defer_short_term),
+            Some(0.02)
+        );
+        assert_eq!(portfolio.target_weights["BTC"], 0.3);
+        assert_eq!(
+            portfolio.get_portfolio_value().unwrap(),
+            Discrete::new(95_000)
+        );
+    }
+
+    #[test]
+    fn test_weights_must_sum_to_one() {
+        let config = CONFIG.replace("CASH = 0.2", "CASH = 0.3");
+        let err = PortfolioConfig::from_toml(&config).unwrap_err();
+        assert!(err.to_string().contains("sum to 1"), "{}", err);
+    }
+
+    #[test]
+    fn test_unknown_tickers_are_flagged() {
+        let config = CONFIG.replace("CASH = 0.2", "QCLN = 0.1\nMARA = 0.1");
+        let err = PortfolioConfig::from_toml(&config).unwrap_err();
+        assert!(err.to_string().contains("MARA, QCLN"), "{}", err);
+    }
+
+    #[test]
+    fn test_invalid_configs() {
+        for (from, to) in [
+            ("threshold = 0.05\n", ""),
+            ("frequency = 30\n", "frequency = 0\n"),
+            ("amount = 2.0", "amount = -2.0"),
+            (", id = \"bitcoin\"", ""),
+            ("class = \"crypto\"", "class = \"bond\""),
+            ("min_trade", "minimum_trade"),
+            ("decimals = 8", "lot = 0"),
+            ("flat_fee = 1.0", "flat_fee = -1.0"),
+            ("per = 1000000.0", "per = 0.0"),
+            ("spread_bps", "spread"),
+            ("drift_penalty = 0.01", "drift_penalty = -0.01"),
+            ("max_turnover = 50000.0", "max_turnover = -1.0"),
+            ("max_orders = 10", "max_orders = -1"),
+            (
+                "ticker = \"AAA\", amount = 1.0",
+                "ticker = \"AAA\", amount = 1.0, quantity = \"whole_shares\"",
+            ),
+            ("BTC = 0.1", "ETH = 0.1"),
+            ("BTC = 0.1", "BTC = 1.5"),
+            ("min_trade =", "band = \"percent\"\nmin_trade ="),
+            ("\"50.00 USD\"", "\"50.00 EUR\""),
+            (
+                "cash = \"100.00 USD\"",
+                "cash = \"100.00 USD\"\nlot_method = { specific_id = [1] }",
+            ),
+            (
+                "cash = \"100.00 USD\"",
+                "cash = \"100.00 USD\"\nlot_method = \"lowest\"",
+            ),
+            ("short_term_rate = 0.35", "short_term_rate = 1.5"),
+            ("defer_short_term = 0.02", "defer_short_term = -0.1"),
+            ("long_term_rate", "rate"),
+            ("min_loss = 25.0", "min_loss = -25.0"),
+            ("cost_basis = 80.0", "cost_basis = -80.0"),
+            ("acquired = 1600000000", "acquired = \"2020-09-13\""),
+            ("AAA = \"BTC\"", "AAA = \"IVV\""),
+            ("AAA = \"BTC\"", "AAA = \"AAA\""),
+        ] {
+            let config = CONFIG.replace(from, to);
+            assert!(
+                PortfolioConfig::from_toml(&config).is_err(),
+                "accepted {:?} -> {:?}",
+                from,
+                to
+            );
+        }
+        assert!(PortfolioConfig::from_toml(CONFIG).is_ok());
+    }
+
+    #[test]
+    fn test_relative_bands_to_band_edge() {
+        let config = CONFIG.replace(
+            "min_trade = 10.0",
+            "band = \"relative\"\nrebalance_to = \"band_edge\"",
+        );
+        let config = PortfolioConfig::from_toml(&config).unwrap();
+        let bands = Bands::relative(0.05)
+            .with_override("BTC", Band::Relative(0.1))
+            .rebalance_to(RebalanceTo::BandEdge);
+        assert_eq!(
+            config.rebalance.rebalance_type().unwrap(),
+            RebalanceType::ThresholdAndFrequency(bands, 30)
+        );
+    }
+
+    #[test]
+    fn test_shipped_config_is_valid() {
+        let path = Path::new(env!("CARGO_MANIFEST_DIR")).join("portfolio.toml");
+        PortfolioConfig::load(path).unwrap();
+    }
+}
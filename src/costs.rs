@@ -0,0 +1,184 @@
+use serde::{Deserialize, Serialize};
+
+use crate::rebalance::Side;
+
+/// What trading costs on top of the last price: fees, and a fill price pushed
+/// away from the last price by spread and slippage. Prices and fees are in USD.
+pub trait CostModel: Send + Sync {
+    /// Price a trade of `quantity` fills at, above `price` for buys and below
+    /// it for sells.
+    fn fill_price(&self, side: Side, quantity: f64, price: f64) -> f64;
+    /// Fees charged on a trade of `quantity` at `price`.
+    fn fees(&self, quantity: f64, price: f64) -> f64;
+    /// The model as `TradingCosts`, so it can be saved, when it is one.
+    fn trading_costs(&self) -> Option<TradingCosts> {
+        None
+    }
+}
+
+/// Slippage growing with the size of a trade.
+#[derive(Debug, Clone, Copy, PartialEq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Impact {
+    #[default]
+    None,
+    /// `bps` basis points for every `per` dollars traded.
+    Linear { bps: f64, per: f64 },
+    /// `bps` basis points times the square root of the dollars traded over `per`,
+    /// so four times the size costs twice the slippage.
+    SquareRoot { bps: f64, per: f64 },
+}
+
+impl Impact {
+    /// Slippage of a trade of `notional` dollars, as a fraction of the price.
+    pub fn slippage(&self, notional: f64) -> f64 {
+        match self {
+            Impact::None => 0.0,
+            Impact::Linear { bps, per } => bps / 10_000.0 * notional / per,
+            Impact::SquareRoot { bps, per } => bps / 10_000.0 * (notional / per).sqrt(),
+        }
+    }
+}
+
+/// Broker fees and market costs, all zero by default.
+#[derive(Debug, Clone, PartialEq, Default, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct TradingCosts {
+    /// Dollars per trade.
+    pub flat_fee: f64,
+    /// Dollars per share or coin traded.
+    pub per_share: f64,
+    /// Commission in basis points of the notional.
+    pub commission_bps: f64,
+    /// Bid/ask spread in basis points of the price, buys pay half of it above
+    /// the last price and sells get half of it below.
+    pub spread_bps: f64,
+    pub impact: Impact,
+}
+
+impl TradingCosts {
+    /// Whether every cost is a non-negative number, and impacts have a positive size.
+    pub fn is_valid(&self) -> bool {
+        let amounts = [
+            self.flat_fee,
+            self.per_share,
+            self.commission_bps,
+            self.spread_bps,
+        ];
+        let impact = match self.impact {
+            Impact::None => true,
+            Impact::Linear { bps, per } | Impact::SquareRoot { bps, per } => {
+                bps.is_finite() && bps >= 0.0 && per.is_finite() && per > 0.0
+            }
+        };
+        impact && amounts.iter().all(|a| a.is_finite() && *a >= 0.0)
+    }
+}
+
+impl CostModel for TradingCosts {
+    fn fill_price(&self, side: Side, quantity: f64, price: f64) -> f64 {
+        let slippage = self.spread_bps / 20_000.0 + self.impact.slippage(quantity * price);
+        match side {
+            Side::Buy => price * (1.0 + slippage),
+            Side::Sell => price * (1.0 - slippage),
+        }
+    }
+
+    fn fees(&self, quantity: f64, price: f64) -> f64 {
+        self.flat_fee
+            + self.per_share * quantity
+            + self.commission_bps / 10_000.0 * quantity * price
+    }
+
+    fn trading_costs(&self) -> Option<TradingCosts> {
+        Some(self.clone())
+    }
+}
+
+/// Cash a trade moves, costs included: paid for a buy, received for a sell.
+pub fn cash_for(costs: &dyn CostModel, side: Side, quantity: f64, price: f64) -> f64 {
+    let gross = costs.fill_price(side, quantity, price) * quantity;
+    match side {
+        Side::Buy => gross + costs.fees(quantity, price),
+        Side::Sell => gross - costs.fees(quantity, price),
+    }
+}
+
+/// What a trade costs over filling it at `price`.
+pub fn cost_of(costs: &dyn CostModel, side: Side, quantity: f64, price: f64) -> f64 {
+    (cash_for(costs, side, quantity, price) - price * quantity).abs()
+}
+
+/// Quantity a buy spending `notional` dollars gets at `price`, costs included.
+pub fn quantity_for(costs: &dyn CostModel, notional: f64, price: f64) -> f64 {
+    if price <= 0.0 || notional <= 0.0 {
+        return 0.0;
+    }
+    // what a buy spends only grows with its quantity, and never spends less than
+    // the quantity at the last price, so the answer is bracketed
+    let (mut low, mut high) = (0.0, notional / price);
+    if cash_for(costs, Side::Buy, high, price) <= notional {
+        return high;
+    }
+    for _ in 0..100 {
+        let mid = (low + high) / 2.0;
+        if cash_for(costs, Side::Buy, mid, price) <= notional {
+            low = mid;
+        } else {
+            high = mid;
+        }
+    }
+    low
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_fees_and_spread() {
+        let costs = TradingCosts {
+            flat_fee: 1.0,
+            per_share: 0.01,
+            commission_bps: 10.0,
+            spread_bps: 20.0,
+            ..Default::default()
+        };
+        // 1 + 100 * 0.01 + 0.1% of 10_000
+        assert!((costs.fees(100.0, 100.0) - 12.0).abs() < 1e-9);
+        assert!((costs.fill_price(Side::Buy, 100.0, 100.0) - 100.1).abs() < 1e-9);
+        assert!((costs.fill_price(Side::Sell, 100.0, 100.0) - 99.9).abs() < 1e-9);
+        assert!((cash_for(&costs, Side::Buy, 100.0, 100.0) - 10_022.0).abs() < 1e-6);
+        assert!((cash_for(&costs, Side::Sell, 100.0, 100.0) - 9_978.0).abs() < 1e-6);
+        assert!((cost_of(&costs, Side::Sell, 100.0, 100.0) - 22.0).abs() < 1e-6);
+    }
+
+    #[test]
+    fn test_market_impact() {
+        let linear = Impact::Linear {
+            bps: 10.0,
+            per: 1_000_000.0,
+        };
+        assert!((linear.slippage(2_000_000.0) - 0.002).abs() < 1e-12);
+        let square_root = Impact::SquareRoot {
+            bps: 10.0,
+            per: 1_000_000.0,
+        };
+        assert!((square_root.slippage(4_000_000.0) - 0.002).abs() < 1e-12);
+        assert_eq!(Impact::None.slippage(1e9), 0.0);
+    }
+
+    #[test]
+    fn test_quantity_for_a_notional() {
+        let costs = TradingCosts {
+            flat_fee: 2.0,
+            commission_bps: 50.0,
+            ..Default::default()
+        };
+        let quantity = quantity_for(&costs, 1_000.0, 10.0);
+        assert!((cash_for(&costs, Side::Buy, quantity, 10.0) - 1_000.0).abs() < 1e-6);
+        assert_eq!(quantity_for(&TradingCosts::default(), 1_000.0, 10.0), 100.0);
+        // the flat fee alone eats the notional
+        assert_eq!(quantity_for(&costs, 1.0, 10.0), 0.0);
+    }
+}
@@ -0,0 +1,251 @@
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+use anyhow::{Context, Result};
+use async_trait::async_trait;
+
+use crate::price_source::{AssetMetadata, PriceSource, Quote};
+
+const METADATA_FILE: &str = "metadata.csv";
+
+/// Price source that replays a market snapshot recorded on disk.
+///
+/// The directory holds one history per ticker, either `<ticker>.csv` with a
+/// `ticker,timestamp,close` header or `<ticker>.json` holding an array of
+/// `{"ticker", "timestamp", "close"}` objects, plus an optional `metadata.csv`
+/// with `ticker,currency` rows. The latest quote is the last row of the history.
+pub struct FixtureSource {
+    dir: PathBuf,
+}
+
+impl FixtureSource {
+    pub fn new(dir: impl AsRef<Path>) -> Self {
+        Self {
+            dir: dir.as_ref().to_path_buf(),
+        }
+    }
+
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    /// All quotes recorded for `ticker`, oldest first.
+    pub fn load_history(&self, ticker: &str) -> Result<Vec<Quote>> {
+        let csv_path = self.dir.join(format!("{}.csv", ticker));
+        let json_path = self.dir.join(format!("{}.json", ticker));
+        let mut quotes: Vec<Quote> = if csv_path.exists() {
+            let mut reader = csv::Reader::from_path(&csv_path)
+                .with_context(|| format!("Failed to open {}", csv_path.display()))?;
+            reader
+                .deserialize()
+                .collect::<Result<_, _>>()
+                .with_context(|| format!("Failed to parse {}", csv_path.display()))?
+        } else if json_path.exists() {
+            let contents = fs::read_to_string(&json_path)
+                .with_context(|| format!("Failed to open {}", json_path.display()))?;
+            serde_json::from_str(&contents)
+                .with_context(|| format!("Failed to parse {}", json_path.display()))?
+        } else {
+            return Err(anyhow::anyhow!(
+                "No fixture for {} in {}",
+                ticker,
+                self.dir.display()
+            ));
+        };
+        quotes.sort_by_key(|q| q.timestamp);
+        Ok(quotes)
+    }
+
+    /// Writes `quotes` as the csv history of `ticker`, replacing what was there.
+    pub fn save_history(&self, ticker: &str, quotes: &[Quote]) -> Result<()> {
+        fs::create_dir_all(&self.dir)?;
+        let path = self.dir.join(format!("{}.csv", ticker));
+        let mut writer = csv::Writer::from_path(&path)
+            .with_context(|| format!("Failed to create {}", path.display()))?;
+        for quote in quotes {
+            writer.serialize(quote)?;
+        }
+        writer.flush()?;
+        Ok(())
+    }
+
+    pub fn load_metadata(&self) -> Result<HashMap<String, AssetMetadata>> {
+        let path = self.dir.join(METADATA_FILE);
+        if !path.exists() {
+            return Ok(HashMap::new());
+        }
+        let mut reader = csv::Reader::from_path(&path)
+            .with_context(|| format!("Failed to open {}", path.display()))?;
+        let mut metadata = HashMap::new();
+        for row in reader.deserialize() {
+            let row: AssetMetadata =
+                row.with_context(|| format!("Failed to parse {}", path.display()))?;
+            metadata.insert(row.ticker.clone(), row);
+        }
+        Ok(metadata)
+    }
+
+    pub fn save_metadata(&self, metadata: &HashMap<String, AssetMetadata>) -> Result<()> {
+        fs::create_dir_all(&self.dir)?;
+        let path = self.dir.join(METADATA_FILE);
+        let mut writer = csv::Writer::from_path(&path)
+            .with_context(|| format!("Failed to create {}", path.display()))?;
+        let mut rows: Vec<_> = metadata.values().collect();
+        rows.sort_by(|a, b| a.ticker.cmp(&b.ticker));
+        for row in rows {
+            writer.serialize(row)?;
+        }
+        writer.flush()?;
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl PriceSource for FixtureSource {
+    async fn latest_quote(&self, ticker: &str) -> Result<Quote> {
+        self.load_history(ticker)?
+            .pop()
+            .ok_or_else(|| anyhow::anyhow!("Empty fixture for {}", ticker))
+    }
+
+    async fn historical_daily(&self, ticker: &str, number_of_days: i64) -> Result<Vec<Quote>> {
+        let history = self.load_history(ticker)?;
+        let skip = history.len().saturating_sub(number_of_days.max(0) as usize);
+        Ok(history[skip..].to_vec())
+    }
+
+    async fn metadata(&self, ticker: &str) -> Result<AssetMetadata> {
+        // tickers without a metadata row are assumed to be quoted in USD
+        Ok(self
+            .load_metadata()?
+            .remove(ticker)
+            .unwrap_or_else(|| AssetMetadata {
+                ticker: ticker.to_string(),
+                currency: "USD".to_string(),
+            }))
+    }
+}
+
+/// Passes requests through to a live source and writes every response into a
+/// fixture directory, so the run can later be replayed with `FixtureSource`.
+pub struct RecordingSource {
+    inner: Arc<dyn PriceSource>,
+    fixtures: FixtureSource,
+    // serialises the read-merge-write of fixture files
+    lock: Mutex<()>,
+}
+
+impl RecordingSource {
+    pub fn new(inner: Arc<dyn PriceSource>, dir: impl AsRef<Path>) -> Self {
+        Self {
+            inner,
+            fixtures: FixtureSource::new(dir),
+            lock: Mutex::new(()),
+        }
+    }
+
+    fn record_quotes(&self, ticker: &str, quotes: &[Quote]) -> Result<()> {
+        let _guard = self.lock.lock().unwrap();
+        let mut history = self.fixtures.load_history(ticker).unwrap_or_default();
+        for quote in quotes {
+            match history.iter_mut().find(|q| q.timestamp == quote.timestamp) {
+                Some(existing) => *existing = quote.clone(),
+                None => history.push(quote.clone()),
+            }
+        }
+        history.sort_by_key(|q| q.timestamp);
+        self.fixtures.save_history(ticker, &history)
+    }
+}
+
+#[async_trait]
+impl PriceSource for RecordingSource {
+    async fn latest_quote(&self, ticker: &str) -> Result<Quote> {
+        let quote = self.inner.latest_quote(ticker).await?;
+        self.record_quotes(ticker, std::slice::from_ref(&quote))?;
+        Ok(quote)
+    }
+
+    async fn historical_daily(&self, ticker: &str, number_of_days: i64) -> Result<Vec<Quote>> {
+        let history = self.inner.historical_daily(ticker, number_of_days).await?;
+        self.record_quotes(ticker, &history)?;
+        Ok(history)
+    }
+
+    async fn metadata(&self, ticker: &str) -> Result<AssetMetadata> {
+        let metadata = self.inner.metadata(ticker).await?;
+        let _guard = self.lock.lock().unwrap();
+        let mut recorded = self.fixtures.load_metadata()?;
+        recorded.insert(ticker.to_string(), metadata.clone());
+        self.fixtures.save_metadata(&recorded)?;
+        Ok(metadata)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::price_source::InMemorySource;
+
+    #[tokio::test]
+    async fn test_reads_csv_history() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::write(
+            dir.path().join("SPY.csv"),
+            "ticker,timestamp,close\nSPY,200,101.5\nSPY,100,100.0\n",
+        )
+        .unwrap();
+        let source = FixtureSource::new(dir.path());
+
+        let history = source.historical_daily("SPY", 10).await.unwrap();
+        assert_eq!(history.len(), 2);
+        assert_eq!(history[0].timestamp, 100);
+        assert_eq!(source.latest_quote("SPY").await.unwrap().close, 101.5);
+        assert_eq!(source.metadata("SPY").await.unwrap().currency, "USD");
+    }
+
+    #[tokio::test]
+    async fn test_reads_json_history() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::write(
+            dir.path().join("ethereum.json"),
+            r#"[{"ticker": "ethereum", "timestamp": 100, "close": 3000.0},
+                {"ticker": "ethereum", "timestamp": 200, "close": 3100.0}]"#,
+        )
+        .unwrap();
+        let source = FixtureSource::new(dir.path());
+
+        let history = source.historical_daily("ethereum", 1).await.unwrap();
+        assert_eq!(history.len(), 1);
+        assert_eq!(history[0].close, 3100.0);
+    }
+
+    #[tokio::test]
+    async fn test_missing_fixture_is_an_error() {
+        let dir = tempfile::tempdir().unwrap();
+        let source = FixtureSource::new(dir.path());
+        assert!(source.latest_quote("SPY").await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_record_then_replay() {
+        let dir = tempfile::tempdir().unwrap();
+        let live = Arc::new(
+            InMemorySource::new()
+                .with_history("VOD.L", &[(100, 70.0), (200, 72.5)])
+                .with_currency("VOD.L", "GBP"),
+        );
+        let recorder = RecordingSource::new(live, dir.path());
+        recorder.historical_daily("VOD.L", 30).await.unwrap();
+        recorder.latest_quote("VOD.L").await.unwrap();
+        recorder.metadata("VOD.L").await.unwrap();
+
+        let replay = FixtureSource::new(dir.path());
+        let history = replay.historical_daily("VOD.L", 30).await.unwrap();
+        assert_eq!(history.len(), 2);
+        assert_eq!(replay.latest_quote("VOD.L").await.unwrap().close, 72.5);
+        assert_eq!(replay.metadata("VOD.L").await.unwrap().currency, "GBP");
+    }
+}
@@ -0,0 +1,381 @@
+use std::collections::BTreeMap;
+
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+
+use crate::assets::{Asset, QuantityRule};
+use crate::costs;
+use crate::ledger::Event;
+use crate::portfolio::Portfolio;
+use crate::rebalance::{Fill, Side};
+use crate::safe_money::{Dense, Discrete, USD};
+use crate::tax::LotMethod;
+
+/// Selling at a loss doesn't count when the same asset is bought within this
+/// long before or after the sale.
+pub const WASH_SALE_SECONDS: i64 = 30 * 86_400;
+
+/// Which losses are worth harvesting, and what to hold instead meanwhile.
+#[derive(Debug, Clone, PartialEq, Default, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Harvest {
+    /// Lots with a smaller unrealized loss, in dollars, are left alone.
+    pub min_loss: f64,
+    /// Ticker bought with the proceeds of selling a ticker, e.g. SPY = "IVV".
+    /// Tickers without one are never harvested.
+    pub substitutes: BTreeMap<String, String>,
+}
+
+/// Loss lots of one position sold, and the proceeds moved into its substitute.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct HarvestTrade {
+    pub ticker: String,
+    pub substitute: String,
+    /// Ids of the lots sold.
+    pub lots: Vec<usize>,
+    pub quantity: f64,
+    /// Unrealized loss of the lots at the last price.
+    pub loss: Discrete<USD>,
+    /// Why the sale or the buy would be a wash sale, if it would.
+    pub wash_sale: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct HarvestPlan {
+    pub trades: Vec<HarvestTrade>,
+}
+
+impl HarvestPlan {
+    pub fn is_empty(&self) -> bool {
+        self.trades.is_empty()
+    }
+
+    /// Reasons trades of the plan would be wash sales.
+    pub fn wash_sales(&self) -> Vec<&str> {
+        self.trades
+            .iter()
+            .filter_map(|trade| trade.wash_sale.as_deref())
+            .collect()
+    }
+}
+
+impl Portfolio {
+    /// Lots of positions with a substitute in `harvest` whose loss at the last
+    /// price is at least `min_loss`, to be sold and replaced by the substitute.
+    ///
+    /// A trade is flagged as a wash sale when the ledger has a buy of the ticker
+    /// in the last 30 days, or a sale of the substitute at a loss that buying it
+    /// back would wash. The 30 days after are kept by `plan_rebalance` and
+    /// `plan_deposit`, which don't buy what `harvested` returns.
+    pub fn plan_harvest(&self) -> Result<HarvestPlan> {
+        let now = self.now()?;
+        let mut trades = Vec::new();
+        for asset in self.assets() {
+            let ticker = asset.ticker();
+            let Some(substitute) = self.harvest.substitutes.get(&ticker) else {
+                continue;
+            };
+            self.position(substitute)?;
+            let price = self.price_in::<USD>(asset)?.amount();
+            let losing: Vec<_> = self
+                .lots(&ticker)
+                .iter()
+                .filter(|lot| {
+                    let loss = (lot.cost_per_unit - price) * lot.quantity;
+                    loss > 0.0 && loss >= self.harvest.min_loss
+                })
+                .collect();
+            if losing.is_empty() {
+                continue;
+            }
+            let quantity: f64 = losing.iter().map(|lot| lot.quantity).sum();
+            let loss: f64 = losing
+                .iter()
+                .map(|lot| (lot.cost_per_unit - price) * lot.quantity)
+                .sum();
+            trades.push(HarvestTrade {
+                lots: losing.iter().map(|lot| lot.id).collect(),
+                quantity,
+                loss: Discrete::try_from_dense(Dense::from(loss))?,
+                wash_sale: self.wash_sale(&ticker, substitute, now),
+                substitute: substitute.clone(),
+                ticker,
+            });
+        }
+        Ok(HarvestPlan { trades })
+    }
+
+    /// Sells the loss lots `plan_harvest` finds and buys their substitutes with
+    /// the proceeds, in whole steps for substitutes with a `QuantityRule`. Refuses
+    /// to trade anything when a trade would be a wash sale, and trades nothing
+    /// when one fails. Lots whose proceeds don't buy a step of the substitute are
+    /// kept, and get no fills.
+    pub fn harvest_losses(&mut self) -> Result<Vec<Fill>> {
+        let plan = self.plan_harvest()?;
+        let wash_sales = plan.wash_sales();
+        if !wash_sales.is_empty() {
+            return Err(anyhow::anyhow!(
+                "Harvest would trigger wash sales: {}",
+                wash_sales.join("; ")
+            ));
+        }
+
+        // traded on a copy, kept only once every trade went through
+        let mut portfolio = self.clone();
+        let mut fills = Vec::new();
+        for trade in &plan.trades {
+            let mut pair = portfolio.clone();
+            if let Some(pair_fills) = pair.harvest(trade)? {
+                portfolio = pair;
+                fills.extend(pair_fills);
+            }
+        }
+        *self = portfolio;
+        Ok(fills)
+    }
+
+    // sells the lots of `trade` and buys its substitute, none when the proceeds
+    // don't buy a step of it
+    fn harvest(&mut self, trade: &HarvestTrade) -> Result<Option<Vec<Fill>>> {
+        let method = LotMethod::SpecificId(trade.lots.clone());
+        let proceeds = self.paper_sell_lots(trade.quantity, &trade.ticker, method)?;
+        let sell = self.fill(Side::Sell, &trade.ticker, trade.quantity, proceeds)?;
+
+        let substitute = self.position(&trade.substitute)?;
+        let (quantity, notional) = match substitute.quantity_rule() {
+            QuantityRule::Fractional => (
+                self.paper_buy_notional(proceeds, &trade.substitute)?,
+                proceeds,
+            ),
+            rule => {
+                let price = self.price_in::<USD>(substitute)?.amount();
+                let affordable =
+                    costs::quantity_for(self.cost_model.as_ref(), proceeds.to_f64(), price);
+                let quantity = rule.round_down(affordable);
+                if quantity <= 0.0 {
+                    return Ok(None);
+                }
+                (quantity, self.paper_buy(quantity, &trade.substitute)?)
+            }
+        };
+        let buy = self.fill(Side::Buy, &trade.substitute, quantity, notional)?;
+        Ok(Some(vec![sell, buy]))
+    }
+
+    /// Tickers with a substitute sold at a loss in the 30 days before `now`, with
+    /// the time of their last such sale. Buying one back before the window passes
+    /// would wash the loss.
+    pub fn harvested(&self, now: i64) -> BTreeMap<&str, i64> {
+        let mut harvested = BTreeMap::new();
+        for realized in &self.realized {
+            if self.harvest.substitutes.contains_key(&realized.ticker)
+                && now - realized.sold <= WASH_SALE_SECONDS
+                && realized.gain().is_ok_and(|gain| gain < Discrete::zero())
+            {
+                harvested.insert(realized.ticker.as_str(), realized.sold);
+            }
+        }
+        harvested
+    }
+
+    // why harvesting `ticker` into `substitute` at `now` would be a wash sale
+    fn wash_sale(&self, ticker: &str, substitute: &str, now: i64) -> Option<String> {
+        let recent = |timestamp: i64| now - timestamp <= WASH_SALE_SECONDS;
+        let mut reasons = Vec::new();
+        let bought = self.ledger.entries().iter().rev().find(|entry| {
+            recent(entry.timestamp)
+                && matches!(&entry.event, Event::Buy { ticker: bought, .. } if bought == ticker)
+        });
+        if let Some(entry) = bought {
+            reasons.push(format!(
+                "{} was bought at {}, within 30 days",
+                ticker, entry.timestamp
+            ));
+        }
+        let sold = self.realized.iter().rev().find(|realized| {
+            realized.ticker == substitute
+                && recent(realized.sold)
+                && realized.gain().is_ok_and(|gain| gain < Discrete::zero())
+        });
+        if let Some(realized) = sold {
+            reasons.push(format!(
+                "{} was sold at a loss at {}, within 30 days",
+                substitute, realized.sold
+            ));
+        }
+        (!reasons.is_empty()).then(|| reasons.join(", "))
+    }
+
+    fn fill(
+        &self,
+        side: Side,
+        ticker: &str,
+        quantity: f64,
+        notional: Discrete<USD>,
+    ) -> Result<Fill> {
+        let mark =
+            Discrete::try_from_dense(self.price_in::<USD>(self.position(ticker)?)? * quantity)?;
+        Ok(Fill {
+            ticker: ticker.to_string(),
+            side,
+            quantity,
+            notional,
+            cost: match side {
+                Side::Buy => notional.checked_sub(mark)?,
+                Side::Sell => mark.checked_sub(notional)?,
+            },
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use super::*;
+    use crate::price_source::InMemorySource;
+    use crate::rebalance::RebalancePlan;
+
+    const DAY: i64 = 86_400;
+
+    // 10 SPY bought at 100 and 10 at 80 on day 0, SPY at 90 on day 40 and $100
+    // of cash left
+    async fn portfolio() -> Portfolio {
+        let source = InMemorySource::new()
+            .with_price("SPY", 100.0)
+            .with_price("IVV", 50.0);
+        let mut portfolio = Portfolio::builder()
+            .price_source(Arc::new(source))
+            .add_asset("SPY", 0.0)
+            .add_asset("IVV", 0.0)
+            .cash(Discrete::new(190_000))
+            .build()
+            .await
+            .unwrap();
+        portfolio.harvest = Harvest {
+            min_loss: 50.0,
+            substitutes: BTreeMap::from([("SPY".to_string(), "IVV".to_string())]),
+        };
+        portfolio.clock = Some(0);
+        portfolio.paper_buy(10.0, "SPY").unwrap();
+        portfolio.set_price("SPY", 80.0).unwrap();
+        portfolio.paper_buy(10.0, "SPY").unwrap();
+        portfolio.clock = Some(40 * DAY);
+        portfolio.set_price("SPY", 90.0).unwrap();
+        portfolio
+    }
+
+    #[tokio::test]
+    async fn test_harvest_sells_loss_lots_into_the_substitute() {
+        let mut portfolio = portfolio().await;
+        let plan = portfolio.plan_harvest().unwrap();
+        assert_eq!(plan.trades.len(), 1);
+        assert_eq!(plan.trades[0].quantity, 10.0);
+        assert_eq!(plan.trades[0].loss, Discrete::new(10_000));
+        assert!(plan.wash_sales().is_empty());
+
+        let fills = portfolio.harvest_losses().unwrap();
+        assert_eq!(fills.len(), 2);
+        assert_eq!(
+            (fills[1].ticker.as_str(), fills[1].side),
+            ("IVV", Side::Buy)
+        );
+        // the lot at 80 stays, the proceeds buy 18 IVV
+        assert_eq!(portfolio.position("SPY").unwrap().amount_held(), 10.0);
+        assert_eq!(portfolio.lots("SPY")[0].cost_per_unit, 80.0);
+        assert_eq!(portfolio.position("IVV").unwrap().amount_held(), 18.0);
+        assert_eq!(
+            portfolio.realized_gains().unwrap().short_term,
+            Discrete::new(-10_000)
+        );
+        assert!(portfolio.plan_harvest().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_small_losses_are_left() {
+        let mut portfolio = portfolio().await;
+        portfolio.harvest.min_loss = 150.0;
+        assert!(portfolio.plan_harvest().unwrap().is_empty());
+        portfolio.harvest.substitutes.clear();
+        portfolio.harvest.min_loss = 0.0;
+        assert!(portfolio.plan_harvest().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_losses_are_kept_when_the_substitute_is_out_of_reach() {
+        let mut portfolio = portfolio().await;
+        portfolio
+            .position_mut("IVV")
+            .unwrap()
+            .set_quantity_rule(QuantityRule::WholeShares);
+        // the $900 of proceeds can't buy one IVV
+        portfolio.set_price("IVV", 1_000.0).unwrap();
+        let lots = portfolio.lots("SPY").to_vec();
+        let entries = portfolio.ledger.len();
+
+        assert!(portfolio.harvest_losses().unwrap().is_empty());
+        assert_eq!(portfolio.lots("SPY"), lots.as_slice());
+        assert!(portfolio.realized.is_empty());
+        assert_eq!(portfolio.ledger.len(), entries);
+    }
+
+    #[tokio::test]
+    async fn test_wash_sales_are_refused() {
+        let mut portfolio = portfolio().await;
+        portfolio.clock = Some(35 * DAY);
+        portfolio.paper_buy(1.0, "SPY").unwrap();
+        portfolio.clock = Some(40 * DAY);
+        let plan = portfolio.plan_harvest().unwrap();
+        assert_eq!(plan.wash_sales().len(), 1);
+
+        let entries = portfolio.ledger.len();
+        assert!(portfolio.harvest_losses().is_err());
+        assert_eq!(portfolio.ledger.len(), entries);
+
+        // a month after the buy the loss can be taken
+        portfolio.clock = Some(66 * DAY);
+        assert!(portfolio.plan_harvest().unwrap().wash_sales().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_buying_back_a_harvested_substitute_is_refused() {
+        let mut portfolio = portfolio().await;
+        portfolio.harvest_losses().unwrap();
+        // IVV falls, harvesting it back into SPY would wash the SPY loss
+        portfolio.harvest.substitutes = BTreeMap::from([("IVV".to_string(), "SPY".to_string())]);
+        portfolio.clock = Some(50 * DAY);
+        portfolio.set_price("IVV", 40.0).unwrap();
+        let plan = portfolio.plan_harvest().unwrap();
+        assert_eq!(plan.trades[0].ticker, "IVV");
+        assert!(plan.trades[0].wash_sale.as_ref().unwrap().contains("SPY"));
+    }
+
+    #[tokio::test]
+    async fn test_harvested_tickers_are_not_bought_back() {
+        let mut portfolio = portfolio().await;
+        portfolio.target_weights = [("SPY", 0.5), ("IVV", 0.5)]
+            .into_iter()
+            .map(|(ticker, weight)| (ticker.to_string(), weight))
+            .collect();
+        portfolio.harvest_losses().unwrap();
+        assert_eq!(portfolio.harvested(41 * DAY)["SPY"], 40 * DAY);
+
+        // a day later SPY is under its target, but buying it would wash the loss
+        portfolio.clock = Some(41 * DAY);
+        let buys_spy = |plan: &RebalancePlan| {
+            plan.orders
+                .iter()
+                .any(|order| order.ticker == "SPY" && order.side == Side::Buy)
+        };
+        let plan = portfolio.plan_rebalance().unwrap();
+        assert!(!plan.is_empty());
+        assert!(!buys_spy(&plan));
+        assert!(!buys_spy(
+            &portfolio.plan_deposit(Discrete::new(50_000)).unwrap()
+        ));
+
+        portfolio.clock = Some(71 * DAY);
+        assert!(portfolio.harvested(71 * DAY).is_empty());
+        assert!(buys_spy(&portfolio.plan_rebalance().unwrap()));
+    }
+}
@@ -0,0 +1,439 @@
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+
+use crate::assets::{Asset, Position};
+use crate::portfolio::Portfolio;
+use crate::safe_money::{Discrete, USD};
+use crate::tax::{self, Lot, LotMethod, OpeningLot, Realized};
+
+/// Something that changed the holdings, cash or prices of a portfolio.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Event {
+    /// A holding brought into the portfolio without spending its cash, as one lot
+    /// costing `cost_per_unit` dollars, its `dollar_price` by default, and
+    /// acquired at `acquired`. Opening a ticker already held adds to it.
+    Open {
+        position: Position,
+        #[serde(default)]
+        cost_per_unit: Option<f64>,
+        #[serde(default)]
+        acquired: Option<i64>,
+        /// Dollars a unit was worth when opened, at the FX rates of the time.
+        /// Ledgers from before it was recorded use the rates at replay.
+        #[serde(default)]
+        dollar_price: Option<f64>,
+    },
+    /// `notional` is the cash paid, fees and slippage included.
+    Buy {
+        ticker: String,
+        quantity: f64,
+        notional: Discrete<USD>,
+    },
+    /// `notional` is the cash received, after fees and slippage. `method` picks
+    /// the lots sold.
+    Sell {
+        ticker: String,
+        quantity: f64,
+        notional: Discrete<USD>,
+        #[serde(default)]
+        method: LotMethod,
+    },
+    Deposit {
+        amount: Discrete<USD>,
+    },
+    Withdraw {
+        amount: Discrete<USD>,
+    },
+    Dividend {
+        ticker: String,
+        amount: Discrete<USD>,
+    },
+    Fee {
+        amount: Discrete<USD>,
+    },
+    /// New last price, in the currency the asset is quoted in.
+    PriceUpdate {
+        ticker: String,
+        price: f64,
+    },
+    /// A rebalance ran, its trades are the buys and sells before it.
+    Rebalance,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Entry {
+    /// Unix timestamp.
+    pub timestamp: i64,
+    pub event: Event,
+}
+
+/// Every event of a portfolio in the order they happened. Entries are only
+/// added by `Portfolio::commit`, and never changed or removed.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct Ledger {
+    entries: Vec<Entry>,
+}
+
+impl Ledger {
+    pub fn new() -> Ledger {
+        Ledger::default()
+    }
+
+    pub fn entries(&self) -> &[Entry] {
+        &self.entries
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+}
+
+impl Portfolio {
+    /// Applies `event` and records it in the ledger, stamped with `clock` or the
+    /// system time when it isn't set.
+    pub fn commit(&mut self, event: Event) -> Result<()> {
+        let entry = Entry {
+            timestamp: self.now()?,
+            event,
+        };
+        self.apply(&entry)?;
+        self.ledger.entries.push(entry);
+        Ok(())
+    }
+
+    /// `clock`, or the system time when it isn't set.
+    pub fn now(&self) -> Result<i64> {
+        Ok(match self.clock {
+            Some(timestamp) => timestamp,
+            None => std::time::SystemTime::now()
+                .duration_since(std::time::UNIX_EPOCH)?
+                .as_secs() as i64,
+        })
+    }
+
+    /// Rebuilds the holdings, tax lots, cash, prices and last rebalance from the
+    /// ledger alone. Everything else, settings and FX rates included, is kept as is.
+    pub fn replay(&self) -> Result<Portfolio> {
+        let mut portfolio = self.clone();
+        portfolio.positions = Vec::new();
+        portfolio.cash = Discrete::zero();
+        portfolio.last_rebalanced = None;
+        portfolio.lots.clear();
+        portfolio.realized.clear();
+        portfolio.ledger = Ledger::new();
+        for entry in self.ledger.entries() {
+            portfolio.apply(entry)?;
+            portfolio.ledger.entries.push(entry.clone());
+        }
+        Ok(portfolio)
+    }
+
+    /// Brings `positions` and `cash` into the portfolio, as the ledger's first entries.
+    pub fn open(&mut self, positions: Vec<Position>, cash: Discrete<USD>) -> Result<()> {
+        for position in positions {
+            self.open_lots(position, &[])?;
+        }
+        if cash != Discrete::zero() {
+            self.commit(Event::Deposit { amount: cash })?;
+        }
+        Ok(())
+    }
+
+    /// Brings `position` into the portfolio as `lots`, and whatever of it they
+    /// don't cover as one more lot costing its price at the time.
+    pub fn open_lots(&mut self, position: Position, lots: &[OpeningLot]) -> Result<()> {
+        // priced once here, so replaying the events doesn't depend on later FX rates
+        let dollar_price = self.price_in::<USD>(&position)?.amount();
+        let mut rest = position.amount_held();
+        for lot in lots {
+            if lot.quantity > rest + 1e-9 {
+                return Err(anyhow::anyhow!(
+                    "Lots of {} add up to more than the {} held",
+                    position.ticker(),
+                    position.amount_held()
+                ));
+            }
+            rest -= lot.quantity;
+            let mut part = position.clone();
+            part.set_amount_held(lot.quantity);
+            self.commit(Event::Open {
+                position: part,
+                cost_per_unit: Some(lot.cost_per_unit.unwrap_or(dollar_price)),
+                acquired: lot.acquired,
+                dollar_price: Some(dollar_price),
+            })?;
+        }
+        if lots.is_empty() || rest > 1e-9 {
+            let mut part = position;
+            part.set_amount_held(rest.max(0.0));
+            self.commit(Event::Open {
+                position: part,
+                cost_per_unit: Some(dollar_price),
+                acquired: None,
+                dollar_price: Some(dollar_price),
+            })?;
+        }
+        Ok(())
+    }
+
+    /// Cash received from `ticker` paying out.
+    pub fn dividend(&mut self, ticker: &str, amount: Discrete<USD>) -> Result<()> {
+        self.commit(Event::Dividend {
+            ticker: ticker.to_string(),
+            amount,
+        })
+    }
+
+    /// Cash paid for anything other than a trade, like account fees.
+    pub fn pay_fee(&mut self, amount: Discrete<USD>) -> Result<()> {
+        self.commit(Event::Fee { amount })
+    }
+
+    /// Sets the last price of `ticker`, in the currency it is quoted in.
+    pub fn set_price(&mut self, ticker: &str, price: f64) -> Result<()> {
+        self.commit(Event::PriceUpdate {
+            ticker: ticker.to_string(),
+            price,
+        })
+    }
+
+    fn apply(&mut self, entry: &Entry) -> Result<()> {
+        // lots are named after the entry that bought them
+        let id = self.ledger.len();
+        match &entry.event {
+            Event::Open {
+                position,
+                cost_per_unit,
+                acquired,
+                dollar_price,
+            } => {
+                let cost_per_unit = match cost_per_unit.or(*dollar_price) {
+                    Some(cost) => cost,
+                    None => self.price_in::<USD>(position)?.amount(),
+                };
+                let ticker = position.ticker();
+                if self.position(&ticker).is_ok() {
+                    let asset = self.position_mut(&ticker)?;
+                    asset.set_amount_held(asset.amount_held() + position.amount_held());
+                } else {
+                    self.positions.push(position.clone());
+                }
+                self.add_lot(
+                    &ticker,
+                    id,
+                    acquired.unwrap_or(entry.timestamp),
+                    position.amount_held(),
+                    cost_per_unit,
+                );
+            }
+            Event::Buy {
+                ticker,
+                quantity,
+                notional,
+            } => {
+                if *notional > self.cash {
+                    return Err(anyhow::Error::msg("Not enough cash"));
+                }
+                let asset = self.position_mut(ticker)?;
+                asset.set_amount_held(asset.amount_held() + quantity);
+                self.cash = self.cash.checked_sub(*notional)?;
+                if *quantity > 0.0 {
+                    let cost_per_unit = notional.to_f64() / quantity;
+                    self.add_lot(ticker, id, entry.timestamp, *quantity, cost_per_unit);
+                }
+            }
+            Event::Sell {
+                ticker,
+                quantity,
+                notional,
+                method,
+            } => {
+                if *quantity > self.position(ticker)?.amount_held() {
+                    return Err(anyhow::Error::msg("Not enough assets to sell"));
+                }
+                // relieved on a copy, so a sale the lots can't cover changes nothing
+                let mut lots = self.lots(ticker).to_vec();
+                let parts = tax::relieve(&mut lots, *quantity, *notional, method, entry.timestamp)?;
+                for (lot, proceeds) in parts {
+                    self.realized.push(Realized {
+                        ticker: ticker.clone(),
+                        lot: lot.id,
+                        acquired: lot.acquired,
+                        sold: entry.timestamp,
+                        quantity: lot.quantity,
+                        proceeds,
+                        cost_basis: tax::cost_basis(&lot)?,
+                    });
+                }
+                self.lots.insert(ticker.clone(), lots);
+                let asset = self.position_mut(ticker)?;
+                asset.set_amount_held(asset.amount_held() - quantity);
+                self.cash = self.cash.checked_add(*notional)?;
+            }
+            Event::Deposit { amount } => {
+                self.cash = self.cash.checked_add(*amount)?;
+            }
+            Event::Dividend { ticker, amount } => {
+                self.position(ticker)?;
+                self.cash = self.cash.checked_add(*amount)?;
+            }
+            Event::Withdraw { amount } | Event::Fee { amount } => {
+                if *amount > self.cash {
+                    return Err(anyhow::Error::msg("Not enough cash"));
+                }
+                self.cash = self.cash.checked_sub(*amount)?;
+            }
+            Event::PriceUpdate { ticker, price } => {
+                self.position_mut(ticker)?.set_last_price(*price);
+            }
+            Event::Rebalance => self.last_rebalanced = Some(entry.timestamp),
+        }
+        Ok(())
+    }
+
+    fn add_lot(
+        &mut self,
+        ticker: &str,
+        id: usize,
+        acquired: i64,
+        quantity: f64,
+        cost_per_unit: f64,
+    ) {
+        if quantity > 0.0 {
+            self.lots.entry(ticker.to_string()).or_default().push(Lot {
+                id,
+                acquired,
+                quantity,
+                cost_per_unit,
+            });
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use super::*;
+    use crate::costs::TradingCosts;
+    use crate::price_source::InMemorySource;
+
+    async fn portfolio() -> Portfolio {
+        let source = InMemorySource::new()
+            .with_price("AAA", 100.0)
+            .with_price("BBB", 50.0);
+        Portfolio::builder()
+            .price_source(Arc::new(source))
+            .add_asset("AAA", 3.0)
+            .add_asset("BBB", 2.0)
+            .target_weight("AAA", 0.5)
+            .target_weight("BBB", 0.5)
+            .cash(Discrete::new(10_000))
+            .cost_model(Arc::new(TradingCosts {
+                flat_fee: 1.0,
+                ..Default::default()
+            }))
+            .build()
+            .await
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_every_change_is_recorded() {
+        let mut portfolio = portfolio().await;
+        portfolio.clock = Some(1_000);
+        portfolio.paper_buy(1.0, "BBB").unwrap();
+        portfolio.dividend("AAA", Discrete::new(250)).unwrap();
+        portfolio.pay_fee(Discrete::new(100)).unwrap();
+        portfolio.set_price("BBB", 60.0).unwrap();
+
+        let events: Vec<&Event> = portfolio
+            .ledger
+            .entries()
+            .iter()
+            .map(|e| &e.event)
+            .collect();
+        // two holdings and the starting cash, then the changes
+        assert!(matches!(events[0], Event::Open { .. }));
+        assert!(matches!(events[1], Event::Open { .. }));
+        assert_eq!(
+            events[2],
+            &Event::Deposit {
+                amount: Discrete::new(10_000)
+            }
+        );
+        assert_eq!(
+            events[3],
+            &Event::Buy {
+                ticker: "BBB".to_string(),
+                quantity: 1.0,
+                notional: Discrete::new(5_100),
+            }
+        );
+        assert_eq!(events.len(), 7);
+        assert_eq!(portfolio.ledger.entries()[3].timestamp, 1_000);
+        assert_eq!(portfolio.cash, Discrete::new(5_050));
+    }
+
+    #[tokio::test]
+    async fn test_replay_rebuilds_the_portfolio() {
+        let mut portfolio = portfolio().await;
+        portfolio.rebalance_at(2_000).unwrap();
+        portfolio.set_price("AAA", 120.0).unwrap();
+        portfolio.deposit(Discrete::new(20_000)).unwrap();
+        portfolio.withdraw(Discrete::new(5_000)).unwrap();
+        portfolio.paper_sell(0.5, "AAA").unwrap();
+
+        let replayed = portfolio.replay().unwrap();
+        assert_eq!(replayed.positions, portfolio.positions);
+        assert_eq!(replayed.cash, portfolio.cash);
+        assert_eq!(replayed.last_rebalanced, Some(2_000));
+        assert_eq!(replayed.ledger, portfolio.ledger);
+        assert!(portfolio
+            .ledger
+            .entries()
+            .iter()
+            .any(|e| e.event == Event::Rebalance && e.timestamp == 2_000));
+    }
+
+    #[tokio::test]
+    async fn test_replay_keeps_the_fx_rates_holdings_opened_at() {
+        let source = Arc::new(
+            InMemorySource::new()
+                .with_price("SAP.DE", 200.0)
+                .with_currency("SAP.DE", "EUR")
+                .with_price("EURUSD=X", 1.1),
+        );
+        let mut portfolio = Portfolio::builder()
+            .price_source(source.clone())
+            .add_asset("SAP.DE", 2.0)
+            .build()
+            .await
+            .unwrap();
+        let contributions = portfolio.pnl().unwrap().net_contributions;
+        source.set_price("EURUSD=X", 1.2);
+        portfolio.update_fx_rates().await.unwrap();
+
+        let replayed = portfolio.replay().unwrap();
+        assert!((replayed.lots("SAP.DE")[0].cost_per_unit - 220.0).abs() < 1e-9);
+        assert_eq!(replayed.lots, portfolio.lots);
+        assert_eq!(replayed.pnl().unwrap().net_contributions, contributions);
+        assert_eq!(contributions, Discrete::new(44_000));
+    }
+
+    #[tokio::test]
+    async fn test_rejected_changes_are_not_recorded() {
+        let mut portfolio = portfolio().await;
+        let entries = portfolio.ledger.len();
+        assert!(portfolio.paper_sell(10.0, "AAA").is_err());
+        assert!(portfolio.pay_fee(Discrete::new(1_000_000)).is_err());
+        assert!(portfolio.dividend("CCC", Discrete::new(100)).is_err());
+        assert_eq!(portfolio.ledger.len(), entries);
+    }
+}
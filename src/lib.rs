@@ -0,0 +1,14 @@
+pub mod assets;
+pub mod cli;
+pub mod config;
+pub mod costs;
+pub mod fixture_source;
+pub mod harvest;
+pub mod ledger;
+pub mod pnl;
+pub mod portfolio;
+pub mod price_source;
+pub mod rebalance;
+pub mod safe_money;
+pub mod snapshot;
+pub mod tax;
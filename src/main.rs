@@ -1,29 +1,17 @@
-use anyhow::{Ok, Result};
-pub mod assets;
-pub mod portfolio;
-pub mod safe_money;
-#[allow(dead_code)]
-#[tokio::main]
-async fn main() -> Result<()> {
-    let mut portfolio = portfolio::Portfolio::builder().build().await?;
-
-    println!("Positions: {:#?}", portfolio.positions);
-    println!("Target weights: {:#?}", portfolio.target_weights);
-    println!("Rebalance type: {:#?}", portfolio.rebalance_type);
-    let start_time = std::time::Instant::now();
-    println!("actual weights:{:#?} ", portfolio.get_actual_weights()?);
-    let duration = start_time.elapsed();
-    println!("Time taken to update weights: {:?}", duration);
-    println!("Portfolio value: {}", portfolio.get_portfolio_value());
+use std::process::ExitCode;
 
-    println!("Rebalancing...");
-    portfolio.rebalance()?;
+use beta_balancing::cli::{self, Cli, EXIT_ERROR};
+use clap::Parser;
 
-    println!("Positions: {:#?}", portfolio.positions);
-    println!("Target weights: {:#?}", portfolio.target_weights);
-    println!("actual weights:{:#?} ", portfolio.get_actual_weights()?);
-    println!("Portfolio value: {}", portfolio.get_portfolio_value());
-    println!("cash: {}", portfolio.cash);
-
-    Ok(())
+#[tokio::main]
+async fn main() -> ExitCode {
+    let cli = Cli::parse();
+    let mut stdout = std::io::stdout().lock();
+    match cli::run(&cli, &mut stdout).await {
+        Ok(code) => ExitCode::from(code),
+        Err(e) => {
+            eprintln!("error: {:#}", e);
+            ExitCode::from(EXIT_ERROR)
+        }
+    }
 }
@@ -0,0 +1,169 @@
+use std::collections::BTreeMap;
+
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+
+use crate::assets::Asset;
+use crate::ledger::Event;
+use crate::portfolio::Portfolio;
+use crate::safe_money::{Dense, Discrete, USD};
+use crate::tax::{self, Gains};
+
+/// Profit and loss of one position, in dollars.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct PositionPnl {
+    pub ticker: String,
+    pub quantity: f64,
+    /// What the lots still held cost.
+    pub cost_basis: Discrete<USD>,
+    pub market_value: Discrete<USD>,
+    /// Market value over cost basis.
+    pub unrealized: Discrete<USD>,
+    /// Gains of past sales.
+    pub realized: Discrete<USD>,
+}
+
+/// Profit and loss of the whole portfolio, in dollars.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct PnlReport {
+    pub positions: Vec<PositionPnl>,
+    pub unrealized: Discrete<USD>,
+    pub realized: Gains,
+    /// Deposits and holdings brought in, less withdrawals.
+    pub net_contributions: Discrete<USD>,
+    pub value: Discrete<USD>,
+    /// Value over net contributions: gains, dividends, fees and trading costs.
+    pub total_return: Discrete<USD>,
+    /// Total return as a share of the net contributions.
+    pub total_return_pct: f64,
+}
+
+impl Portfolio {
+    /// Gains at the last prices against the tax lots, gains of past sales, and
+    /// the return over what was put into the portfolio according to the ledger.
+    pub fn pnl(&self) -> Result<PnlReport> {
+        let mut realized_by_ticker: BTreeMap<&str, Discrete<USD>> = BTreeMap::new();
+        for realized in &self.realized {
+            let total = realized_by_ticker.entry(&realized.ticker).or_default();
+            *total = total.checked_add(realized.gain()?)?;
+        }
+
+        let mut positions = Vec::new();
+        let mut unrealized = Discrete::zero();
+        for asset in self.assets() {
+            let ticker = asset.ticker();
+            let cost_basis = self.lots(&ticker).iter().try_fold(
+                Discrete::zero(),
+                |total, lot| -> Result<Discrete<USD>> {
+                    Ok(total.checked_add(tax::cost_basis(lot)?)?)
+                },
+            )?;
+            let market_value = self.position_value_in::<USD>(asset)?;
+            let gain = market_value.checked_sub(cost_basis)?;
+            unrealized = unrealized.checked_add(gain)?;
+            positions.push(PositionPnl {
+                quantity: asset.amount_held(),
+                cost_basis,
+                market_value,
+                unrealized: gain,
+                realized: realized_by_ticker
+                    .get(ticker.as_str())
+                    .copied()
+                    .unwrap_or_default(),
+                ticker,
+            });
+        }
+
+        let mut net_contributions = Discrete::zero();
+        for entry in self.ledger.entries() {
+            net_contributions = match &entry.event {
+                Event::Open {
+                    position,
+                    dollar_price,
+                    ..
+                } => {
+                    let price = match dollar_price {
+                        Some(price) => *price,
+                        None => self.price_in::<USD>(position)?.amount(),
+                    };
+                    net_contributions.checked_add(Discrete::try_from_dense(Dense::from(
+                        price * position.amount_held(),
+                    ))?)?
+                }
+                Event::Deposit { amount } => net_contributions.checked_add(*amount)?,
+                Event::Withdraw { amount } => net_contributions.checked_sub(*amount)?,
+                _ => net_contributions,
+            };
+        }
+        let value = self.get_portfolio_value()?;
+        let total_return = value.checked_sub(net_contributions)?;
+        Ok(PnlReport {
+            positions,
+            unrealized,
+            realized: self.realized_gains()?,
+            net_contributions,
+            value,
+            total_return,
+            total_return_pct: if net_contributions > Discrete::zero() {
+                total_return.to_f64() / net_contributions.to_f64()
+            } else {
+                0.0
+            },
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use super::*;
+    use crate::costs::TradingCosts;
+    use crate::price_source::InMemorySource;
+
+    #[tokio::test]
+    async fn test_pnl_report() {
+        let source = InMemorySource::new()
+            .with_price("AAA", 100.0)
+            .with_price("BBB", 50.0);
+        let mut portfolio = Portfolio::builder()
+            .price_source(Arc::new(source))
+            .add_asset("AAA", 2.0)
+            .add_asset("BBB", 0.0)
+            .cash(Discrete::new(100_000))
+            .cost_model(Arc::new(TradingCosts {
+                flat_fee: 1.0,
+                ..Default::default()
+            }))
+            .build()
+            .await
+            .unwrap();
+        portfolio.paper_buy(4.0, "BBB").unwrap();
+        portfolio.set_price("AAA", 130.0).unwrap();
+        portfolio.paper_sell(1.0, "AAA").unwrap();
+        portfolio.set_price("BBB", 45.0).unwrap();
+        portfolio.dividend("BBB", Discrete::new(500)).unwrap();
+        portfolio.withdraw(Discrete::new(20_000)).unwrap();
+
+        let report = portfolio.pnl().unwrap();
+        let aaa = &report.positions[0];
+        // one share left at 100, sold the other at 130 less the fee
+        assert_eq!(aaa.cost_basis, Discrete::new(10_000));
+        assert_eq!(aaa.unrealized, Discrete::new(3_000));
+        assert_eq!(aaa.realized, Discrete::new(2_900));
+        let bbb = &report.positions[1];
+        // bought 4 at 50 plus the fee
+        assert_eq!(bbb.cost_basis, Discrete::new(20_100));
+        assert_eq!(bbb.unrealized, Discrete::new(-2_100));
+        assert_eq!(report.realized.short_term, Discrete::new(2_900));
+
+        // 200 of AAA and 1000 of cash in, 200 out
+        assert_eq!(report.net_contributions, Discrete::new(100_000));
+        // the gains, the dividend and the fees
+        assert_eq!(report.total_return, Discrete::new(4_300));
+        assert!((report.total_return_pct - 0.043).abs() < 1e-12);
+
+        let df = portfolio.pnl_to_dataframe(&report).unwrap();
+        assert_eq!(df.shape(), (2, 6));
+    }
+}
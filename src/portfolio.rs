@@ -1,15 +1,25 @@
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
+use std::sync::Arc;
 
 use anyhow::{Ok, Result};
 use futures::{stream::FuturesUnordered, StreamExt};
 use polars::prelude::*;
+use serde::{Deserialize, Serialize};
 
-use crate::assets::{Asset, Crypto, Stock};
-use crate::safe_money::USD;
+use crate::assets::{Asset, AssetClass, Crypto, Position, QuantityRule, Stock};
+use crate::costs::{self, CostModel, TradingCosts};
+use crate::harvest::Harvest;
+use crate::ledger::{Event, Ledger};
+use crate::pnl::{PnlReport, PositionPnl};
+use crate::price_source::{CoinGeckoSource, PriceSource, YahooSource};
+use crate::rebalance::{Side, TradeLimits};
+use crate::safe_money::{Currency, Dense, Discrete, FxRates, USD};
+use crate::tax::{Lot, LotMethod, OpeningLot, Realized, TaxPolicy};
 
+#[derive(Clone)]
 pub struct Portfolio {
-    // asset and wieght
-    pub positions: (Vec<Stock>, Vec<Crypto>),
+    // stocks and crypto alike
+    pub positions: Vec<Position>,
     // maybe we want time series of pvf
     // value_over_time: Vec<f32>,
     // target weights
@@ -18,55 +28,129 @@ pub struct Portfolio {
     pub actual_weights: HashMap<String, f64>,
     // rebalance type
     pub rebalance_type: RebalanceType,
-    // reblance threshold
+    // trades worth no more than this many dollars are skipped
     pub rebalance_threshold: Option<f64>,
+    // unix timestamp of the last rebalance, for frequency based rebalancing
+    pub last_rebalanced: Option<i64>,
+    // orders costing more than this share of their notional are not worth placing
+    pub drift_penalty: Option<f64>,
+    // minimum order size, maximum orders and turnover of a rebalance
+    pub limits: TradeLimits,
     // cash on hand
-    pub cash: USD,
+    pub cash: Discrete<USD>,
+    // for valuing positions quoted in other currencies
+    pub fx_rates: FxRates,
+    // where stock and crypto prices come from
+    pub stock_source: Arc<dyn PriceSource>,
+    pub crypto_source: Arc<dyn PriceSource>,
+    // fees and slippage of paper trades
+    pub cost_model: Arc<dyn CostModel>,
+    // every change to holdings, cash and prices, see `commit`
+    pub ledger: Ledger,
+    // lots sales are taken from, unless a sale picks its own
+    pub lot_method: LotMethod,
+    // rates and deferrals of tax-aware rebalancing, tax-blind when unset
+    pub tax_policy: Option<TaxPolicy>,
+    // losses worth harvesting and the tickers to replace the sales with
+    pub harvest: Harvest,
+    // tax lots still held by ticker, and what past sales realized
+    pub lots: BTreeMap<String, Vec<Lot>>,
+    pub realized: Vec<Realized>,
+    // unix time ledger entries are stamped with, the system time when unset
+    pub clock: Option<i64>,
 }
 impl Portfolio {
     pub fn builder() -> PortfolioBuilder {
         PortfolioBuilder::new()
     }
 
-    pub fn get_portfolio_value(&self) -> USD {
-        let stocks_value = self
-            .positions
-            .0
-            .iter()
-            .fold(0.0, |acc, x| acc + (x.last_price.amount * x.amount_held));
-        let cryptos_value = self
-            .positions
-            .1
+    pub fn assets(&self) -> impl Iterator<Item = &dyn Asset> {
+        self.positions.iter().map(|x| x as &dyn Asset)
+    }
+
+    pub fn position(&self, ticker: &str) -> Result<&Position> {
+        self.positions
             .iter()
-            .fold(0.0, |acc, x| acc + (x.last_price * x.amount_held));
-        stocks_value + cryptos_value + self.cash
+            .find(|x| x.ticker() == ticker)
+            .ok_or_else(|| anyhow::anyhow!("No position in {}", ticker))
     }
 
-    pub fn get_actual_weights(&mut self) -> Result<HashMap<String, f64>> {
+    pub fn position_mut(&mut self, ticker: &str) -> Result<&mut Position> {
+        self.positions
+            .iter_mut()
+            .find(|x| x.ticker() == ticker)
+            .ok_or_else(|| anyhow::anyhow!("No position in {}", ticker))
+    }
+
+    /// Where prices for assets of `class` come from.
+    pub fn source_for(&self, class: AssetClass) -> &Arc<dyn PriceSource> {
+        match class {
+            AssetClass::Stock => &self.stock_source,
+            AssetClass::Crypto => &self.crypto_source,
+        }
+    }
+
+    /// Last price of `asset` converted into `B`.
+    pub fn price_in<B: Currency>(&self, asset: &dyn Asset) -> Result<Dense<B>> {
+        Ok(Dense::from(self.fx_rates.convert_amount(
+            asset.last_price(),
+            &asset.currency(),
+            B::symbol(),
+        )?))
+    }
+
+    /// Value of the holding in `asset`, converted into `B` and marked to the cent.
+    pub fn position_value_in<B: Currency>(&self, asset: &dyn Asset) -> Result<Discrete<B>> {
+        Ok(Discrete::try_from_dense(
+            self.price_in::<B>(asset)? * asset.amount_held(),
+        )?)
+    }
+
+    /// Total value of positions and cash, expressed in the base currency `B`.
+    pub fn get_portfolio_value_in<B: Currency>(&self) -> Result<Discrete<B>> {
+        let mut total = Discrete::try_from_dense(self.fx_rates.convert(self.cash.to_dense())?)?;
+        for asset in self.assets() {
+            total = total.checked_add(self.position_value_in::<B>(asset)?)?;
+        }
+        Ok(total)
+    }
+
+    pub fn get_portfolio_value(&self) -> Result<Discrete<USD>> {
+        self.get_portfolio_value_in::<USD>()
+    }
+
+    /// Weight of every position and of cash ("CASH") in the portfolio value, all
+    /// zero when there is no value to weigh.
+    pub fn weights(&self) -> Result<HashMap<String, f64>> {
         let mut actual_weights: HashMap<String, f64> = HashMap::new();
-        let total_value = self.get_portfolio_value();
+        let total_value = self.get_portfolio_value()?.to_f64();
+        if total_value == 0.0 {
+            for asset in self.assets() {
+                actual_weights.insert(asset.ticker(), 0.0);
+            }
+            actual_weights.insert("CASH".to_string(), 0.0);
+            return Ok(actual_weights);
+        }
 
-        for asset in self
-            .positions
-            .0
-            .iter()
-            .map(|x| x as &dyn Asset)
-            .chain(self.positions.1.iter().map(|x| x as &dyn Asset))
-        {
-            let weight =
-                (USD::new(asset.last_price().amount * asset.amount_held()) / total_value).amount;
+        for asset in self.assets() {
+            let weight = self.position_value_in::<USD>(asset)?.to_f64() / total_value;
             actual_weights.insert(asset.ticker(), weight);
         }
 
         // Add cash weight
-        let cash_weight = (self.cash / total_value).amount;
+        let cash_weight = self.cash.to_f64() / total_value;
         actual_weights.insert("CASH".to_string(), cash_weight);
 
         let total_weight: f64 = actual_weights.values().sum();
-        assert!(
-            (total_weight - 1.0).abs() < 1e-8,
-            "Weights do not add up to 1"
-        );
+        if (total_weight - 1.0).abs() >= 1e-8 {
+            return Err(anyhow::anyhow!("Weights add up to {}, not 1", total_weight));
+        }
+        Ok(actual_weights)
+    }
+
+    /// Computes the weights and keeps them in `actual_weights`.
+    pub fn get_actual_weights(&mut self) -> Result<HashMap<String, f64>> {
+        let actual_weights = self.weights()?;
         self.actual_weights = actual_weights.clone();
         // let df = self.weights_to_dataframe(actual_weights)?;
         Ok(actual_weights)
@@ -80,164 +164,228 @@ impl Portfolio {
         )?)
     }
 
-    #[allow(dead_code)]
-    async fn update_prices(&mut self) -> Result<()> {
-        self.update_stock_prices().await?;
-        self.update_crypto_prices().await?;
+    /// One row per position of `report`, amounts in dollars.
+    pub fn pnl_to_dataframe(&self, report: &PnlReport) -> Result<DataFrame> {
+        let positions = &report.positions;
+        let dollars = |amount: fn(&PositionPnl) -> Discrete<USD>| -> Vec<f64> {
+            positions.iter().map(|p| amount(p).to_f64()).collect()
+        };
+        Ok(df!(
+            "ticker" => positions.iter().map(|p| p.ticker.clone()).collect::<Vec<_>>(),
+            "quantity" => positions.iter().map(|p| p.quantity).collect::<Vec<_>>(),
+            "cost_basis" => dollars(|p| p.cost_basis),
+            "market_value" => dollars(|p| p.market_value),
+            "unrealized" => dollars(|p| p.unrealized),
+            "realized" => dollars(|p| p.realized)
+        )?)
+    }
+
+    pub async fn update_prices(&mut self) -> Result<()> {
+        self.update_asset_prices().await?;
+        self.update_fx_rates().await?;
         Ok(())
     }
 
-    async fn update_stock_prices(&mut self) -> Result<()> {
-        let mut futures: FuturesUnordered<_> = self
-            .positions
-            .0
-            .iter_mut()
-            .map(|asset| asset.fetch_price())
+    /// Fetches a USD rate for every currency a position is quoted in, using yahoo's
+    /// `<FROM><TO>=X` pair tickers. Rates that can't be fetched keep their current value.
+    pub async fn update_fx_rates(&mut self) -> Result<()> {
+        let mut currencies: Vec<String> = self
+            .assets()
+            .map(|asset| FxRates::major_currency(&asset.currency()).to_string())
+            .filter(|currency| currency != USD::symbol())
             .collect();
-        while let Some(result) = futures.next().await {
-            result?;
+        currencies.sort();
+        currencies.dedup();
+
+        for currency in currencies {
+            let pair = format!("{}{}=X", currency, USD::symbol());
+            match self.stock_source.latest_quote(&pair).await {
+                Result::Ok(quote) => self
+                    .fx_rates
+                    .set_rate(&currency, USD::symbol(), quote.close),
+                Err(_) if self.fx_rates.rate(&currency, USD::symbol()).is_ok() => {}
+                Err(e) => return Err(e.context(format!("No FX rate for {}", currency))),
+            }
         }
         Ok(())
     }
-    async fn update_crypto_prices(&mut self) -> Result<()> {
-        let mut futures: FuturesUnordered<_> = self
+
+    async fn update_asset_prices(&mut self) -> Result<()> {
+        let portfolio = &*self;
+        let mut futures: FuturesUnordered<_> = portfolio
             .positions
-            .1
-            .iter_mut()
-            .map(|asset| asset.fetch_price())
+            .iter()
+            .map(|position| async move {
+                let source = portfolio.source_for(position.class());
+                let quote = source.latest_quote(&position.price_id()).await;
+                (position.ticker(), quote)
+            })
             .collect();
-        while let Some(result) = futures.next().await {
-            result?;
+        let mut prices = Vec::new();
+        while let Some((ticker, quote)) = futures.next().await {
+            prices.push((ticker, quote?.close));
+        }
+        drop(futures);
+        for (ticker, price) in prices {
+            self.set_price(&ticker, price)?;
         }
         Ok(())
     }
 
+    /// Rebalances at `now()` if `rebalance_type` calls for it, see `should_rebalance`.
     pub fn rebalance(&mut self) -> Result<()> {
-        let original_pvf = self.get_portfolio_value();
-        let target_weights = &self.target_weights;
-        let actual_weights = &self.actual_weights;
-
-        let mut trades = Vec::new();
-
-        for asset in &self.positions.0 {
-            if let Some(target_weight) = target_weights.get(&asset.ticker) {
-                let actual_weight = actual_weights.get(&asset.ticker()).unwrap_or(&0.0);
-                let target_quantity = original_pvf.amount * (*target_weight);
-                let actual_quantity = original_pvf.amount * (*actual_weight);
-                let amount_to_trade = target_quantity - actual_quantity;
-
-                if amount_to_trade.abs() > self.rebalance_threshold.unwrap_or(0.0) {
-                    let price = asset.last_price();
-                    let quantity_to_trade = amount_to_trade / price;
-                    trades.push((quantity_to_trade, asset.ticker.clone()));
-                }
-            }
-        }
-
-        for (quantity_to_trade, ticker) in trades {
-            // If actual weight is higher than target weight, sell to reach target weight
-            if quantity_to_trade < 0.0 {
-                self.paper_sell(quantity_to_trade.abs(), &ticker)?;
-            }
-            // If actual weight is lower than target weight, buy to reach target weight
-            else if quantity_to_trade > 0.0 {
-                self.paper_buy(quantity_to_trade.abs(), &ticker)?;
-            }
-        }
-        self.reinvest()?;
-        let new_pvf = self.get_portfolio_value();
-        assert!(new_pvf == original_pvf);
+        self.rebalance_at(self.now()?)?;
         Ok(())
     }
 
-    fn reinvest(&mut self) -> Result<()> {
-        let excess_cash = self.cash;
-        let num_assets = self.positions.0.len() as f64;
-        let cash_per_asset = USD::new(excess_cash.amount / num_assets);
-
-        let mut quantities_to_buy = Vec::new();
-
-        for asset in &self.positions.0 {
-            if cash_per_asset.amount > 0.0 {
-                let quantity_to_buy = (cash_per_asset / asset.last_price()).amount;
-                quantities_to_buy.push((quantity_to_buy, asset.ticker.clone()));
-            }
+    /// Spends exactly `notional` on `ticker`, costs included, returns the quantity bought.
+    pub fn paper_buy_notional(&mut self, notional: Discrete<USD>, ticker: &str) -> Result<f64> {
+        if notional < Discrete::zero() {
+            return Err(anyhow::Error::msg("Notional must be positive"));
         }
-
-        for (quantity_to_buy, ticker) in quantities_to_buy {
-            self.paper_buy(quantity_to_buy, &ticker)?;
+        if notional > self.cash {
+            return Err(anyhow::Error::msg("Not enough cash"));
         }
-
-        Ok(())
+        let asset = self.position(ticker)?;
+        if asset.quantity_rule() != QuantityRule::Fractional {
+            return Err(anyhow::anyhow!(
+                "{} trades in {}, buy it by quantity",
+                ticker,
+                asset.quantity_rule()
+            ));
+        }
+        let price = self.price_in::<USD>(asset)?.amount();
+        if price <= 0.0 {
+            return Err(anyhow::anyhow!("No price for {}", ticker));
+        }
+        let quantity = costs::quantity_for(self.cost_model.as_ref(), notional.to_f64(), price);
+        self.commit(Event::Buy {
+            ticker: ticker.to_string(),
+            quantity,
+            notional,
+        })?;
+        Ok(quantity)
     }
 
-    pub fn paper_buy(&mut self, quantity: f64, ticker: &str) -> Result<()> {
-        if quantity < 0.0 {
+    /// Buys `quantity` of `ticker` at the last price, returns the cash spent
+    /// including fees and slippage.
+    pub fn paper_buy(&mut self, quantity: f64, ticker: &str) -> Result<Discrete<USD>> {
+        if !quantity.is_finite() || quantity < 0.0 {
             return Err(anyhow::Error::msg("Quantity must be positive"));
         }
-        let asset = self
-            .positions
-            .0
-            .iter()
-            .find(|x| x.ticker == ticker)
-            .unwrap();
-        if USD::new(quantity * asset.last_price.amount) > self.cash {
+        let asset = self.position(ticker)?;
+        check_quantity(asset, quantity)?;
+        let notional = self.cash_for(Side::Buy, quantity, asset)?;
+        if notional > self.cash {
             return Err(anyhow::Error::msg("Not enough cash"));
-        } else {
-            self.cash -= USD::new(quantity * asset.last_price.amount);
-            let asset = self
-                .positions
-                .0
-                .iter_mut()
-                .find(|x| x.ticker == ticker)
-                .unwrap();
-            asset.amount_held += quantity;
         }
-        Ok(())
+        self.commit(Event::Buy {
+            ticker: ticker.to_string(),
+            quantity,
+            notional,
+        })?;
+        Ok(notional)
     }
 
-    pub fn paper_sell(&mut self, quantity: f64, ticker: &str) -> Result<()> {
-        if quantity < 0.0 {
+    /// Sells `quantity` of `ticker` at the last price, returns the cash received
+    /// after fees and slippage. The quantity comes out of lots picked by `lot_method`.
+    pub fn paper_sell(&mut self, quantity: f64, ticker: &str) -> Result<Discrete<USD>> {
+        self.paper_sell_lots(quantity, ticker, self.lot_method.clone())
+    }
+
+    /// Sells like `paper_sell`, taking the quantity out of lots picked by `method`.
+    pub fn paper_sell_lots(
+        &mut self,
+        quantity: f64,
+        ticker: &str,
+        method: LotMethod,
+    ) -> Result<Discrete<USD>> {
+        if !quantity.is_finite() || quantity < 0.0 {
             return Err(anyhow::Error::msg("Quantity must be positive"));
         }
-        let asset = self
-            .positions
-            .0
-            .iter()
-            .find(|x| x.ticker == ticker)
-            .unwrap();
-        if quantity > asset.amount_held {
+        let asset = self.position(ticker)?;
+        check_quantity(asset, quantity)?;
+        let notional = self.cash_for(Side::Sell, quantity, asset)?;
+        if quantity > asset.amount_held() {
             return Err(anyhow::Error::msg("Not enough assets to sell"));
-        } else {
-            self.cash += USD::new(quantity * asset.last_price.amount);
-            let asset = self
-                .positions
-                .0
-                .iter_mut()
-                .find(|x| x.ticker == ticker)
-                .unwrap();
-            asset.amount_held -= quantity;
         }
-        Ok(())
+        self.commit(Event::Sell {
+            ticker: ticker.to_string(),
+            quantity,
+            notional,
+            method,
+        })?;
+        Ok(notional)
+    }
+
+    /// Cash a trade of `quantity` of `asset` moves under `cost_model`, to the cent.
+    pub fn cash_for(&self, side: Side, quantity: f64, asset: &dyn Asset) -> Result<Discrete<USD>> {
+        let price = self.price_in::<USD>(asset)?.amount();
+        Ok(Discrete::try_from_dense(Dense::from(costs::cash_for(
+            self.cost_model.as_ref(),
+            side,
+            quantity,
+            price,
+        )))?)
     }
 }
 
+fn check_quantity(asset: &dyn Asset, quantity: f64) -> Result<()> {
+    if !asset.quantity_rule().allows(quantity) {
+        return Err(anyhow::anyhow!(
+            "Can't trade {} {}, it trades in {}",
+            quantity,
+            asset.ticker(),
+            asset.quantity_rule()
+        ));
+    }
+    Ok(())
+}
+
 pub struct PortfolioBuilder {
-    positions: Vec<Stock>,
+    // (ticker, amount held), prices are fetched on build
+    positions: Vec<(String, f64)>,
+    // (coin id, token, amount held)
+    cryptos: Vec<(String, String, f64)>,
+    quantity_rules: HashMap<String, QuantityRule>,
+    lots: HashMap<String, Vec<OpeningLot>>,
     target_weights: HashMap<String, f64>,
     actual_weights: HashMap<String, f64>,
     rebalance_type: RebalanceType,
     rebalance_threshold: Option<f64>,
+    drift_penalty: Option<f64>,
+    limits: TradeLimits,
+    lot_method: LotMethod,
+    tax_policy: Option<TaxPolicy>,
+    harvest: Harvest,
+    cash: Discrete<USD>,
+    fx_rates: FxRates,
+    stock_source: Option<Arc<dyn PriceSource>>,
+    crypto_source: Option<Arc<dyn PriceSource>>,
+    cost_model: Option<Arc<dyn CostModel>>,
 }
 
 impl Default for PortfolioBuilder {
     fn default() -> Self {
         Self {
             positions: Vec::new(),
+            cryptos: Vec::new(),
+            quantity_rules: HashMap::new(),
+            lots: HashMap::new(),
             target_weights: HashMap::new(),
             actual_weights: HashMap::new(),
             rebalance_type: RebalanceType::None,
             rebalance_threshold: None,
+            drift_penalty: None,
+            limits: TradeLimits::default(),
+            lot_method: LotMethod::default(),
+            tax_policy: None,
+            harvest: Harvest::default(),
+            cash: Discrete::zero(),
+            fx_rates: FxRates::new(),
+            stock_source: None,
+            crypto_source: None,
+            cost_model: None,
         }
     }
 }
@@ -247,55 +395,128 @@ impl PortfolioBuilder {
         PortfolioBuilder::default()
     }
 
-    pub async fn build(self) -> Result<Portfolio> {
-        if self.positions.is_empty() {
-            let stock = vec![
-                Stock::new(COIN, 10.0).await?,
-                Stock::new(NVDA, 2.0).await?,
-                Stock::new(GLDM, 4.0).await?,
-                Stock::new(SPY, 1.0).await?,
-                Stock::new(ENPH, 3.0).await?,
-                Stock::new(APPL, 1.5).await?,
-                Stock::new(MSFT, 0.38).await?,
-            ];
-            let crypto = vec![];
-            let actual_weights = HashMap::new();
-            Ok(Portfolio {
-                positions: (stock, crypto),
-                target_weights: self.load_target_weights(),
-                actual_weights,
-                rebalance_type: RebalanceType::Threshold(REBALANCE_THRESHOLD),
-                rebalance_threshold: self.rebalance_threshold,
-                cash: 0.0.into(),
-            })
-        } else {
-            Ok(Portfolio {
-                positions: (self.positions, Vec::new()),
-                target_weights: self.target_weights,
-                actual_weights: self.actual_weights,
-                rebalance_type: self.rebalance_type,
-                rebalance_threshold: self.rebalance_threshold,
-                cash: 0.0.into(),
-            })
+    pub async fn build(mut self) -> Result<Portfolio> {
+        let stock_source = self
+            .stock_source
+            .take()
+            .unwrap_or_else(|| Arc::new(YahooSource::new()));
+        let crypto_source = self
+            .crypto_source
+            .take()
+            .unwrap_or_else(|| Arc::new(CoinGeckoSource::default()));
+
+        let mut positions: Vec<Position> = Vec::new();
+        for (ticker, amount) in &self.positions {
+            positions.push(
+                Stock::new(ticker, *amount, stock_source.as_ref())
+                    .await?
+                    .into(),
+            );
+        }
+        for (name, token, amount) in &self.cryptos {
+            positions.push(
+                Crypto::new(name, token, *amount, crypto_source.as_ref())
+                    .await?
+                    .into(),
+            );
         }
+
+        for position in &mut positions {
+            if let Some(rule) = self.quantity_rules.get(&position.ticker()) {
+                position.set_quantity_rule(*rule);
+            }
+        }
+
+        let mut portfolio = Portfolio {
+            positions,
+            target_weights: self.target_weights,
+            actual_weights: self.actual_weights,
+            rebalance_type: self.rebalance_type.clone(),
+            rebalance_threshold: self.rebalance_threshold,
+            last_rebalanced: None,
+            drift_penalty: self.drift_penalty,
+            limits: self.limits,
+            cash: Discrete::zero(),
+            fx_rates: self.fx_rates,
+            stock_source,
+            crypto_source,
+            cost_model: self
+                .cost_model
+                .unwrap_or_else(|| Arc::new(TradingCosts::default())),
+            ledger: Ledger::new(),
+            lot_method: self.lot_method,
+            tax_policy: self.tax_policy,
+            harvest: self.harvest,
+            lots: BTreeMap::new(),
+            realized: Vec::new(),
+            clock: None,
+        };
+        // rates first, holdings are opened at their dollar price
+        portfolio.update_fx_rates().await?;
+        for position in std::mem::take(&mut portfolio.positions) {
+            let lots = self.lots.remove(&position.ticker()).unwrap_or_default();
+            portfolio.open_lots(position, &lots)?;
+        }
+        portfolio.open(Vec::new(), self.cash)?;
+        Ok(portfolio)
     }
-    fn load_target_weights(&self) -> HashMap<String, f64> {
-        let mut map = HashMap::new();
-        map.insert(COIN.to_string(), 0.15);
-        map.insert(NVDA.to_string(), 0.20);
-        map.insert(GLDM.to_string(), 0.10);
-        map.insert(SPY.to_string(), 0.30);
-        map.insert(ENPH.to_string(), 0.10);
-        map.insert(QCLN.to_string(), 0.10);
-        map.insert(MSTR.to_string(), 0.025);
-        map.insert(MARA.to_string(), 0.025);
-        map.insert("USD".to_string(), 0.0);
-        map
-    }
-
-    pub async fn add_asset(mut self, ticker: &str, amount: f64) -> Self {
-        let asset = Stock::new(ticker, amount).await.unwrap();
-        self.positions.push(asset);
+    pub fn add_asset(mut self, ticker: &str, amount: f64) -> Self {
+        self.positions.push((ticker.to_string(), amount));
+        self
+    }
+
+    pub fn add_crypto(mut self, name: &str, token: &str, amount: f64) -> Self {
+        self.cryptos
+            .push((name.to_string(), token.to_string(), amount));
+        self
+    }
+
+    /// Quantities `ticker` can be traded in, any by default.
+    pub fn quantity_rule(mut self, ticker: &str, rule: QuantityRule) -> Self {
+        self.quantity_rules.insert(ticker.to_string(), rule);
+        self
+    }
+
+    /// Opens part of `ticker` as a tax lot of its own, see `Portfolio::open_lots`.
+    pub fn lot(mut self, ticker: &str, lot: OpeningLot) -> Self {
+        self.lots.entry(ticker.to_string()).or_default().push(lot);
+        self
+    }
+
+    pub fn target_weight(mut self, ticker: &str, weight: f64) -> Self {
+        self.target_weights.insert(ticker.to_string(), weight);
+        self
+    }
+
+    pub fn cash(mut self, amount: Discrete<USD>) -> Self {
+        self.cash = amount;
+        self
+    }
+
+    /// Sets a fixed rate, used when the price source can't provide one.
+    pub fn fx_rate(mut self, from: &str, to: &str, rate: f64) -> Self {
+        self.fx_rates.set_rate(from, to, rate);
+        self
+    }
+
+    /// Replaces all fixed rates set so far.
+    pub fn fx_rates(mut self, fx_rates: FxRates) -> Self {
+        self.fx_rates = fx_rates;
+        self
+    }
+
+    /// Use `source` for both stock and crypto prices.
+    pub fn price_source(self, source: Arc<dyn PriceSource>) -> Self {
+        self.stock_source(source.clone()).crypto_source(source)
+    }
+
+    pub fn stock_source(mut self, source: Arc<dyn PriceSource>) -> Self {
+        self.stock_source = Some(source);
+        self
+    }
+
+    pub fn crypto_source(mut self, source: Arc<dyn PriceSource>) -> Self {
+        self.crypto_source = Some(source);
         self
     }
 
@@ -308,39 +529,439 @@ impl PortfolioBuilder {
         self.rebalance_threshold = threshold;
         self
     }
+
+    /// Fees and slippage of paper trades, none by default.
+    pub fn cost_model(mut self, cost_model: Arc<dyn CostModel>) -> Self {
+        self.cost_model = Some(cost_model);
+        self
+    }
+
+    pub fn limits(mut self, limits: TradeLimits) -> Self {
+        self.limits = limits;
+        self
+    }
+
+    /// Share of an order's notional worth paying to trade it back toward target.
+    pub fn drift_penalty(mut self, drift_penalty: Option<f64>) -> Self {
+        self.drift_penalty = drift_penalty;
+        self
+    }
+
+    /// Lots sales are taken from, oldest first by default.
+    pub fn lot_method(mut self, lot_method: LotMethod) -> Self {
+        self.lot_method = lot_method;
+        self
+    }
+
+    /// Rebalance tax-aware under `tax_policy`, see `TaxPolicy`.
+    pub fn tax_policy(mut self, tax_policy: Option<TaxPolicy>) -> Self {
+        self.tax_policy = tax_policy;
+        self
+    }
+
+    /// Losses `Portfolio::harvest_losses` takes, none by default.
+    pub fn harvest(mut self, harvest: Harvest) -> Self {
+        self.harvest = harvest;
+        self
+    }
 }
+/// When to rebalance, see `Portfolio::should_rebalance`.
+#[derive(Clone, PartialEq, Serialize, Deserialize)]
 pub enum RebalanceType {
-    Threshold(f64),
+    /// When a weight drifts out of its band.
+    Threshold(Bands),
+    /// Every this many days.
     Frequency(u32),
-    ThresholdAndFrequency(f64, u32),
+    /// When a weight drifted out of its band and the days have passed.
+    ThresholdAndFrequency(Bands, u32),
+    /// Whenever asked.
     None,
 }
 impl std::fmt::Debug for RebalanceType {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
-            RebalanceType::Threshold(t) => write!(f, "Threshold({})", t),
+            RebalanceType::Threshold(b) => write!(f, "Threshold({:?})", b),
             RebalanceType::Frequency(u) => write!(f, "Frequency({})", u),
-            RebalanceType::ThresholdAndFrequency(t, u) => {
-                write!(f, "ThresholdAndFrequency({}, {})", t, u)
+            RebalanceType::ThresholdAndFrequency(b, u) => {
+                write!(f, "ThresholdAndFrequency({:?}, {})", b, u)
             }
             RebalanceType::None => write!(f, "None"),
         }
     }
 }
 
-#[allow(dead_code)]
-const REBALANCE_FREQUENCY: u32 = 30;
-const REBALANCE_THRESHOLD: f64 = 0.05;
-
-const MSFT: &str = "MSFT";
-#[allow(dead_code)]
-const AMD: &str = "AMD";
-const APPL: &str = "AAPL";
-const COIN: &str = "COIN";
-const NVDA: &str = "NVDA";
-const GLDM: &str = "GLDM";
-const SPY: &str = "SPY";
-const ENPH: &str = "ENPH";
-const QCLN: &str = "QCLN";
-const MSTR: &str = "MSTR";
-const MARA: &str = "MARA";
+impl RebalanceType {
+    /// Drift bands of the threshold types.
+    pub fn bands(&self) -> Option<&Bands> {
+        match self {
+            RebalanceType::Threshold(bands) | RebalanceType::ThresholdAndFrequency(bands, _) => {
+                Some(bands)
+            }
+            RebalanceType::Frequency(_) | RebalanceType::None => None,
+        }
+    }
+}
+
+/// How far a weight may drift from its target.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Band {
+    /// Percentage points either side of the target, 0.05 keeps a 30% target
+    /// within 25% and 35%.
+    Absolute(f64),
+    /// Fraction of the target either side of it, 0.25 keeps a 20% target
+    /// within 15% and 25%.
+    Relative(f64),
+}
+
+impl Band {
+    /// Allowed drift either side of `target`.
+    pub fn width(&self, target: f64) -> f64 {
+        match self {
+            Band::Absolute(points) => *points,
+            Band::Relative(fraction) => fraction * target,
+        }
+    }
+}
+
+/// Where trades take a position that drifted out of its band.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RebalanceTo {
+    /// All the way back to the target weight.
+    #[default]
+    Target,
+    /// Only to the nearest edge of the band, for less turnover.
+    BandEdge,
+}
+
+/// Drift bands of the positions: one band for all, with overrides by ticker.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Bands {
+    pub default: Band,
+    #[serde(default)]
+    pub overrides: BTreeMap<String, Band>,
+    #[serde(default)]
+    pub rebalance_to: RebalanceTo,
+}
+
+impl Bands {
+    pub fn new(default: Band) -> Self {
+        Self {
+            default,
+            overrides: BTreeMap::new(),
+            rebalance_to: RebalanceTo::Target,
+        }
+    }
+
+    /// Percentage points either side of every target.
+    pub fn absolute(points: f64) -> Self {
+        Self::new(Band::Absolute(points))
+    }
+
+    /// A fraction of every target either side of it.
+    pub fn relative(fraction: f64) -> Self {
+        Self::new(Band::Relative(fraction))
+    }
+
+    pub fn with_override(mut self, ticker: &str, band: Band) -> Self {
+        self.overrides.insert(ticker.to_string(), band);
+        self
+    }
+
+    pub fn rebalance_to(mut self, rebalance_to: RebalanceTo) -> Self {
+        self.rebalance_to = rebalance_to;
+        self
+    }
+
+    pub fn band(&self, ticker: &str) -> Band {
+        self.overrides.get(ticker).copied().unwrap_or(self.default)
+    }
+
+    /// Allowed drift either side of the target of `ticker`.
+    pub fn width(&self, ticker: &str, target: f64) -> f64 {
+        self.band(ticker).width(target)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::price_source::InMemorySource;
+    use crate::safe_money::EUR;
+
+    async fn two_stock_portfolio(source: Arc<InMemorySource>) -> Portfolio {
+        Portfolio::builder()
+            .price_source(source)
+            .add_asset("AAA", 3.0)
+            .add_asset("BBB", 1.0)
+            .target_weight("AAA", 0.5)
+            .target_weight("BBB", 0.5)
+            .build()
+            .await
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_build_uses_injected_source() {
+        let source = Arc::new(
+            InMemorySource::new()
+                .with_price("AAA", 100.0)
+                .with_price("BBB", 100.0),
+        );
+        let portfolio = two_stock_portfolio(source).await;
+        assert_eq!(portfolio.positions.len(), 2);
+        assert_eq!(
+            portfolio.get_portfolio_value().unwrap(),
+            Discrete::new(40_000)
+        );
+    }
+
+    #[tokio::test]
+    async fn test_update_prices() {
+        let source = Arc::new(
+            InMemorySource::new()
+                .with_price("AAA", 100.0)
+                .with_price("BBB", 100.0),
+        );
+        let mut portfolio = two_stock_portfolio(source.clone()).await;
+        source.set_price("BBB", 300.0);
+        portfolio.update_prices().await.unwrap();
+        assert_eq!(
+            portfolio.get_portfolio_value().unwrap(),
+            Discrete::new(60_000)
+        );
+    }
+
+    #[tokio::test]
+    async fn test_rebalance_to_target_weights() {
+        let source = Arc::new(
+            InMemorySource::new()
+                .with_price("AAA", 100.0)
+                .with_price("BBB", 100.0),
+        );
+        let mut portfolio = two_stock_portfolio(source).await;
+        portfolio.get_actual_weights().unwrap();
+        portfolio.rebalance().unwrap();
+
+        let weights = portfolio.get_actual_weights().unwrap();
+        assert!((weights["AAA"] - 0.5).abs() < 1e-9);
+        assert!((weights["BBB"] - 0.5).abs() < 1e-9);
+        assert_eq!(
+            portfolio.get_portfolio_value().unwrap(),
+            Discrete::new(40_000)
+        );
+    }
+
+    #[tokio::test]
+    async fn test_crypto_is_rebalanced_like_stocks() {
+        let source = Arc::new(
+            InMemorySource::new()
+                .with_price("AAA", 100.0)
+                .with_price("bitcoin", 1_000.0),
+        );
+        let mut portfolio = Portfolio::builder()
+            .price_source(source)
+            .add_asset("AAA", 1.0)
+            .add_crypto("bitcoin", "BTC", 0.3)
+            .target_weight("AAA", 0.5)
+            .target_weight("BTC", 0.5)
+            .build()
+            .await
+            .unwrap();
+
+        portfolio.get_actual_weights().unwrap();
+        portfolio.rebalance().unwrap();
+        let weights = portfolio.get_actual_weights().unwrap();
+        assert!((weights["AAA"] - 0.5).abs() < 1e-4);
+        assert!((weights["BTC"] - 0.5).abs() < 1e-4);
+        assert!((portfolio.position("BTC").unwrap().amount_held() - 0.2).abs() < 1e-9);
+
+        let cash = portfolio.cash;
+        let proceeds = portfolio.paper_sell(0.1, "BTC").unwrap();
+        assert_eq!(proceeds, Discrete::new(10_000));
+        assert_eq!(portfolio.cash, cash + proceeds);
+        assert!(portfolio.paper_sell(1.0, "ETH").is_err());
+    }
+
+    #[tokio::test]
+    async fn test_paper_trades_move_cash_to_the_cent() {
+        let source = Arc::new(InMemorySource::new().with_price("AAA", 33.33));
+        let mut portfolio = Portfolio::builder()
+            .price_source(source)
+            .add_asset("AAA", 0.0)
+            .cash(Discrete::new(10_000))
+            .build()
+            .await
+            .unwrap();
+
+        let spent = portfolio.paper_buy(0.1, "AAA").unwrap();
+        assert_eq!(spent, Discrete::new(333));
+        assert_eq!(portfolio.cash, Discrete::new(9_667));
+
+        let received = portfolio.paper_sell(0.1, "AAA").unwrap();
+        assert_eq!(received, Discrete::new(333));
+        assert_eq!(portfolio.cash, Discrete::new(10_000));
+        assert!(portfolio.paper_buy(3.01, "AAA").is_err());
+    }
+
+    #[tokio::test]
+    async fn test_bad_quote_is_an_error_not_wrapped_cash() {
+        let source = Arc::new(InMemorySource::new().with_price("AAA", 1e37));
+        let mut portfolio = Portfolio::builder()
+            .price_source(source.clone())
+            .add_asset("AAA", 100.0)
+            .cash(Discrete::new(10_000))
+            .build()
+            .await
+            .unwrap();
+
+        assert!(portfolio.get_portfolio_value().is_err());
+        assert!(portfolio.paper_sell(100.0, "AAA").is_err());
+        assert_eq!(portfolio.cash, Discrete::new(10_000));
+        assert_eq!(portfolio.positions[0].amount_held(), 100.0);
+
+        source.set_price("AAA", f64::NAN);
+        portfolio.update_prices().await.unwrap();
+        assert!(portfolio.paper_sell(1.0, "AAA").is_err());
+        assert!(portfolio.paper_buy(f64::INFINITY, "AAA").is_err());
+        assert_eq!(portfolio.cash, Discrete::new(10_000));
+
+        // absurd but representable: valued and sold to the cent, nothing wraps
+        let source = Arc::new(InMemorySource::new().with_price("AAA", 100.0));
+        let mut portfolio = Portfolio::builder()
+            .price_source(source.clone())
+            .add_asset("AAA", 100.0)
+            .cash(Discrete::new(10_000))
+            .build()
+            .await
+            .unwrap();
+        source.set_price("AAA", 1e15);
+        portfolio.update_prices().await.unwrap();
+        assert_eq!(
+            portfolio.get_portfolio_value().unwrap(),
+            Discrete::new(10_000_000_000_000_010_000)
+        );
+        assert_eq!(
+            portfolio.paper_sell(1.0, "AAA").unwrap(),
+            Discrete::new(100_000_000_000_000_000)
+        );
+        assert_eq!(portfolio.cash, Discrete::new(100_000_000_000_010_000));
+    }
+
+    #[tokio::test]
+    async fn test_empty_portfolio_has_zero_weights() {
+        let source = Arc::new(InMemorySource::new().with_price("AAA", 100.0));
+        let mut portfolio = Portfolio::builder()
+            .price_source(source)
+            .add_asset("AAA", 0.0)
+            .target_weight("AAA", 1.0)
+            .build()
+            .await
+            .unwrap();
+        let weights = portfolio.get_actual_weights().unwrap();
+        assert_eq!((weights["AAA"], weights["CASH"]), (0.0, 0.0));
+        assert!(portfolio.plan_rebalance().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_rebalance_reinvests_all_cash() {
+        let source = Arc::new(
+            InMemorySource::new()
+                .with_price("AAA", 3.0)
+                .with_price("BBB", 7.0)
+                .with_price("CCC", 11.0)
+                .with_price("DDD", 13.0),
+        );
+        let mut portfolio = Portfolio::builder()
+            .price_source(source)
+            .add_asset("AAA", 0.0)
+            .add_asset("BBB", 0.0)
+            .add_asset("CCC", 0.0)
+            .add_asset("DDD", 0.0)
+            .target_weight("AAA", 0.5)
+            .target_weight("BBB", 0.3)
+            .target_weight("CCC", 0.2)
+            .cash(Discrete::new(10_000))
+            .build()
+            .await
+            .unwrap();
+        portfolio.get_actual_weights().unwrap();
+        portfolio.rebalance().unwrap();
+
+        assert_eq!(portfolio.cash, Discrete::zero());
+        assert_eq!(
+            portfolio.get_portfolio_value().unwrap(),
+            Discrete::new(10_000)
+        );
+        // the cash follows the targets, and a position without one gets none
+        let weights = portfolio.get_actual_weights().unwrap();
+        assert!((weights["AAA"] - 0.5).abs() < 1e-3);
+        assert!((weights["CCC"] - 0.2).abs() < 1e-3);
+        assert_eq!(portfolio.position("DDD").unwrap().amount_held(), 0.0);
+    }
+
+    #[tokio::test]
+    async fn test_values_foreign_positions_in_base_currency() {
+        let source = Arc::new(
+            InMemorySource::new()
+                .with_price("AAA", 100.0)
+                .with_price("SAP.DE", 200.0)
+                .with_currency("SAP.DE", "EUR")
+                .with_price("EURUSD=X", 1.25),
+        );
+        let mut portfolio = Portfolio::builder()
+            .price_source(source.clone())
+            .add_asset("AAA", 1.0)
+            .add_asset("SAP.DE", 1.0)
+            .cash(Discrete::new(5_000))
+            .build()
+            .await
+            .unwrap();
+
+        // 100 + 200 EUR * 1.25 + 50 cash
+        assert_eq!(
+            portfolio.get_portfolio_value().unwrap(),
+            Discrete::new(40_000)
+        );
+        let in_eur: Discrete<EUR> = portfolio.get_portfolio_value_in().unwrap();
+        assert_eq!(in_eur, Discrete::new(32_000));
+
+        source.set_price("EURUSD=X", 1.5);
+        portfolio.update_prices().await.unwrap();
+        assert_eq!(
+            portfolio.get_portfolio_value().unwrap(),
+            Discrete::new(45_000)
+        );
+
+        let spent = portfolio.paper_buy(0.1, "SAP.DE").unwrap();
+        assert_eq!(spent, Discrete::new(3_000));
+    }
+
+    #[tokio::test]
+    async fn test_missing_fx_rate_fails_build() {
+        let source = Arc::new(
+            InMemorySource::new()
+                .with_price("SAP.DE", 200.0)
+                .with_currency("SAP.DE", "EUR"),
+        );
+        let result = Portfolio::builder()
+            .price_source(source.clone())
+            .add_asset("SAP.DE", 1.0)
+            .build()
+            .await;
+        assert!(result.is_err());
+
+        let portfolio = Portfolio::builder()
+            .price_source(source)
+            .add_asset("SAP.DE", 1.0)
+            .fx_rate("EUR", "USD", 1.1)
+            .build()
+            .await
+            .unwrap();
+        assert_eq!(
+            portfolio.get_portfolio_value().unwrap(),
+            Discrete::new(22_000)
+        );
+    }
+}
@@ -0,0 +1,290 @@
+use std::collections::HashMap;
+use std::sync::RwLock;
+
+use anyhow::Result;
+use async_trait::async_trait;
+use serde::{Deserialize, Serialize};
+use yahoo_finance_api::YahooConnector;
+
+/// A single closing price observation.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Quote {
+    pub ticker: String,
+    // unix timestamp in seconds
+    pub timestamp: i64,
+    pub close: f64,
+}
+
+/// Static information about a ticker, as reported by the price source.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct AssetMetadata {
+    pub ticker: String,
+    pub currency: String,
+}
+
+/// Where `Stock` and `Crypto` get their prices from.
+///
+/// Tickers are whatever the source understands: exchange symbols for Yahoo,
+/// coin ids (e.g. "ethereum") for CoinGecko.
+#[async_trait]
+pub trait PriceSource: Send + Sync {
+    async fn latest_quote(&self, ticker: &str) -> Result<Quote>;
+    /// Daily closes for the last `number_of_days` days, oldest first.
+    async fn historical_daily(&self, ticker: &str, number_of_days: i64) -> Result<Vec<Quote>>;
+    async fn metadata(&self, ticker: &str) -> Result<AssetMetadata>;
+}
+
+pub struct YahooSource {
+    client: YahooConnector,
+}
+
+impl Default for YahooSource {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl YahooSource {
+    pub fn new() -> Self {
+        Self {
+            client: YahooConnector::new(),
+        }
+    }
+}
+
+// yahoo only accepts a fixed set of ranges, pick the smallest one that covers the request
+fn yahoo_range(number_of_days: i64) -> &'static str {
+    match number_of_days {
+        d if d <= 5 => "5d",
+        d if d <= 30 => "1mo",
+        d if d <= 90 => "3mo",
+        d if d <= 180 => "6mo",
+        d if d <= 365 => "1y",
+        d if d <= 730 => "2y",
+        d if d <= 1825 => "5y",
+        d if d <= 3650 => "10y",
+        _ => "max",
+    }
+}
+
+#[async_trait]
+impl PriceSource for YahooSource {
+    async fn latest_quote(&self, ticker: &str) -> Result<Quote> {
+        let res = self.client.get_latest_quotes(ticker, "1d").await?;
+        let quote = res.last_quote()?;
+        Ok(Quote {
+            ticker: ticker.to_string(),
+            timestamp: quote.timestamp as i64,
+            close: quote.close,
+        })
+    }
+
+    async fn historical_daily(&self, ticker: &str, number_of_days: i64) -> Result<Vec<Quote>> {
+        let res = self
+            .client
+            .get_quote_range(ticker, "1d", yahoo_range(number_of_days))
+            .await?;
+        let quotes = res.quotes()?;
+        let skip = quotes.len().saturating_sub(number_of_days.max(0) as usize);
+        Ok(quotes
+            .into_iter()
+            .skip(skip)
+            .map(|q| Quote {
+                ticker: ticker.to_string(),
+                timestamp: q.timestamp as i64,
+                close: q.close,
+            })
+            .collect())
+    }
+
+    async fn metadata(&self, ticker: &str) -> Result<AssetMetadata> {
+        let res = self.client.get_latest_quotes(ticker, "1d").await?;
+        let metadata = res.metadata()?;
+        Ok(AssetMetadata {
+            ticker: ticker.to_string(),
+            currency: metadata.currency,
+        })
+    }
+}
+
+pub struct CoinGeckoSource {
+    // currency prices are quoted in, lowercase as coingecko expects it
+    vs_currency: String,
+}
+
+impl Default for CoinGeckoSource {
+    fn default() -> Self {
+        Self::new("usd")
+    }
+}
+
+impl CoinGeckoSource {
+    pub fn new(vs_currency: &str) -> Self {
+        Self {
+            vs_currency: vs_currency.to_lowercase(),
+        }
+    }
+}
+
+#[async_trait]
+impl PriceSource for CoinGeckoSource {
+    async fn latest_quote(&self, ticker: &str) -> Result<Quote> {
+        let id = ticker.to_string();
+        let vs_currency = self.vs_currency.clone();
+        let res = tokio::task::spawn_blocking(move || {
+            rust_gecko::simple::price(vec![&id], vec![&vs_currency], None, None, None, None)
+        })
+        .await?;
+        let json = res
+            .json
+            .ok_or_else(|| anyhow::Error::msg("No data received"))?;
+        let close = json
+            .get(ticker)
+            .and_then(|coin| coin.get(&self.vs_currency))
+            .and_then(|price| price.as_f64())
+            .ok_or_else(|| anyhow::anyhow!("No {} price for {}", self.vs_currency, ticker))?;
+        Ok(Quote {
+            ticker: ticker.to_string(),
+            timestamp: std::time::SystemTime::now()
+                .duration_since(std::time::UNIX_EPOCH)?
+                .as_secs() as i64,
+            close,
+        })
+    }
+
+    async fn historical_daily(&self, ticker: &str, number_of_days: i64) -> Result<Vec<Quote>> {
+        let id = ticker.to_string();
+        let vs_currency = self.vs_currency.clone();
+        let res = tokio::task::spawn_blocking(move || {
+            rust_gecko::coins::market_chart(
+                &id,
+                &vs_currency,
+                (number_of_days - 1).to_string().as_str(),
+                Some("daily"),
+            )
+        })
+        .await?;
+        let json = res
+            .json
+            .ok_or_else(|| anyhow::Error::msg("No data received"))?;
+        // can also parse the daily market caps and total volumes from this repsonse
+        let prices = json
+            .get("prices")
+            .and_then(|prices| prices.as_array())
+            .ok_or_else(|| anyhow::anyhow!("No price history for {}", ticker))?;
+        prices
+            .iter()
+            .map(|x| {
+                let point = x.as_array();
+                let timestamp = point.and_then(|p| p.first()).and_then(|t| t.as_f64());
+                let close = point.and_then(|p| p.get(1)).and_then(|c| c.as_f64());
+                match (timestamp, close) {
+                    (Some(timestamp), Some(close)) => Ok(Quote {
+                        ticker: ticker.to_string(),
+                        // coingecko reports milliseconds
+                        timestamp: (timestamp / 1000.0) as i64,
+                        close,
+                    }),
+                    _ => Err(anyhow::anyhow!("Malformed price point for {}", ticker)),
+                }
+            })
+            .collect()
+    }
+
+    async fn metadata(&self, ticker: &str) -> Result<AssetMetadata> {
+        Ok(AssetMetadata {
+            ticker: ticker.to_string(),
+            currency: self.vs_currency.to_uppercase(),
+        })
+    }
+}
+
+/// Price source backed by prices held in memory, for tests and offline runs.
+#[derive(Default)]
+pub struct InMemorySource {
+    histories: RwLock<HashMap<String, Vec<Quote>>>,
+    currencies: RwLock<HashMap<String, String>>,
+}
+
+impl InMemorySource {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_price(self, ticker: &str, price: f64) -> Self {
+        self.set_price(ticker, price);
+        self
+    }
+
+    /// Replaces the history of `ticker` with `closes`, given as (timestamp, close) oldest first.
+    pub fn with_history(self, ticker: &str, closes: &[(i64, f64)]) -> Self {
+        let quotes = closes
+            .iter()
+            .map(|&(timestamp, close)| Quote {
+                ticker: ticker.to_string(),
+                timestamp,
+                close,
+            })
+            .collect();
+        self.histories
+            .write()
+            .unwrap()
+            .insert(ticker.to_string(), quotes);
+        self
+    }
+
+    pub fn with_currency(self, ticker: &str, currency: &str) -> Self {
+        self.currencies
+            .write()
+            .unwrap()
+            .insert(ticker.to_string(), currency.to_string());
+        self
+    }
+
+    /// Appends a new latest price for `ticker`.
+    pub fn set_price(&self, ticker: &str, price: f64) {
+        let mut histories = self.histories.write().unwrap();
+        let history = histories.entry(ticker.to_string()).or_default();
+        let timestamp = history.last().map_or(0, |q| q.timestamp + 86_400);
+        history.push(Quote {
+            ticker: ticker.to_string(),
+            timestamp,
+            close: price,
+        });
+    }
+}
+
+#[async_trait]
+impl PriceSource for InMemorySource {
+    async fn latest_quote(&self, ticker: &str) -> Result<Quote> {
+        self.histories
+            .read()
+            .unwrap()
+            .get(ticker)
+            .and_then(|history| history.last().cloned())
+            .ok_or_else(|| anyhow::anyhow!("No price for {}", ticker))
+    }
+
+    async fn historical_daily(&self, ticker: &str, number_of_days: i64) -> Result<Vec<Quote>> {
+        let histories = self.histories.read().unwrap();
+        let history = histories
+            .get(ticker)
+            .ok_or_else(|| anyhow::anyhow!("No price history for {}", ticker))?;
+        let skip = history.len().saturating_sub(number_of_days.max(0) as usize);
+        Ok(history[skip..].to_vec())
+    }
+
+    async fn metadata(&self, ticker: &str) -> Result<AssetMetadata> {
+        let currency = self
+            .currencies
+            .read()
+            .unwrap()
+            .get(ticker)
+            .cloned()
+            .unwrap_or_else(|| "USD".to_string());
+        Ok(AssetMetadata {
+            ticker: ticker.to_string(),
+            currency,
+        })
+    }
+}
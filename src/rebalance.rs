@@ -0,0 +1,1625 @@
+use std::collections::HashMap;
+
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+
+use crate::assets::{Asset, QuantityRule};
+use crate::costs::{self, CostModel};
+use crate::ledger::Event;
+use crate::portfolio::{Bands, Portfolio, RebalanceTo, RebalanceType};
+use crate::safe_money::{Dense, Discrete, Remainder, USD};
+use crate::tax::{Gains, Term};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Side {
+    Buy,
+    Sell,
+}
+
+impl std::fmt::Display for Side {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Side::Buy => write!(f, "buy"),
+            Side::Sell => write!(f, "sell"),
+        }
+    }
+}
+
+/// Why the planner wants an order.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Reason {
+    /// Above its target weight.
+    Overweight,
+    /// Below its target weight.
+    Underweight,
+    /// Spends cash left over after the other orders. Filled by notional, so the
+    /// plan spends the cash to the cent.
+    InvestCash,
+    /// Spends a deposit on the most underweight positions.
+    Deposit,
+    /// Raises cash for a withdrawal from the most overweight positions.
+    Withdrawal,
+}
+
+/// A proposed trade.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Order {
+    pub ticker: String,
+    pub side: Side,
+    pub quantity: f64,
+    /// Cash the order moves at the last price, costs included.
+    pub notional: Discrete<USD>,
+    /// Estimated fees and slippage.
+    pub cost: Discrete<USD>,
+    pub reason: Reason,
+    /// Weight of the position before the plan.
+    pub pre_weight: f64,
+    /// Weight of the position once the whole plan is filled.
+    pub post_weight: f64,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct PlanSummary {
+    /// Total notional traded, buys and sells.
+    pub turnover: Discrete<USD>,
+    pub bought: Discrete<USD>,
+    pub sold: Discrete<USD>,
+    /// Estimated fees and slippage of all orders.
+    pub costs: Discrete<USD>,
+    /// Cash left once every order is filled.
+    pub cash_residual: Discrete<USD>,
+    /// Gains the sells are expected to realize.
+    pub realized: Gains,
+    /// Tax on the realized gains under `tax_policy`, zero without one.
+    pub estimated_tax: Discrete<USD>,
+}
+
+/// Orders that bring a portfolio back to its target weights, computed without
+/// touching the portfolio. Sells come first so buys can spend the proceeds.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct RebalancePlan {
+    pub orders: Vec<Order>,
+    pub summary: PlanSummary,
+}
+
+impl RebalancePlan {
+    pub fn is_empty(&self) -> bool {
+        self.orders.is_empty()
+    }
+}
+
+/// Limits on the orders of one rebalance. When the order count or turnover
+/// binds, the trades that remove the most drift go first.
+#[derive(Debug, Clone, Copy, PartialEq, Default, Serialize, Deserialize)]
+pub struct TradeLimits {
+    /// Orders moving fewer dollars, costs included, are dropped, e.g. a broker's
+    /// minimum.
+    #[serde(default)]
+    pub min_notional: Option<f64>,
+    #[serde(default)]
+    pub max_orders: Option<usize>,
+    /// Dollars bought and sold together.
+    #[serde(default)]
+    pub max_turnover: Option<f64>,
+}
+
+const SECONDS_PER_DAY: i64 = 86_400;
+
+// above this many positions traded in lots, only the rounding down of every
+// trade is tried instead of every mix of rounding up and down
+const MAX_LOT_COMBINATIONS: usize = 10;
+
+/// An executed order.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Fill {
+    pub ticker: String,
+    pub side: Side,
+    pub quantity: f64,
+    pub notional: Discrete<USD>,
+    /// Fees and slippage paid.
+    pub cost: Discrete<USD>,
+}
+
+impl Portfolio {
+    /// Orders back to `target_weights`, at the last prices. Orders moving no more
+    /// than `rebalance_threshold` dollars, costs included, are left out, and cash
+    /// left after the trades, above what a `CASH` target keeps, is allocated by
+    /// target weight over the positions with a target that trade in any quantity.
+    ///
+    /// Positions with a `QuantityRule` trade in whole steps of it: of the
+    /// roundings of their trades that keep cash non-negative, the plan takes the
+    /// one leaving the weights closest to their targets (least squares).
+    ///
+    /// With bands set to `RebalanceTo::BandEdge`, only positions out of their band
+    /// trade, to the nearest edge of it, and leftover cash stays cash.
+    ///
+    /// Orders are priced with `cost_model`. With a `drift_penalty`, a trade whose
+    /// estimated cost is more than that share of the dollars it moves back toward
+    /// the goal is not worth it and left out.
+    ///
+    /// Trades are ranked by how much they reduce the squared drift from target, and
+    /// `limits` are filled in that order: the top `max_orders`, then each trade in
+    /// full while it fits `max_turnover` and the first that doesn't cut down to fit.
+    ///
+    /// Under a `tax_policy` with `defer_short_term`, a sell of a position within
+    /// that distance of its target only sells lots realizing no short-term gain.
+    /// Positions harvested in the last 30 days, see `harvested`, aren't bought.
+    pub fn plan_rebalance(&self) -> Result<RebalancePlan> {
+        let total = self.get_portfolio_value()?;
+        let weights = self.weights()?;
+        let now = self.now()?;
+        let harvested = self.harvested(now);
+        let to_band_edge = self
+            .rebalance_type
+            .bands()
+            .filter(|bands| bands.rebalance_to == RebalanceTo::BandEdge);
+
+        let mut legs = Vec::new();
+        for asset in self.assets() {
+            let ticker = asset.ticker();
+            let Some(&target_weight) = self.target_weights.get(&ticker) else {
+                continue;
+            };
+            let actual_weight = weights.get(&ticker).copied().unwrap_or(0.0);
+            let goal_weight = match to_band_edge {
+                Some(bands) => {
+                    let width = bands.width(&ticker, target_weight);
+                    let drift = actual_weight - target_weight;
+                    if drift.abs() <= width {
+                        continue;
+                    }
+                    target_weight + width.copysign(drift)
+                }
+                None => target_weight,
+            };
+            let mut amount_to_trade = total.to_dense() * (goal_weight - actual_weight);
+            if amount_to_trade.abs().amount() <= self.rebalance_threshold.unwrap_or(0.0) {
+                continue;
+            }
+
+            let price = self.price_in::<USD>(asset)?;
+            let mut ideal = amount_to_trade / price;
+            if ideal > 0.0 && harvested.contains_key(ticker.as_str()) {
+                continue;
+            }
+            let mut goal_weight = goal_weight;
+            let tolerance = self.tax_policy.and_then(|policy| policy.defer_short_term);
+            if let Some(tolerance) = tolerance.filter(|_| ideal < 0.0) {
+                if (actual_weight - target_weight).abs() <= tolerance {
+                    let free = self.short_term_free(&ticker, price.amount(), now);
+                    if -ideal > free {
+                        if free <= 0.0 {
+                            continue;
+                        }
+                        ideal = -free;
+                        amount_to_trade = price * ideal;
+                        goal_weight = actual_weight + amount_to_trade.amount() / total.to_f64();
+                    }
+                }
+            }
+            if let Some(penalty) = self.drift_penalty {
+                let side = if ideal < 0.0 { Side::Sell } else { Side::Buy };
+                let cost =
+                    costs::cost_of(self.cost_model.as_ref(), side, ideal.abs(), price.amount());
+                if cost > penalty * amount_to_trade.abs().amount() {
+                    continue;
+                }
+            }
+            legs.push(Leg {
+                ticker,
+                price,
+                held: asset.amount_held(),
+                rule: asset.quantity_rule(),
+                costs: self.cost_model.as_ref(),
+                ideal,
+                trade: ideal,
+                pre_weight: actual_weight,
+                goal_weight,
+                benefit: (actual_weight - target_weight).powi(2)
+                    - (goal_weight - target_weight).powi(2),
+            });
+        }
+        legs.sort_by(|a, b| b.benefit.total_cmp(&a.benefit));
+        if let Some(max_orders) = self.limits.max_orders {
+            legs.truncate(max_orders);
+        }
+        // leftover cash is only worth spending when it isn't left on purpose
+        let cash_goal = to_band_edge
+            .is_none()
+            .then(|| self.target_weights.get("CASH").copied().unwrap_or(0.0));
+        round_to_lots(&mut legs, self.cash, total.to_f64(), cash_goal)?;
+        fit_buys_to_cash(&mut legs, self.cash)?;
+        let mut turnover_left = self.limits.max_turnover.unwrap_or(f64::INFINITY);
+        for leg in &mut legs {
+            turnover_left -= leg.fit_turnover(turnover_left)?;
+        }
+        self.drop_small_trades(&mut legs)?;
+        // dropped sells may leave buys short of cash
+        fit_buys_to_cash(&mut legs, self.cash)?;
+
+        let mut orders = orders_from(&legs, |side| match side {
+            Side::Sell => Reason::Overweight,
+            Side::Buy => Reason::Underweight,
+        })?;
+        let mut cash = self.cash;
+        for order in &orders {
+            cash = match order.side {
+                Side::Sell => cash.checked_add(order.notional)?,
+                Side::Buy => cash.checked_sub(order.notional)?,
+            };
+        }
+        // the cash goes to the fractional positions with a target, by target weight
+        let mut fractional: Vec<(&dyn Asset, f64)> = self
+            .assets()
+            .filter(|asset| asset.quantity_rule() == QuantityRule::Fractional)
+            .filter(|asset| !harvested.contains_key(asset.ticker().as_str()))
+            .filter_map(|asset| {
+                let target = self.target_weights.get(&asset.ticker()).copied()?;
+                (target > 0.0).then_some((asset, target))
+            })
+            .collect();
+        // what the limits leave for investing the cash
+        if let Some(max) = self.limits.max_orders {
+            fractional.truncate(max.saturating_sub(orders.len()));
+        }
+        // only the cash above a `CASH` target is invested
+        let kept = Discrete::try_from_dense(total.to_dense() * self.cash_target())?;
+        let spare = cash.checked_sub(kept)?.max(Discrete::zero());
+        let to_invest = match self.limits.max_turnover {
+            Some(_) => spare.min(Discrete::try_from_dense(Dense::from(
+                turnover_left.max(0.0),
+            ))?),
+            None => spare,
+        };
+        if to_invest > Discrete::zero() && !fractional.is_empty() && to_band_edge.is_none() {
+            // the parts add up to exactly the cash invested, so nothing is left behind
+            let targets: Vec<f64> = fractional.iter().map(|(_, target)| *target).collect();
+            let parts = to_invest.allocate(&targets, Remainder::LargestFraction)?;
+            for (notional, (asset, _)) in parts.into_iter().zip(fractional) {
+                let price = self.price_in::<USD>(asset)?;
+                if notional <= Discrete::zero() || price.amount() <= 0.0 {
+                    continue;
+                }
+                if notional.to_f64() <= self.rebalance_threshold.unwrap_or(0.0)
+                    || notional.to_f64() < self.limits.min_notional.unwrap_or(0.0)
+                {
+                    continue;
+                }
+                let ticker = asset.ticker();
+                let pre_weight = weights.get(&ticker).copied().unwrap_or(0.0);
+                let quantity = costs::quantity_for(
+                    self.cost_model.as_ref(),
+                    notional.to_f64(),
+                    price.amount(),
+                );
+                // fees would eat the whole part
+                if quantity <= 0.0 {
+                    continue;
+                }
+                orders.push(Order {
+                    ticker,
+                    side: Side::Buy,
+                    quantity,
+                    notional,
+                    cost: notional.checked_sub(Discrete::try_from_dense(price * quantity)?)?,
+                    reason: Reason::InvestCash,
+                    pre_weight,
+                    post_weight: pre_weight,
+                });
+                cash = cash.checked_sub(notional)?;
+            }
+        }
+        self.summarize(orders, cash)
+    }
+
+    /// Orders spending a deposit of `amount` on the positions furthest below their
+    /// targets, without selling anything: positions are topped up from the most
+    /// underweight until all the money is spent, and a `CASH` target keeps its share.
+    /// Positions harvested in the last 30 days, see `harvested`, aren't bought.
+    pub fn plan_deposit(&self, amount: Discrete<USD>) -> Result<RebalancePlan> {
+        if amount < Discrete::zero() {
+            return Err(anyhow::Error::msg("Deposit must be positive"));
+        }
+        let cash = self.cash.checked_add(amount)?;
+        let mut legs = self.cash_flow_legs()?;
+        let harvested = self.harvested(self.now()?);
+        // a harvested position gets none of the money, like one without a target
+        let shares: Vec<(f64, f64)> = legs
+            .iter()
+            .map(|leg| {
+                let target = if harvested.contains_key(leg.ticker.as_str()) {
+                    0.0
+                } else {
+                    leg.goal_weight
+                };
+                (target, leg.held * leg.price.amount())
+            })
+            .chain([(self.cash_target(), self.cash.to_f64())])
+            .collect();
+        let dollars = fill_up(&shares, amount.to_f64());
+        for (leg, dollars) in legs.iter_mut().zip(dollars) {
+            leg.trade = leg.rule.round_down(dollars / leg.price.amount());
+        }
+        fit_buys_to_cash(&mut legs, cash)?;
+        self.drop_small_trades(&mut legs)?;
+
+        let orders = orders_from(&legs, |_| Reason::Deposit)?;
+        let cash = cash_after(&legs, cash)?;
+        self.summarize(orders, cash)
+    }
+
+    /// Orders raising `amount` for a withdrawal. Cash above its target is used
+    /// first, then the most overweight positions are sold down until the
+    /// proceeds, after costs, cover the rest.
+    pub fn plan_withdrawal(&self, amount: Discrete<USD>) -> Result<RebalancePlan> {
+        if amount < Discrete::zero() {
+            return Err(anyhow::Error::msg("Withdrawal must be positive"));
+        }
+        let mut legs = self.cash_flow_legs()?;
+        let shares: Vec<(f64, f64)> = legs
+            .iter()
+            .map(|leg| (leg.goal_weight, leg.held * leg.price.amount()))
+            .chain([(self.cash_target(), self.cash.to_f64())])
+            .collect();
+        let dollars = draw_down(&shares, amount.to_f64());
+        for (leg, dollars) in legs.iter_mut().zip(&dollars) {
+            leg.trade = -round_up(leg.rule, dollars / leg.price.amount()).min(-leg.lowest());
+        }
+        // costs take a cut of the proceeds, sell a little more to make up for it
+        let needed = amount.checked_sub(Discrete::try_from_dense(Dense::from(
+            dollars.last().copied().unwrap_or(0.0),
+        ))?)?;
+        for _ in 0..10 {
+            let proceeds = cash_after(&legs, Discrete::zero())?;
+            if proceeds >= needed || proceeds <= Discrete::zero() {
+                break;
+            }
+            let scale = (needed.to_f64() + 0.01) / proceeds.to_f64();
+            for leg in legs.iter_mut().filter(|leg| leg.trade < 0.0) {
+                let quantity = round_up(leg.rule, -leg.trade * scale);
+                leg.trade = -quantity.min(-leg.lowest());
+            }
+        }
+        self.drop_small_trades(&mut legs)?;
+
+        let orders = orders_from(&legs, |_| Reason::Withdrawal)?;
+        let cash = cash_after(&legs, self.cash.checked_sub(amount)?)?;
+        self.summarize(orders, cash)
+    }
+
+    /// Adds `amount` to the cash and spends it as `plan_deposit` would.
+    pub fn deposit(&mut self, amount: Discrete<USD>) -> Result<Vec<Fill>> {
+        let plan = self.plan_deposit(amount)?;
+        self.commit(Event::Deposit { amount })?;
+        self.execute(&plan)
+    }
+
+    /// Sells as `plan_withdrawal` would and takes `amount` out of the cash. Nothing
+    /// is sold when the portfolio can't raise the amount.
+    pub fn withdraw(&mut self, amount: Discrete<USD>) -> Result<Vec<Fill>> {
+        let plan = self.plan_withdrawal(amount)?;
+        if plan.summary.cash_residual < Discrete::zero() {
+            return Err(anyhow::anyhow!(
+                "Can't raise {}, {} short",
+                amount,
+                -plan.summary.cash_residual
+            ));
+        }
+        let fills = self.execute(&plan)?;
+        self.commit(Event::Withdraw { amount })?;
+        Ok(fills)
+    }
+
+    // targeted positions with nothing to trade yet, the target as the goal
+    fn cash_flow_legs(&self) -> Result<Vec<Leg<'_>>> {
+        let weights = self.weights()?;
+        let mut legs = Vec::new();
+        for asset in self.assets() {
+            let ticker = asset.ticker();
+            let Some(&target_weight) = self.target_weights.get(&ticker) else {
+                continue;
+            };
+            legs.push(Leg {
+                pre_weight: weights.get(&ticker).copied().unwrap_or(0.0),
+                ticker,
+                price: self.price_in::<USD>(asset)?,
+                held: asset.amount_held(),
+                rule: asset.quantity_rule(),
+                costs: self.cost_model.as_ref(),
+                ideal: 0.0,
+                trade: 0.0,
+                goal_weight: target_weight,
+                benefit: 0.0,
+            });
+        }
+        Ok(legs)
+    }
+
+    fn cash_target(&self) -> f64 {
+        self.target_weights.get("CASH").copied().unwrap_or(0.0)
+    }
+
+    // drops trades moving no more than `rebalance_threshold` dollars, or fewer
+    // than `limits.min_notional`
+    fn drop_small_trades(&self, legs: &mut [Leg]) -> Result<()> {
+        if self.rebalance_threshold.is_some() || self.limits.min_notional.is_some() {
+            let threshold = self.rebalance_threshold.unwrap_or(0.0);
+            let min_notional = self.limits.min_notional.unwrap_or(0.0);
+            for leg in legs {
+                let notional = leg.notional()?.to_f64().abs();
+                if notional <= threshold || notional < min_notional {
+                    leg.trade = 0.0;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    // weighs the positions once `orders` are filled and sums them up, with `cash`
+    // left at the end
+    fn summarize(&self, mut orders: Vec<Order>, cash: Discrete<USD>) -> Result<RebalancePlan> {
+        // values once everything is filled, to weigh the positions after the plan
+        let mut values: HashMap<String, Discrete<USD>> = HashMap::new();
+        for asset in self.assets() {
+            values.insert(asset.ticker(), self.position_value_in::<USD>(asset)?);
+        }
+        let mut bought = Discrete::zero();
+        let mut sold = Discrete::zero();
+        let mut costs = Discrete::zero();
+        for order in &orders {
+            // positions move by the value traded, costs come out of the cash
+            let value = values.entry(order.ticker.clone()).or_default();
+            match order.side {
+                Side::Buy => {
+                    *value = value.checked_add(order.notional.checked_sub(order.cost)?)?;
+                    bought = bought.checked_add(order.notional)?;
+                }
+                Side::Sell => {
+                    *value = value.checked_sub(order.notional.checked_add(order.cost)?)?;
+                    sold = sold.checked_add(order.notional)?;
+                }
+            }
+            costs = costs.checked_add(order.cost)?;
+        }
+        let total_after = values
+            .values()
+            .try_fold(cash, |total, value| total.checked_add(*value))?;
+        for order in &mut orders {
+            order.post_weight = values[&order.ticker] / total_after;
+        }
+
+        let now = self.now()?;
+        let mut realized = Gains::zero();
+        for order in orders.iter().filter(|order| order.side == Side::Sell) {
+            let gains = self.estimate_gains(&order.ticker, order.quantity, order.notional, now)?;
+            realized.add(Term::Short, gains.short_term)?;
+            realized.add(Term::Long, gains.long_term)?;
+        }
+        let estimated_tax = match self.tax_policy {
+            Some(policy) => policy.tax_on(&realized)?,
+            None => Discrete::zero(),
+        };
+
+        Ok(RebalancePlan {
+            orders,
+            summary: PlanSummary {
+                turnover: bought.checked_add(sold)?,
+                bought,
+                sold,
+                costs,
+                cash_residual: cash,
+                realized,
+                estimated_tax,
+            },
+        })
+    }
+
+    /// Largest distance between a targeted position's weight and its target.
+    pub fn max_drift(&self) -> Result<f64> {
+        let weights = self.weights()?;
+        Ok(self
+            .target_weights
+            .iter()
+            .filter(|(ticker, _)| ticker.as_str() != "CASH")
+            .map(|(ticker, target)| (weights.get(ticker).copied().unwrap_or(0.0) - target).abs())
+            .fold(0.0, f64::max))
+    }
+
+    /// Whether any targeted position drifted out of its band.
+    pub fn outside_bands(&self, bands: &Bands) -> Result<bool> {
+        let weights = self.weights()?;
+        Ok(self
+            .target_weights
+            .iter()
+            .filter(|(ticker, _)| ticker.as_str() != "CASH")
+            .any(|(ticker, target)| {
+                let actual = weights.get(ticker).copied().unwrap_or(0.0);
+                (actual - target).abs() > bands.width(ticker, *target)
+            }))
+    }
+
+    /// Whether `rebalance_type` calls for a rebalance at `now`, a unix timestamp.
+    ///
+    /// Frequencies count days since `last_rebalanced` and are due right away on a
+    /// portfolio never rebalanced. `ThresholdAndFrequency` needs both: a weight
+    /// drifted out of its band and the days have passed since the last rebalance.
+    pub fn should_rebalance(&self, now: i64) -> Result<bool> {
+        let days_passed = |days: u32| match self.last_rebalanced {
+            Some(last) => now - last >= i64::from(days) * SECONDS_PER_DAY,
+            None => true,
+        };
+        Ok(match &self.rebalance_type {
+            RebalanceType::None => true,
+            RebalanceType::Threshold(bands) => self.outside_bands(bands)?,
+            RebalanceType::Frequency(days) => days_passed(*days),
+            RebalanceType::ThresholdAndFrequency(bands, days) => {
+                days_passed(*days) && self.outside_bands(bands)?
+            }
+        })
+    }
+
+    /// Plans and executes a rebalance if `should_rebalance(now)`, and records it in
+    /// the ledger at `now`, which makes `now` the last rebalance. Returns no fills when no rebalance was due.
+    pub fn rebalance_at(&mut self, now: i64) -> Result<Vec<Fill>> {
+        if !self.should_rebalance(now)? {
+            return Ok(Vec::new());
+        }
+        let plan = self.plan_rebalance()?;
+        // the trades and the rebalance happen at `now`
+        let clock = self.clock.replace(now);
+        let fills = self.execute(&plan).and_then(|fills| {
+            self.commit(Event::Rebalance)?;
+            Ok(fills)
+        });
+        self.clock = clock;
+        fills
+    }
+
+    /// Fills the orders of `plan` with paper trades at the current last prices.
+    /// When an order fails, none of them is filled.
+    pub fn execute(&mut self, plan: &RebalancePlan) -> Result<Vec<Fill>> {
+        // filled on a copy, kept only once every order went through
+        let mut portfolio = self.clone();
+        let fills = portfolio.fill_orders(plan)?;
+        *self = portfolio;
+        Ok(fills)
+    }
+
+    fn fill_orders(&mut self, plan: &RebalancePlan) -> Result<Vec<Fill>> {
+        let original_pvf = self.get_portfolio_value()?;
+        let original_cash = self.cash;
+
+        // net cash moved by the fills, kept to check the cash ledger against
+        let mut cash_flow = Discrete::zero();
+        let mut costs = Discrete::zero();
+        let mut fills = Vec::new();
+        for order in &plan.orders {
+            let (quantity, notional) = match (order.side, order.reason) {
+                (Side::Buy, Reason::InvestCash) => (
+                    self.paper_buy_notional(order.notional, &order.ticker)?,
+                    order.notional,
+                ),
+                (Side::Buy, _) => (
+                    order.quantity,
+                    self.paper_buy(order.quantity, &order.ticker)?,
+                ),
+                (Side::Sell, _) => (
+                    order.quantity,
+                    self.paper_sell_lots(order.quantity, &order.ticker, self.sale_method())?,
+                ),
+            };
+            let mark = Discrete::try_from_dense(
+                self.price_in::<USD>(self.position(&order.ticker)?)? * quantity,
+            )?;
+            let cost = match order.side {
+                Side::Buy => notional.checked_sub(mark)?,
+                Side::Sell => mark.checked_sub(notional)?,
+            };
+            cash_flow = match order.side {
+                Side::Buy => cash_flow.checked_sub(notional)?,
+                Side::Sell => cash_flow.checked_add(notional)?,
+            };
+            costs = costs.checked_add(cost)?;
+            fills.push(Fill {
+                ticker: order.ticker.clone(),
+                side: order.side,
+                quantity,
+                notional,
+                cost,
+            });
+        }
+
+        if self.cash != original_cash.checked_add(cash_flow)? {
+            return Err(anyhow::Error::msg("Cash ledger does not balance"));
+        }
+        // every fill moves cash by its exact notional, but positions are marked to the
+        // cent, so past the costs the value can only move by rounding: under a cent
+        // per fill
+        let drift = (self.get_portfolio_value()? - original_pvf + costs)
+            .minor_units()
+            .abs();
+        if drift > fills.len() as i128 {
+            return Err(anyhow::anyhow!(
+                "Portfolio value drifted by {} cents",
+                drift
+            ));
+        }
+        Ok(fills)
+    }
+}
+
+// a position the plan trades
+struct Leg<'a> {
+    ticker: String,
+    price: Dense<USD>,
+    held: f64,
+    rule: QuantityRule,
+    costs: &'a dyn CostModel,
+    // quantity that would reach the goal weight exactly, negative to sell
+    ideal: f64,
+    // quantity the plan trades
+    trade: f64,
+    pre_weight: f64,
+    goal_weight: f64,
+    // squared drift the whole trade removes
+    benefit: f64,
+}
+
+impl Leg<'_> {
+    // cuts the trade down to `budget` dollars, returns the dollars it moves
+    fn fit_turnover(&mut self, budget: f64) -> Result<f64> {
+        // costs don't scale with the quantity, so a few passes may be needed
+        for _ in 0..10 {
+            let notional = self.notional()?.to_f64().abs();
+            if notional <= budget || self.trade == 0.0 {
+                return Ok(notional);
+            }
+            // a cent under the budget, for the rounding of the notional
+            let scaled = self.trade.abs() * ((budget - 0.01).max(0.0) / notional);
+            self.trade = self.rule.round_down(scaled).copysign(self.trade);
+        }
+        self.trade = 0.0;
+        Ok(0.0)
+    }
+
+    // cash the trade moves, costs included
+    fn notional(&self) -> Result<Discrete<USD>> {
+        if self.trade == 0.0 {
+            return Ok(Discrete::zero());
+        }
+        let side = if self.trade < 0.0 {
+            Side::Sell
+        } else {
+            Side::Buy
+        };
+        let cash = costs::cash_for(self.costs, side, self.trade.abs(), self.price.amount());
+        Ok(Discrete::try_from_dense(Dense::from(cash))?)
+    }
+
+    // largest sale the rule allows out of the holding, as a negative trade
+    fn lowest(&self) -> f64 {
+        -self.rule.round_down(self.held)
+    }
+}
+
+// cash once the trades of `legs` are filled
+fn cash_after(legs: &[Leg], cash: Discrete<USD>) -> Result<Discrete<USD>> {
+    legs.iter().try_fold(cash, |cash, leg| {
+        Ok(if leg.trade < 0.0 {
+            cash.checked_add(leg.notional()?)?
+        } else {
+            cash.checked_sub(leg.notional()?)?
+        })
+    })
+}
+
+// squared distance of the weights of `legs` from their goals, with cash counted
+// against `cash_goal` when it is set
+fn tracking_error(
+    legs: &[Leg],
+    cash: Discrete<USD>,
+    total: f64,
+    cash_goal: Option<f64>,
+) -> Result<f64> {
+    let mut error = 0.0;
+    for leg in legs {
+        let weight = (leg.held + leg.trade) * leg.price.amount() / total;
+        error += (weight - leg.goal_weight).powi(2);
+    }
+    if let Some(goal) = cash_goal {
+        error += (cash_after(legs, cash)?.to_f64() / total - goal).powi(2);
+    }
+    Ok(error)
+}
+
+// orders for the trades of `legs`, sells first so the buys can spend the proceeds
+fn orders_from(legs: &[Leg], reason: impl Fn(Side) -> Reason) -> Result<Vec<Order>> {
+    let mut orders = Vec::new();
+    for leg in legs {
+        if leg.trade == 0.0 {
+            continue;
+        }
+        let side = if leg.trade < 0.0 {
+            Side::Sell
+        } else {
+            Side::Buy
+        };
+        let notional = leg.notional()?;
+        let mark = Discrete::try_from_dense(leg.price * leg.trade.abs())?;
+        orders.push(Order {
+            ticker: leg.ticker.clone(),
+            side,
+            quantity: leg.trade.abs(),
+            notional,
+            cost: match side {
+                Side::Buy => notional.checked_sub(mark)?,
+                Side::Sell => mark.checked_sub(notional)?,
+            },
+            reason: reason(side),
+            pre_weight: leg.pre_weight,
+            post_weight: leg.pre_weight,
+        });
+    }
+    orders.sort_by_key(|order| order.side == Side::Buy);
+    Ok(orders)
+}
+
+// steps of the rule of `leg` that `dollars` buy at its price, before costs
+fn steps_worth(leg: &Leg, dollars: f64) -> f64 {
+    let value = leg.rule.step().unwrap_or(0.0) * leg.price.amount();
+    if value > 0.0 {
+        dollars / value
+    } else {
+        0.0
+    }
+}
+
+// smallest tradable quantity not below `quantity`
+fn round_up(rule: QuantityRule, quantity: f64) -> f64 {
+    -rule.round_down(-quantity)
+}
+
+/// Spreads `amount` dollars over (target weight, value) pairs, most underweight
+/// first: the pairs furthest below their target are brought up to the same
+/// share of it, and no pair gets money while another sits lower. Pairs without
+/// a target get nothing.
+fn fill_up(pairs: &[(f64, f64)], amount: f64) -> Vec<f64> {
+    let mut order: Vec<usize> = (0..pairs.len()).filter(|&i| pairs[i].0 > 0.0).collect();
+    order.sort_by(|&a, &b| (pairs[a].1 / pairs[a].0).total_cmp(&(pairs[b].1 / pairs[b].0)));
+    // value per unit of target every pair below it is raised to
+    let mut level = 0.0;
+    let (mut targets, mut values) = (0.0, 0.0);
+    for (n, &i) in order.iter().enumerate() {
+        targets += pairs[i].0;
+        values += pairs[i].1;
+        level = (amount + values) / targets;
+        let next = order.get(n + 1).map(|&j| pairs[j].1 / pairs[j].0);
+        if next.is_none_or(|next| level <= next) {
+            break;
+        }
+    }
+    pairs
+        .iter()
+        .map(|(target, value)| (target * level - value).max(0.0))
+        .collect()
+}
+
+/// Takes `amount` dollars out of (target weight, value) pairs, most overweight
+/// first: pairs without a target are drawn from before any other, then the pairs
+/// furthest above their target are brought down to the same share of it.
+fn draw_down(pairs: &[(f64, f64)], amount: f64) -> Vec<f64> {
+    let mut taken = vec![0.0; pairs.len()];
+    let mut left = amount;
+    for (i, (target, value)) in pairs.iter().enumerate() {
+        if *target <= 0.0 && left > 0.0 {
+            taken[i] = value.min(left).max(0.0);
+            left -= taken[i];
+        }
+    }
+    let mut order: Vec<usize> = (0..pairs.len()).filter(|&i| pairs[i].0 > 0.0).collect();
+    order.sort_by(|&a, &b| (pairs[b].1 / pairs[b].0).total_cmp(&(pairs[a].1 / pairs[a].0)));
+    if left <= 0.0 || order.is_empty() {
+        return taken;
+    }
+    // value per unit of target every pair above it is lowered to
+    let mut level = 0.0;
+    let (mut targets, mut values) = (0.0, 0.0);
+    for (n, &i) in order.iter().enumerate() {
+        targets += pairs[i].0;
+        values += pairs[i].1;
+        level = ((values - left) / targets).max(0.0);
+        let next = order.get(n + 1).map(|&j| pairs[j].1 / pairs[j].0);
+        if next.is_none_or(|next| level >= next) {
+            break;
+        }
+    }
+    for &i in &order {
+        taken[i] = (pairs[i].1 - pairs[i].0 * level).max(0.0);
+    }
+    taken
+}
+
+/// Shrinks the buys of fractional legs until the cash covers them, costs make
+/// trading back to target spend more than the sells bring in. Buys in lots give
+/// back as many steps as the shortfall is worth, the last legs first, when
+/// that's not enough.
+fn fit_buys_to_cash(legs: &mut [Leg], cash: Discrete<USD>) -> Result<()> {
+    fit_fractional_buys(legs, cash)?;
+    for i in (0..legs.len()).rev() {
+        let Some(step) = legs[i].rule.step() else {
+            continue;
+        };
+        while legs[i].trade > 0.0 && cash_after(legs, cash)? < Discrete::zero() {
+            let shortfall = -cash_after(legs, cash)?.to_f64();
+            let steps = steps_worth(&legs[i], shortfall).ceil().max(1.0);
+            legs[i].trade = (legs[i].trade - steps * step).max(0.0);
+        }
+    }
+    Ok(())
+}
+
+fn fit_fractional_buys(legs: &mut [Leg], cash: Discrete<USD>) -> Result<()> {
+    let is_fractional_buy = |leg: &Leg| leg.trade > 0.0 && leg.rule.step().is_none();
+    // costs grow slower than the quantity, a few passes close the gap
+    for _ in 0..10 {
+        let shortfall = -cash_after(legs, cash)?.to_f64();
+        if shortfall <= 0.0 {
+            return Ok(());
+        }
+        let mut spent = 0.0;
+        for leg in legs.iter().filter(|leg| is_fractional_buy(leg)) {
+            spent += leg.notional()?.to_f64();
+        }
+        if spent <= 0.0 {
+            return Ok(());
+        }
+        // a cent more than the shortfall, for the rounding of the notionals
+        let scale = ((spent - shortfall - 0.01) / spent).max(0.0);
+        for leg in legs.iter_mut().filter(|leg| is_fractional_buy(leg)) {
+            leg.trade *= scale;
+        }
+    }
+    Ok(())
+}
+
+/// Rounds the trades of legs with a quantity rule to whole steps of it.
+///
+/// Every mix of rounding each trade up or down is tried (just down past
+/// `MAX_LOT_COMBINATIONS` legs). A mix that overspends gives back as many steps as
+/// the shortfall is worth where that costs the least tracking error, then leftover
+/// cash buys steps for as long as that brings the weights closer to their goals,
+/// as many as it affords or halves of that. The mix with the least tracking error
+/// wins; fractional legs keep their exact trade.
+fn round_to_lots(
+    legs: &mut [Leg],
+    cash: Discrete<USD>,
+    total: f64,
+    cash_goal: Option<f64>,
+) -> Result<()> {
+    let lots: Vec<usize> = (0..legs.len())
+        .filter(|&i| legs[i].rule.step().is_some())
+        .collect();
+    if lots.is_empty() || total <= 0.0 {
+        return Ok(());
+    }
+    let floors: Vec<f64> = lots
+        .iter()
+        .map(|&i| legs[i].rule.round_down(legs[i].ideal).max(legs[i].lowest()))
+        .collect();
+    let combinations = if lots.len() <= MAX_LOT_COMBINATIONS {
+        1usize << lots.len()
+    } else {
+        1
+    };
+
+    let mut best: Option<(f64, Vec<f64>)> = None;
+    for combination in 0..combinations {
+        for (n, &i) in lots.iter().enumerate() {
+            let step = legs[i].rule.step().unwrap_or(0.0);
+            let up = combination & (1 << n) != 0;
+            legs[i].trade = floors[n] + if up { step } else { 0.0 };
+        }
+
+        // give back steps until the cash covers the buys
+        let mut feasible = true;
+        loop {
+            let shortfall = -cash_after(legs, cash)?.to_f64();
+            if shortfall <= 0.0 {
+                break;
+            }
+            let mut cheapest: Option<(f64, usize, f64)> = None;
+            for &i in &lots {
+                let step = legs[i].rule.step().unwrap_or(0.0);
+                let room = ((legs[i].trade - legs[i].lowest()) / step + 1e-9).floor();
+                if room < 1.0 {
+                    continue;
+                }
+                let steps = steps_worth(&legs[i], shortfall).ceil().clamp(1.0, room);
+                let trade = legs[i].trade;
+                legs[i].trade -= steps * step;
+                let error = tracking_error(legs, cash, total, cash_goal)?;
+                legs[i].trade = trade;
+                if cheapest.is_none_or(|(e, _, _)| error < e) {
+                    cheapest = Some((error, i, steps));
+                }
+            }
+            match cheapest {
+                Some((_, i, steps)) => legs[i].trade -= steps * legs[i].rule.step().unwrap_or(0.0),
+                None => {
+                    feasible = false;
+                    break;
+                }
+            }
+        }
+        if !feasible {
+            continue;
+        }
+
+        // spend what's left while it helps
+        let mut error = tracking_error(legs, cash, total, cash_goal)?;
+        loop {
+            let left = cash_after(legs, cash)?.to_f64();
+            let mut better: Option<(f64, usize, f64)> = None;
+            for &i in &lots {
+                let step = legs[i].rule.step().unwrap_or(0.0);
+                let mut steps = steps_worth(&legs[i], left).floor().max(1.0);
+                while steps >= 1.0 {
+                    let trade = legs[i].trade;
+                    legs[i].trade += steps * step;
+                    if cash_after(legs, cash)? >= Discrete::zero() {
+                        let e = tracking_error(legs, cash, total, cash_goal)?;
+                        if e < better.map_or(error, |(b, _, _)| b) - 1e-12 {
+                            better = Some((e, i, steps));
+                        }
+                    }
+                    legs[i].trade = trade;
+                    steps = (steps / 2.0).floor();
+                }
+            }
+            match better {
+                Some((e, i, steps)) => {
+                    legs[i].trade += steps * legs[i].rule.step().unwrap_or(0.0);
+                    error = e;
+                }
+                None => break,
+            }
+        }
+
+        if best.as_ref().is_none_or(|(b, _)| error < *b) {
+            best = Some((error, lots.iter().map(|&i| legs[i].trade).collect()));
+        }
+    }
+
+    // with nothing affordable, round every trade down and let execution complain
+    let trades = best.map_or(floors, |(_, trades)| trades);
+    for (&i, trade) in lots.iter().zip(trades) {
+        // snap to the step so the quantity is an exact multiple of it, dividing
+        // for decimal steps since 0.1 and the like have no exact float
+        let step = legs[i].rule.step().unwrap_or(1.0);
+        let steps = (trade / step).round();
+        legs[i].trade = if step < 1.0 {
+            steps / step.recip().round()
+        } else {
+            steps * step
+        };
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use super::*;
+    use crate::costs::TradingCosts;
+    use crate::portfolio::Band;
+    use crate::price_source::InMemorySource;
+    use crate::tax::{LotMethod, TaxPolicy};
+
+    async fn portfolio(cash: i128) -> Portfolio {
+        let source = InMemorySource::new()
+            .with_price("AAA", 100.0)
+            .with_price("BBB", 100.0);
+        Portfolio::builder()
+            .price_source(Arc::new(source))
+            .add_asset("AAA", 3.0)
+            .add_asset("BBB", 1.0)
+            .target_weight("AAA", 0.5)
+            .target_weight("BBB", 0.5)
+            .cash(Discrete::new(cash))
+            .build()
+            .await
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_plan_does_not_touch_the_portfolio() {
+        let portfolio = portfolio(0).await;
+        let plan = portfolio.plan_rebalance().unwrap();
+
+        assert_eq!(plan.orders.len(), 2);
+        let sell = &plan.orders[0];
+        assert_eq!((sell.ticker.as_str(), sell.side), ("AAA", Side::Sell));
+        assert_eq!(sell.reason, Reason::Overweight);
+        assert_eq!(sell.quantity, 1.0);
+        assert_eq!(sell.notional, Discrete::new(10_000));
+        assert_eq!((sell.pre_weight, sell.post_weight), (0.75, 0.5));
+        let buy = &plan.orders[1];
+        assert_eq!((buy.ticker.as_str(), buy.side), ("BBB", Side::Buy));
+        assert_eq!((buy.pre_weight, buy.post_weight), (0.25, 0.5));
+
+        assert_eq!(plan.summary.turnover, Discrete::new(20_000));
+        assert_eq!(plan.summary.cash_residual, Discrete::zero());
+        assert_eq!(portfolio.position("AAA").unwrap().amount_held(), 3.0);
+        assert_eq!(portfolio.cash, Discrete::zero());
+    }
+
+    #[tokio::test]
+    async fn test_execute_fills_the_plan() {
+        let mut portfolio = portfolio(0).await;
+        let plan = portfolio.plan_rebalance().unwrap();
+        let fills = portfolio.execute(&plan).unwrap();
+
+        assert_eq!(fills.len(), 2);
+        assert_eq!(fills[0].notional, plan.orders[0].notional);
+        assert_eq!(portfolio.position("AAA").unwrap().amount_held(), 2.0);
+        assert_eq!(portfolio.position("BBB").unwrap().amount_held(), 2.0);
+        assert!(portfolio.plan_rebalance().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_failed_order_fills_nothing() {
+        let mut portfolio = portfolio(0).await;
+        let mut plan = portfolio.plan_rebalance().unwrap();
+        // the sale goes through, the buy then asks for more than it brings in
+        plan.orders[1].quantity = 5.0;
+        let entries = portfolio.ledger.len();
+        assert!(portfolio.execute(&plan).is_err());
+        assert_eq!(portfolio.ledger.len(), entries);
+        assert_eq!(portfolio.position("AAA").unwrap().amount_held(), 3.0);
+        assert_eq!(portfolio.cash, Discrete::zero());
+    }
+
+    #[tokio::test]
+    async fn test_plan_invests_leftover_cash() {
+        // on target, but a cent can't be split in two: the bigger target gets it
+        let mut portfolio = portfolio(1).await;
+        portfolio.target_weights.insert("AAA".to_string(), 0.75);
+        portfolio.target_weights.insert("BBB".to_string(), 0.25);
+        // drift of less than a cent isn't traded
+        portfolio.rebalance_threshold = Some(0.009);
+        let plan = portfolio.plan_rebalance().unwrap();
+        let invest: Vec<&Order> = plan
+            .orders
+            .iter()
+            .filter(|o| o.reason == Reason::InvestCash)
+            .collect();
+        assert_eq!(invest.len(), 1);
+        assert_eq!(invest[0].ticker, "AAA");
+        assert_eq!(invest[0].notional, Discrete::new(1));
+        assert_eq!(plan.summary.cash_residual, Discrete::zero());
+
+        portfolio.execute(&plan).unwrap();
+        assert_eq!(portfolio.cash, Discrete::zero());
+    }
+
+    #[tokio::test]
+    async fn test_leftover_cash_keeps_the_cash_target() {
+        let mut portfolio = portfolio(100_000).await;
+        portfolio.target_weights.insert("AAA".to_string(), 0.45);
+        portfolio.target_weights.insert("BBB".to_string(), 0.45);
+        portfolio.target_weights.insert("CASH".to_string(), 0.1);
+        let plan = portfolio.plan_rebalance().unwrap();
+
+        // 10% of the $1,400 stays cash instead of being invested 50/50
+        assert_eq!(plan.summary.cash_residual, Discrete::new(14_000));
+        portfolio.execute(&plan).unwrap();
+        let weights = portfolio.get_actual_weights().unwrap();
+        assert!((weights["AAA"] - 0.45).abs() < 1e-9);
+        assert!((weights["BBB"] - 0.45).abs() < 1e-9);
+        assert_eq!(portfolio.cash, Discrete::new(14_000));
+    }
+
+    #[tokio::test]
+    async fn test_whole_shares_minimize_tracking_error() {
+        let source = InMemorySource::new()
+            .with_price("AAA", 300.0)
+            .with_price("BBB", 70.0);
+        let mut portfolio = Portfolio::builder()
+            .price_source(Arc::new(source))
+            .add_asset("AAA", 0.0)
+            .add_asset("BBB", 0.0)
+            .quantity_rule("AAA", QuantityRule::WholeShares)
+            .quantity_rule("BBB", QuantityRule::WholeShares)
+            .target_weight("AAA", 0.5)
+            .target_weight("BBB", 0.5)
+            .cash(Discrete::new(100_000))
+            .build()
+            .await
+            .unwrap();
+        let plan = portfolio.plan_rebalance().unwrap();
+
+        // rounding down and spending the rest on BBB would end at 1 AAA and 9 BBB,
+        // 30% and 63%, where 2 AAA and 5 BBB get to 60% and 35%
+        let quantities: Vec<(&str, f64)> = plan
+            .orders
+            .iter()
+            .map(|o| (o.ticker.as_str(), o.quantity))
+            .collect();
+        assert_eq!(quantities, [("AAA", 2.0), ("BBB", 5.0)]);
+        assert_eq!(plan.summary.cash_residual, Discrete::new(5_000));
+
+        portfolio.execute(&plan).unwrap();
+        assert_eq!(portfolio.cash, Discrete::new(5_000));
+    }
+
+    #[tokio::test]
+    async fn test_lots_leave_the_rest_to_fractional_positions() {
+        let source = InMemorySource::new()
+            .with_price("AAA", 1.0)
+            .with_price("BBB", 10.0);
+        let mut portfolio = Portfolio::builder()
+            .price_source(Arc::new(source))
+            .add_asset("AAA", 0.0)
+            .add_asset("BBB", 0.0)
+            .quantity_rule("AAA", QuantityRule::Lot(100.0))
+            .target_weight("AAA", 0.5)
+            .target_weight("BBB", 0.5)
+            .cash(Discrete::new(105_000))
+            .build()
+            .await
+            .unwrap();
+        let plan = portfolio.plan_rebalance().unwrap();
+
+        assert_eq!(plan.orders[0].ticker, "AAA");
+        assert_eq!(plan.orders[0].quantity, 500.0);
+        let invest = plan.orders.last().unwrap();
+        assert_eq!(
+            (invest.ticker.as_str(), invest.reason),
+            ("BBB", Reason::InvestCash)
+        );
+        assert_eq!(invest.notional, Discrete::new(2_500));
+        assert_eq!(plan.summary.cash_residual, Discrete::zero());
+
+        portfolio.execute(&plan).unwrap();
+        assert!(portfolio.paper_buy(150.0, "AAA").is_err());
+        assert!(portfolio.paper_sell(50.0, "AAA").is_err());
+        assert!(portfolio
+            .paper_buy_notional(Discrete::new(100), "AAA")
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn test_costs_are_planned_and_paid() {
+        let mut portfolio = portfolio(0).await;
+        portfolio.cost_model = Arc::new(TradingCosts {
+            commission_bps: 10.0,
+            ..Default::default()
+        });
+        let plan = portfolio.plan_rebalance().unwrap();
+
+        // the sale brings in 10 cents less, so the buy shrinks to fit
+        let sell = &plan.orders[0];
+        assert_eq!(sell.notional, Discrete::new(9_990));
+        assert_eq!(sell.cost, Discrete::new(10));
+        let buy = &plan.orders[1];
+        assert!(buy.notional <= Discrete::new(9_990));
+        assert!(plan.summary.cash_residual >= Discrete::zero());
+        assert!(plan.summary.costs >= Discrete::new(19));
+
+        let value = portfolio.get_portfolio_value().unwrap();
+        let fills = portfolio.execute(&plan).unwrap();
+        let paid = fills
+            .iter()
+            .try_fold(Discrete::zero(), |total, fill| total.checked_add(fill.cost))
+            .unwrap();
+        assert_eq!(paid, plan.summary.costs);
+        assert!(portfolio.cash >= Discrete::zero());
+        let lost = value - portfolio.get_portfolio_value().unwrap();
+        assert!((lost - paid).minor_units().abs() <= 2);
+    }
+
+    #[tokio::test]
+    async fn test_small_steps_give_back_costs_at_once() {
+        let source = InMemorySource::new()
+            .with_price("BTC", 60_000.0)
+            .with_price("ETH", 3_000.0);
+        let portfolio = Portfolio::builder()
+            .price_source(Arc::new(source))
+            .add_asset("BTC", 0.0)
+            .add_asset("ETH", 0.0)
+            .quantity_rule("BTC", QuantityRule::Decimals(8))
+            .quantity_rule("ETH", QuantityRule::Decimals(8))
+            .target_weight("BTC", 0.6)
+            .target_weight("ETH", 0.4)
+            .cash(Discrete::new(100_000_000))
+            .cost_model(Arc::new(TradingCosts {
+                commission_bps: 10.0,
+                ..Default::default()
+            }))
+            .build()
+            .await
+            .unwrap();
+        let plan = portfolio.plan_rebalance().unwrap();
+
+        // the commission on a million is given back in a few jumps, not a step
+        // of 0.00000001 at a time
+        assert_eq!(plan.orders.len(), 2);
+        for order in &plan.orders {
+            assert!(QuantityRule::Decimals(8).allows(order.quantity));
+        }
+        assert!(plan.summary.cash_residual >= Discrete::zero());
+        assert!(plan.summary.cash_residual < Discrete::new(100));
+    }
+
+    #[tokio::test]
+    async fn test_drift_penalty_skips_costly_trades() {
+        let mut portfolio = portfolio(0).await;
+        portfolio.cost_model = Arc::new(TradingCosts {
+            flat_fee: 1.0,
+            ..Default::default()
+        });
+        // a dollar to move a hundred back: 1% of the notional
+        portfolio.drift_penalty = Some(0.005);
+        assert!(portfolio.plan_rebalance().unwrap().is_empty());
+        portfolio.drift_penalty = Some(0.02);
+        assert_eq!(portfolio.plan_rebalance().unwrap().orders.len(), 2);
+    }
+
+    // AAA 60% for 30%, BBB 20% for 30% and CCC 20% for 40%, $1000 in all
+    async fn three_positions(limits: TradeLimits) -> RebalancePlan {
+        let source = InMemorySource::new()
+            .with_price("AAA", 100.0)
+            .with_price("BBB", 100.0)
+            .with_price("CCC", 100.0);
+        let portfolio = Portfolio::builder()
+            .price_source(Arc::new(source))
+            .add_asset("AAA", 6.0)
+            .add_asset("BBB", 2.0)
+            .add_asset("CCC", 2.0)
+            .target_weight("AAA", 0.3)
+            .target_weight("BBB", 0.3)
+            .target_weight("CCC", 0.4)
+            .limits(limits)
+            .build()
+            .await
+            .unwrap();
+        portfolio.plan_rebalance().unwrap()
+    }
+
+    fn tickers(plan: &RebalancePlan) -> Vec<&str> {
+        plan.orders.iter().map(|o| o.ticker.as_str()).collect()
+    }
+
+    #[tokio::test]
+    async fn test_max_orders_keeps_the_biggest_drift() {
+        let plan = three_positions(TradeLimits::default()).await;
+        // sells first, then the buys by the drift they remove
+        assert_eq!(tickers(&plan), ["AAA", "CCC", "BBB"]);
+
+        let plan = three_positions(TradeLimits {
+            max_orders: Some(2),
+            ..Default::default()
+        })
+        .await;
+        assert_eq!(tickers(&plan), ["AAA", "CCC"]);
+        assert_eq!(plan.summary.cash_residual, Discrete::new(10_000));
+    }
+
+    #[tokio::test]
+    async fn test_max_turnover_cuts_the_last_trade() {
+        let plan = three_positions(TradeLimits {
+            max_turnover: Some(400.0),
+            ..Default::default()
+        })
+        .await;
+        assert_eq!(&tickers(&plan)[..2], ["AAA", "CCC"]);
+        assert_eq!(plan.orders[0].notional, Discrete::new(30_000));
+        assert!(plan.orders[1].notional < Discrete::new(10_000));
+        assert!(plan.summary.turnover <= Discrete::new(40_000));
+        assert!(plan.summary.turnover >= Discrete::new(39_900));
+    }
+
+    #[tokio::test]
+    async fn test_min_notional_drops_small_orders() {
+        let plan = three_positions(TradeLimits {
+            min_notional: Some(150.0),
+            ..Default::default()
+        })
+        .await;
+        // BBB's $100 is too small, and so is a third of the cash left
+        assert_eq!(tickers(&plan), ["AAA", "CCC"]);
+        assert_eq!(plan.summary.cash_residual, Discrete::new(10_000));
+    }
+
+    #[tokio::test]
+    async fn test_min_notional_applies_to_the_final_notional() {
+        let mut portfolio = portfolio(0).await;
+        portfolio.cost_model = Arc::new(TradingCosts {
+            flat_fee: 1.0,
+            ..Default::default()
+        });
+        // $100 drifted, but after the fee both orders move $99
+        portfolio.limits.min_notional = Some(99.5);
+        assert!(portfolio.plan_rebalance().unwrap().is_empty());
+        portfolio.limits.min_notional = Some(98.0);
+        assert_eq!(portfolio.plan_rebalance().unwrap().orders.len(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_threshold_applies_to_the_final_notional() {
+        let mut portfolio = portfolio(0).await;
+        portfolio.cost_model = Arc::new(TradingCosts {
+            flat_fee: 1.0,
+            ..Default::default()
+        });
+        // $100 drifted, but after the fee both orders move $99
+        portfolio.rebalance_threshold = Some(99.5);
+        assert!(portfolio.plan_rebalance().unwrap().is_empty());
+        portfolio.rebalance_threshold = Some(98.0);
+        assert_eq!(portfolio.plan_rebalance().unwrap().orders.len(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_threshold_schedule() {
+        // AAA is 25 points over its target
+        let mut portfolio = portfolio(0).await;
+        portfolio.rebalance_type = RebalanceType::Threshold(Bands::absolute(0.3));
+        assert!(!portfolio.should_rebalance(0).unwrap());
+        assert!(portfolio.rebalance_at(0).unwrap().is_empty());
+        assert_eq!(portfolio.last_rebalanced, None);
+
+        portfolio.rebalance_type = RebalanceType::Threshold(Bands::absolute(0.2));
+        assert!(portfolio.should_rebalance(0).unwrap());
+        assert_eq!(portfolio.rebalance_at(0).unwrap().len(), 2);
+        assert!(!portfolio.should_rebalance(0).unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_relative_bands_and_overrides() {
+        // AAA is at 75% for a 50% target, BBB at 25%
+        let mut portfolio = portfolio(0).await;
+        // half of 50% either side
+        portfolio.rebalance_type = RebalanceType::Threshold(Bands::relative(0.5));
+        assert!(!portfolio.should_rebalance(0).unwrap());
+        portfolio.rebalance_type = RebalanceType::Threshold(Bands::relative(0.4));
+        assert!(portfolio.should_rebalance(0).unwrap());
+
+        let bands = Bands::relative(0.5).with_override("BBB", Band::Absolute(0.2));
+        assert_eq!(bands.width("AAA", 0.5), 0.25);
+        portfolio.rebalance_type = RebalanceType::Threshold(bands);
+        assert!(portfolio.should_rebalance(0).unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_rebalance_to_band_edge() {
+        let mut portfolio = portfolio(0).await;
+        let bands = Bands::absolute(0.2)
+            .with_override("BBB", Band::Absolute(0.3))
+            .rebalance_to(RebalanceTo::BandEdge);
+        portfolio.rebalance_type = RebalanceType::Threshold(bands);
+        let plan = portfolio.plan_rebalance().unwrap();
+
+        // AAA is sold down to 70%, BBB is inside its band and the cash stays
+        assert_eq!(plan.orders.len(), 1);
+        let sell = &plan.orders[0];
+        assert_eq!((sell.ticker.as_str(), sell.side), ("AAA", Side::Sell));
+        assert_eq!(sell.notional, Discrete::new(2_000));
+        assert!((sell.post_weight - 0.7).abs() < 1e-9);
+        assert_eq!(plan.summary.cash_residual, Discrete::new(2_000));
+
+        portfolio.rebalance_at(0).unwrap();
+        assert_eq!(portfolio.cash, Discrete::new(2_000));
+        assert!(!portfolio.should_rebalance(0).unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_frequency_schedule() {
+        let mut portfolio = portfolio(0).await;
+        portfolio.rebalance_type = RebalanceType::Frequency(30);
+        let day = SECONDS_PER_DAY;
+        assert!(portfolio.should_rebalance(0).unwrap());
+        assert_eq!(portfolio.rebalance_at(10 * day).unwrap().len(), 2);
+        assert_eq!(portfolio.last_rebalanced, Some(10 * day));
+
+        // due 30 days later, on target or not
+        assert!(!portfolio.should_rebalance(39 * day).unwrap());
+        assert!(portfolio.should_rebalance(40 * day).unwrap());
+        assert!(portfolio.rebalance_at(40 * day).unwrap().is_empty());
+        assert_eq!(portfolio.last_rebalanced, Some(40 * day));
+    }
+
+    #[tokio::test]
+    async fn test_threshold_and_frequency_schedule() {
+        let mut portfolio = portfolio(0).await;
+        portfolio.rebalance_type = RebalanceType::ThresholdAndFrequency(Bands::absolute(0.2), 30);
+        let day = SECONDS_PER_DAY;
+        portfolio.last_rebalanced = Some(0);
+        // drifted, but too soon
+        assert!(!portfolio.should_rebalance(29 * day).unwrap());
+        assert!(portfolio.should_rebalance(30 * day).unwrap());
+        portfolio.rebalance_at(30 * day).unwrap();
+
+        // days passed, but on target
+        assert!(!portfolio.should_rebalance(90 * day).unwrap());
+        assert!(portfolio.rebalance_at(90 * day).unwrap().is_empty());
+        assert_eq!(portfolio.last_rebalanced, Some(30 * day));
+    }
+
+    #[tokio::test]
+    async fn test_threshold_skips_small_drift() {
+        let mut portfolio = portfolio(0).await;
+        portfolio.rebalance_threshold = Some(100.0);
+        assert!(portfolio.plan_rebalance().unwrap().is_empty());
+        portfolio.rebalance_threshold = Some(99.0);
+        assert_eq!(portfolio.plan_rebalance().unwrap().orders.len(), 2);
+    }
+
+    // 2 AAA bought at 100 on day 0 and 2 more at 50 on `second_buy`, then AAA
+    // at 150 on `now`, well over its half of the portfolio
+    async fn taxed(second_buy: i64, now: i64, tax_policy: Option<TaxPolicy>) -> Portfolio {
+        let day = SECONDS_PER_DAY;
+        let source = InMemorySource::new()
+            .with_price("AAA", 100.0)
+            .with_price("BBB", 100.0);
+        let mut portfolio = Portfolio::builder()
+            .price_source(Arc::new(source))
+            .add_asset("AAA", 0.0)
+            .add_asset("BBB", 0.0)
+            .target_weight("AAA", 0.5)
+            .target_weight("BBB", 0.5)
+            .cash(Discrete::new(40_000))
+            .tax_policy(tax_policy)
+            .build()
+            .await
+            .unwrap();
+        portfolio.clock = Some(0);
+        portfolio.paper_buy(2.0, "AAA").unwrap();
+        portfolio.paper_buy(1.0, "BBB").unwrap();
+        portfolio.clock = Some(second_buy * day);
+        portfolio.set_price("AAA", 50.0).unwrap();
+        portfolio.paper_buy(2.0, "AAA").unwrap();
+        portfolio.clock = Some(now * day);
+        portfolio.set_price("AAA", 150.0).unwrap();
+        portfolio
+    }
+
+    const RATES: TaxPolicy = TaxPolicy {
+        short_term_rate: 0.4,
+        long_term_rate: 0.15,
+        defer_short_term: None,
+    };
+
+    #[tokio::test]
+    async fn test_tax_aware_plan_sells_long_term_lots() {
+        let mut portfolio = taxed(400, 450, None).await;
+        portfolio.lot_method = LotMethod::Lifo;
+        let plan = portfolio.plan_rebalance().unwrap();
+        assert_eq!(plan.orders[0].notional, Discrete::new(25_000));
+        // the newest lot, bought at 50 less than a year ago
+        assert_eq!(plan.summary.realized.short_term, Discrete::new(16_667));
+        assert_eq!(plan.summary.estimated_tax, Discrete::zero());
+
+        portfolio.tax_policy = Some(RATES);
+        let plan = portfolio.plan_rebalance().unwrap();
+        // the lot at 100, held over a year
+        assert_eq!(plan.summary.realized.short_term, Discrete::zero());
+        assert_eq!(plan.summary.realized.long_term, Discrete::new(8_333));
+        assert_eq!(plan.summary.estimated_tax, Discrete::new(1_250));
+
+        portfolio.execute(&plan).unwrap();
+        assert_eq!(portfolio.realized_gains().unwrap(), plan.summary.realized);
+    }
+
+    #[tokio::test]
+    async fn test_tax_aware_plan_defers_short_term_gains() {
+        let deferring = TaxPolicy {
+            defer_short_term: Some(0.4),
+            ..RATES
+        };
+        // every lot would realize a short-term gain, and AAA is 0.36 over target
+        let portfolio = taxed(100, 150, Some(deferring)).await;
+        assert!(portfolio.plan_rebalance().unwrap().is_empty());
+
+        let tighter = TaxPolicy {
+            defer_short_term: Some(0.3),
+            ..RATES
+        };
+        let portfolio = taxed(100, 150, Some(tighter)).await;
+        let plan = portfolio.plan_rebalance().unwrap();
+        assert_eq!(plan.orders.len(), 2);
+        assert!(plan.summary.estimated_tax > Discrete::zero());
+
+        // a year later the first lot is long-term and can go
+        let portfolio = taxed(100, 400, Some(deferring)).await;
+        let plan = portfolio.plan_rebalance().unwrap();
+        assert_eq!(plan.summary.realized.short_term, Discrete::zero());
+        assert_eq!(plan.orders[0].side, Side::Sell);
+    }
+
+    #[tokio::test]
+    async fn test_deposit_buys_the_most_underweight() {
+        let mut portfolio = portfolio(0).await;
+        let fills = portfolio.deposit(Discrete::new(10_000)).unwrap();
+        assert_eq!(fills.len(), 1);
+        assert_eq!(
+            (fills[0].ticker.as_str(), fills[0].side),
+            ("BBB", Side::Buy)
+        );
+        assert_eq!(portfolio.position("BBB").unwrap().amount_held(), 2.0);
+        assert_eq!(portfolio.cash, Discrete::zero());
+
+        // once level, the rest is split by target
+        let plan = portfolio.plan_deposit(Discrete::new(40_000)).unwrap();
+        assert!(plan.orders.iter().all(|o| o.side == Side::Buy));
+        assert!(plan.orders.iter().all(|o| o.reason == Reason::Deposit));
+        assert_eq!(plan.summary.bought, Discrete::new(40_000));
+        assert_eq!(plan.summary.cash_residual, Discrete::zero());
+        assert!(plan.orders.iter().all(|o| o.post_weight == 0.5));
+    }
+
+    #[tokio::test]
+    async fn test_deposit_keeps_the_cash_target() {
+        let mut portfolio = portfolio(0).await;
+        portfolio.target_weights.insert("AAA".to_string(), 0.45);
+        portfolio.target_weights.insert("BBB".to_string(), 0.45);
+        portfolio.target_weights.insert("CASH".to_string(), 0.1);
+        let plan = portfolio.plan_deposit(Discrete::new(60_000)).unwrap();
+        // 1000 in all, 450 each and 100 of cash
+        assert_eq!(tickers(&plan), ["AAA", "BBB"]);
+        assert_eq!(plan.orders[0].notional, Discrete::new(15_000));
+        assert_eq!(plan.orders[1].notional, Discrete::new(35_000));
+        assert_eq!(plan.summary.cash_residual, Discrete::new(10_000));
+    }
+
+    #[tokio::test]
+    async fn test_withdraw_sells_the_most_overweight() {
+        let mut portfolio = portfolio(0).await;
+        let plan = portfolio.plan_withdrawal(Discrete::new(10_000)).unwrap();
+        assert_eq!(tickers(&plan), ["AAA"]);
+        assert_eq!(plan.orders[0].reason, Reason::Withdrawal);
+        assert_eq!(plan.orders[0].quantity, 1.0);
+
+        portfolio.withdraw(Discrete::new(10_000)).unwrap();
+        assert_eq!(portfolio.position("AAA").unwrap().amount_held(), 2.0);
+        assert_eq!(portfolio.position("BBB").unwrap().amount_held(), 1.0);
+        assert_eq!(portfolio.cash, Discrete::zero());
+    }
+
+    #[tokio::test]
+    async fn test_withdraw_spends_cash_first() {
+        let mut portfolio = portfolio(20_000).await;
+        let fills = portfolio.withdraw(Discrete::new(15_000)).unwrap();
+        assert!(fills.is_empty());
+        assert_eq!(portfolio.cash, Discrete::new(5_000));
+
+        // 50 of cash left, the other 50 from AAA
+        let plan = portfolio.plan_withdrawal(Discrete::new(10_000)).unwrap();
+        assert_eq!(tickers(&plan), ["AAA"]);
+        assert_eq!(plan.orders[0].notional, Discrete::new(5_000));
+    }
+
+    #[tokio::test]
+    async fn test_withdraw_covers_costs_and_lots() {
+        let mut portfolio = portfolio(0).await;
+        portfolio.cost_model = Arc::new(TradingCosts {
+            flat_fee: 1.0,
+            ..Default::default()
+        });
+        portfolio
+            .positions
+            .iter_mut()
+            .for_each(|p| p.set_quantity_rule(QuantityRule::WholeShares));
+        let plan = portfolio.plan_withdrawal(Discrete::new(15_000)).unwrap();
+        // 1.5 shares to sell rounds up to 2 and covers the fee
+        assert_eq!(plan.orders[0].quantity, 2.0);
+        assert!(plan.summary.cash_residual >= Discrete::zero());
+
+        let cash = portfolio.cash;
+        assert!(portfolio.withdraw(Discrete::new(1_000_000)).is_err());
+        assert_eq!(portfolio.cash, cash);
+        assert_eq!(portfolio.position("AAA").unwrap().amount_held(), 3.0);
+    }
+}
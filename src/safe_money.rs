@@ -1,6 +1,67 @@
+mod allocate;
+mod checked;
+mod format;
+mod fx;
+mod rounding;
+mod serialize;
+pub use allocate::Remainder;
+pub use checked::MoneyError;
+pub use format::{MoneyFormat, NegativeStyle, ParseMoneyError, SymbolPosition, SymbolStyle};
+pub use fx::FxRates;
+pub use rounding::Rounding;
+
 pub trait Currency {
     fn name() -> &'static str;
     fn symbol() -> &'static str;
+    /// Sign written next to amounts, e.g. "$" or "€". Defaults to the code.
+    fn sign() -> &'static str {
+        Self::symbol()
+    }
+    /// Number of decimal places of the smallest unit, 2 for currencies with cents.
+    fn minor_units() -> u32 {
+        2
+    }
+}
+
+// currencies that only exist as markers for `Dense<C>` and `Discrete<C>`
+macro_rules! currencies {
+    ($($currency:ident => ($name:literal, $sign:literal, $minor_units:literal)),* $(,)?) => {
+        $(
+            #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+            pub struct $currency;
+
+            impl Currency for $currency {
+                fn name() -> &'static str {
+                    $name
+                }
+
+                fn symbol() -> &'static str {
+                    stringify!($currency)
+                }
+
+                fn sign() -> &'static str {
+                    $sign
+                }
+
+                fn minor_units() -> u32 {
+                    $minor_units
+                }
+            }
+        )*
+    };
+}
+
+currencies! {
+    EUR => ("Euro", "€", 2),
+    GBP => ("Pound Sterling", "£", 2),
+    JPY => ("Japanese Yen", "¥", 0),
+    CHF => ("Swiss Franc", "CHF", 2),
+    CAD => ("Canadian Dollar", "CA$", 2),
+    AUD => ("Australian Dollar", "A$", 2),
+    HKD => ("Hong Kong Dollar", "HK$", 2),
+    BHD => ("Bahraini Dinar", "BHD", 3),
+    BTC => ("Bitcoin", "₿", 8),
+    ETH => ("Ether", "Ξ", 18),
 }
 
 #[derive(Debug, Clone, Copy, PartialEq, PartialOrd)]
@@ -20,17 +81,6 @@ impl USD {
     }
 }
 
-impl std::ops::SubAssign<f64> for USD {
-    fn sub_assign(&mut self, other: f64) {
-        self.amount -= other;
-    }
-}
-impl std::ops::AddAssign<f64> for USD {
-    fn add_assign(&mut self, other: f64) {
-        self.amount += other;
-    }
-}
-
 impl std::ops::AddAssign<USD> for USD {
     fn add_assign(&mut self, other: USD) {
         self.amount += other.amount;
@@ -53,25 +103,6 @@ impl std::convert::From<f64> for USD {
     }
 }
 
-impl Add<USD> for f64 {
-    type Output = USD;
-
-    fn add(self, other: USD) -> USD {
-        USD {
-            amount: self + other.amount,
-            ..other
-        }
-    }
-}
-
-impl Div<USD> for f64 {
-    type Output = f64;
-
-    fn div(self, other: USD) -> f64 {
-        self / other.amount
-    }
-}
-
 impl Mul<USD> for f64 {
     type Output = USD;
 
@@ -123,28 +154,37 @@ impl Add for USD {
     }
 }
 
-impl Mul for USD {
+impl Mul<f64> for USD {
     type Output = Self;
 
-    fn mul(self, other: Self) -> Self {
+    fn mul(self, scalar: f64) -> Self {
         Self {
-            amount: self.amount * other.amount,
+            amount: self.amount * scalar,
             ..self
         }
     }
 }
 
-impl Div for USD {
+impl Div<f64> for USD {
     type Output = Self;
 
-    fn div(self, other: Self) -> Self {
+    fn div(self, scalar: f64) -> Self {
         Self {
-            amount: self.amount / other.amount,
+            amount: self.amount / scalar,
             ..self
         }
     }
 }
 
+// dollars over dollars is a plain ratio
+impl Div for USD {
+    type Output = f64;
+
+    fn div(self, other: Self) -> f64 {
+        self.amount / other.amount
+    }
+}
+
 impl std::fmt::Display for USD {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         write!(f, "{:.2} {}", self.amount, self.symbol)
@@ -159,13 +199,66 @@ impl Currency for USD {
     fn symbol() -> &'static str {
         "USD"
     }
+
+    fn sign() -> &'static str {
+        "$"
+    }
 }
 
+/// Continuous money: an `f64` amount of `C`, for prices and intermediate results.
+///
+/// Money only scales by plain numbers, multiplying two amounts or mixing
+/// currencies does not compile:
+///
+/// ```compile_fail
+/// use beta_balancing::safe_money::{Dense, EUR, USD};
+/// let total = Dense::<USD>::from(1.0) + Dense::<EUR>::from(1.0);
+/// ```
+///
+/// ```compile_fail
+/// use beta_balancing::safe_money::{Dense, USD};
+/// let squared = Dense::<USD>::from(1.0) * Dense::<USD>::from(1.0);
+/// ```
 pub struct Dense<C: Currency> {
     amount: f64,
     _currency: PhantomData<C>,
 }
 
+impl<C: Currency> Dense<C> {
+    pub fn amount(&self) -> f64 {
+        self.amount
+    }
+
+    /// Rounds to a whole number of `C`'s minor units.
+    pub fn round(&self, rounding: Rounding) -> Discrete<C> {
+        Discrete::from_dense_with(*self, rounding)
+    }
+
+    pub fn abs(&self) -> Self {
+        Self::from(self.amount.abs())
+    }
+}
+
+impl<C: Currency> Clone for Dense<C> {
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+
+impl<C: Currency> Copy for Dense<C> {}
+
+impl<C: Currency> PartialEq for Dense<C> {
+    fn eq(&self, other: &Self) -> bool {
+        self.amount == other.amount
+    }
+}
+
+impl<C: Currency> PartialOrd for Dense<C> {
+    fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
+        self.amount.partial_cmp(&other.amount)
+    }
+}
+
 impl<C: Currency> Add for Dense<C> {
     type Output = Self;
 
@@ -187,25 +280,43 @@ impl<C: Currency> Sub for Dense<C> {
     }
 }
 
-impl<C: Currency> Mul for Dense<C> {
+impl<C: Currency> std::ops::Neg for Dense<C> {
     type Output = Self;
 
-    fn mul(self, other: Self) -> Self {
-        Self {
-            amount: self.amount * other.amount,
-            _currency: PhantomData,
-        }
+    fn neg(self) -> Self {
+        Self::from(-self.amount)
     }
 }
 
-impl<C: Currency> Div for Dense<C> {
+impl<C: Currency> Mul<f64> for Dense<C> {
     type Output = Self;
 
-    fn div(self, other: Self) -> Self {
-        Self {
-            amount: self.amount / other.amount,
-            _currency: PhantomData,
-        }
+    fn mul(self, scalar: f64) -> Self {
+        Self::from(self.amount * scalar)
+    }
+}
+
+impl<C: Currency> Mul<Dense<C>> for f64 {
+    type Output = Dense<C>;
+
+    fn mul(self, money: Dense<C>) -> Dense<C> {
+        money * self
+    }
+}
+
+impl<C: Currency> Div<f64> for Dense<C> {
+    type Output = Self;
+
+    fn div(self, scalar: f64) -> Self {
+        Self::from(self.amount / scalar)
+    }
+}
+
+impl<C: Currency> Div for Dense<C> {
+    type Output = f64;
+
+    fn div(self, other: Self) -> f64 {
+        self.amount / other.amount
     }
 }
 
@@ -214,7 +325,7 @@ impl<C: Currency> Add for Discrete<C> {
 
     fn add(self, other: Self) -> Self {
         Self {
-            cents: self.cents + other.cents,
+            units: self.units + other.units,
             _currency: PhantomData,
         }
     }
@@ -224,29 +335,53 @@ impl<C: Currency> Sub for Discrete<C> {
     type Output = Self;
     fn sub(self, other: Self) -> Self {
         Self {
-            cents: self.cents - other.cents,
+            units: self.units - other.units,
             _currency: PhantomData,
         }
     }
 }
 
-impl<C: Currency> Mul for Discrete<C> {
+impl<C: Currency> Mul<i64> for Discrete<C> {
     type Output = Self;
-    fn mul(self, other: Self) -> Self {
-        Self {
-            cents: self.cents * other.cents,
-            _currency: PhantomData,
-        }
+    fn mul(self, scalar: i64) -> Self {
+        Self::new(self.units * scalar as i128)
     }
 }
 
-impl<C: Currency> Div for Discrete<C> {
+impl<C: Currency> Mul<Discrete<C>> for i64 {
+    type Output = Discrete<C>;
+    fn mul(self, money: Discrete<C>) -> Discrete<C> {
+        money * self
+    }
+}
+
+// scaling by a fraction leaves the cents, so the result is dense
+impl<C: Currency> Mul<f64> for Discrete<C> {
+    type Output = Dense<C>;
+    fn mul(self, scalar: f64) -> Dense<C> {
+        self.to_dense() * scalar
+    }
+}
+
+// truncates toward zero, the remainder is lost
+impl<C: Currency> Div<i64> for Discrete<C> {
     type Output = Self;
-    fn div(self, other: Self) -> Self {
-        Self {
-            cents: self.cents / other.cents,
-            _currency: PhantomData,
-        }
+    fn div(self, scalar: i64) -> Self {
+        Self::new(self.units / scalar as i128)
+    }
+}
+
+impl<C: Currency> Div<f64> for Discrete<C> {
+    type Output = Dense<C>;
+    fn div(self, scalar: f64) -> Dense<C> {
+        self.to_dense() / scalar
+    }
+}
+
+impl<C: Currency> Div for Discrete<C> {
+    type Output = f64;
+    fn div(self, other: Self) -> f64 {
+        self.units as f64 / other.units as f64
     }
 }
 
@@ -275,7 +410,7 @@ impl<C: Currency> fmt::Debug for Dense<C> {
 
 impl<C: Currency> fmt::Display for Discrete<C> {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(f, "{} cents in {}", self.cents, C::symbol())
+        write!(f, "{}", self.format(&MoneyFormat::code()))
     }
 }
 
@@ -283,8 +418,8 @@ impl<C: Currency> fmt::Debug for Discrete<C> {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         write!(
             f,
-            "Discrete {{ cents: {}, currency: {} }}",
-            self.cents,
+            "Discrete {{ units: {}, currency: {} }}",
+            self.units,
             C::symbol()
         )
     }
@@ -299,18 +434,122 @@ impl<C: Currency> From<f64> for Dense<C> {
     }
 }
 
+/// Fixed-point money: an exact integer number of minor units of `C`, e.g. cents
+/// for USD, yen for JPY, satoshis for BTC. Stored as `i128` so 18 decimal
+/// currencies still have room for large amounts.
 pub struct Discrete<C: Currency> {
-    cents: i64,
+    units: i128,
     _currency: PhantomData<C>,
 }
 
-impl<C: Currency> From<f64> for Discrete<C> {
-    fn from(amount: f64) -> Self {
+impl<C: Currency> Discrete<C> {
+    pub fn new(units: i128) -> Self {
         Self {
-            cents: (amount * 100.0) as i64, // Convert dollars to cents
+            units,
             _currency: PhantomData,
         }
     }
+
+    pub fn zero() -> Self {
+        Self::new(0)
+    }
+
+    /// The amount as a count of `C`'s minor units.
+    pub fn minor_units(&self) -> i128 {
+        self.units
+    }
+
+    /// Rounds `amount` (in major units) to the minor unit with banker's rounding.
+    pub fn round_from(amount: f64) -> Self {
+        Self::round_from_with(amount, Rounding::default())
+    }
+
+    pub fn round_from_with(amount: f64, rounding: Rounding) -> Self {
+        Self::new(rounding::round_scaled(amount, C::minor_units(), rounding))
+    }
+
+    pub fn from_dense(money: Dense<C>) -> Self {
+        Self::round_from(money.amount)
+    }
+
+    pub fn from_dense_with(money: Dense<C>, rounding: Rounding) -> Self {
+        Self::round_from_with(money.amount, rounding)
+    }
+
+    pub fn to_dense(&self) -> Dense<C> {
+        Dense::from(self.to_f64())
+    }
+
+    /// The amount in major units, for ratios and display. Not exact for huge amounts.
+    pub fn to_f64(&self) -> f64 {
+        self.units as f64 / 10f64.powi(C::minor_units() as i32)
+    }
+}
+
+impl<C: Currency> Clone for Discrete<C> {
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+
+impl<C: Currency> Copy for Discrete<C> {}
+
+impl<C: Currency> PartialEq for Discrete<C> {
+    fn eq(&self, other: &Self) -> bool {
+        self.units == other.units
+    }
+}
+
+impl<C: Currency> Eq for Discrete<C> {}
+
+impl<C: Currency> PartialOrd for Discrete<C> {
+    fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl<C: Currency> Ord for Discrete<C> {
+    fn cmp(&self, other: &Self) -> std::cmp::Ordering {
+        self.units.cmp(&other.units)
+    }
+}
+
+impl<C: Currency> Default for Discrete<C> {
+    fn default() -> Self {
+        Self::zero()
+    }
+}
+
+impl<C: Currency> std::ops::AddAssign for Discrete<C> {
+    fn add_assign(&mut self, other: Self) {
+        self.units += other.units;
+    }
+}
+
+impl<C: Currency> std::ops::SubAssign for Discrete<C> {
+    fn sub_assign(&mut self, other: Self) {
+        self.units -= other.units;
+    }
+}
+
+impl<C: Currency> std::ops::Neg for Discrete<C> {
+    type Output = Self;
+
+    fn neg(self) -> Self {
+        Self::new(-self.units)
+    }
+}
+
+impl<C: Currency> std::iter::Sum for Discrete<C> {
+    fn sum<I: Iterator<Item = Self>>(iter: I) -> Self {
+        iter.fold(Self::zero(), |acc, x| acc + x)
+    }
+}
+
+impl<C: Currency> From<f64> for Discrete<C> {
+    fn from(amount: f64) -> Self {
+        Self::round_from(amount)
+    }
 }
 
 mod tests {
@@ -330,7 +569,7 @@ mod tests {
         let money1: Discrete<USD> = Discrete::from(10.0); // 1000 cents = 10 USD
         let money2: Discrete<USD> = Discrete::from(20.0); // 2000 cents = 20 USD
         let result = money1 + money2;
-        assert_eq!(result.cents, 3000); // 3000 cents = 30 USD
+        assert_eq!(result.units, 3000); // 3000 cents = 30 USD
     }
 
     #[test]
@@ -346,39 +585,106 @@ mod tests {
         let money1: Discrete<USD> = Discrete::from(10.0); // 1000 cents = 10 USD
         let money2: Discrete<USD> = Discrete::from(20.0); // 2000 cents = 20 USD
         let result = money1 - money2;
-        assert_eq!(result.cents, -1000); // -1000 cents = -10 USD
+        assert_eq!(result.units, -1000); // -1000 cents = -10 USD
     }
 
     #[test]
     fn test_dense_mul() {
-        let money1: Dense<USD> = Dense::from(10.0);
-        let money2: Dense<USD> = Dense::from(20.0);
-        let result = money1 * money2;
-        assert_eq!(result.amount, 200.0);
+        let money: Dense<USD> = Dense::from(10.0);
+        assert_eq!((money * 20.0).amount, 200.0);
+        assert_eq!((0.5 * money).amount, 5.0);
     }
 
     #[test]
     fn test_discrete_mul() {
-        let money1: Discrete<USD> = Discrete::from(10.0); // 1000 cents = 10 USD
-        let money2: Discrete<USD> = Discrete::from(20.0); // 2000 cents = 20 USD
-        let result = money1 * money2;
-        assert_eq!(result.cents, 2000000); // 2000000 cents = 20000 USD
+        let money: Discrete<USD> = Discrete::from(10.0); // 1000 cents = 10 USD
+        assert_eq!((money * 20).units, 20000); // 20000 cents = 200 USD
+        assert_eq!((3 * money).units, 3000);
+        assert_eq!((money * 0.333).amount(), 3.33);
     }
 
     #[test]
     fn test_dense_div() {
         let money1: Dense<USD> = Dense::from(10.0);
         let money2: Dense<USD> = Dense::from(20.0);
-        let result = money1 / money2;
-        assert_eq!(result.amount, 0.5);
+        let ratio: f64 = money1 / money2;
+        assert_eq!(ratio, 0.5);
+        assert_eq!((money1 / 4.0).amount, 2.5);
     }
 
     #[test]
     fn test_discrete_div() {
         let money1: Discrete<USD> = Discrete::from(10.0); // 1000 cents = 10 USD
         let money2: Discrete<USD> = Discrete::from(20.0); // 2000 cents = 20 USD
-        let result = money1 / money2;
-        assert_eq!(result.cents, 0); // 0 cents = 0 USD
+        let ratio: f64 = money1 / money2;
+        assert_eq!(ratio, 0.5);
+        assert_eq!((money1 / 3).units, 333);
+        assert_eq!((money1 / 8.0).amount(), 1.25);
+    }
+
+    #[test]
+    fn test_usd_scalar_ops() {
+        let price = USD::new(10.0);
+        assert_eq!((price * 3.0).amount, 30.0);
+        assert_eq!((2.0 * price).amount, 20.0);
+        assert_eq!((price / 4.0).amount, 2.5);
+        assert_eq!(price / USD::new(40.0), 0.25);
+    }
+
+    #[test]
+    fn test_discrete_round_from() {
+        assert_eq!(Discrete::<USD>::round_from(19.999).minor_units(), 2000);
+        assert_eq!(Discrete::<USD>::round_from(0.1 + 0.2).minor_units(), 30);
+        assert_eq!(Discrete::<USD>::round_from(-1.005).minor_units(), -100);
+        assert_eq!(Discrete::<USD>::new(1234).to_f64(), 12.34);
+    }
+
+    #[test]
+    fn test_from_f64_rounds_instead_of_truncating() {
+        assert_eq!(Discrete::<USD>::from(19.999).minor_units(), 2000);
+        assert_eq!(Discrete::<USD>::from(-19.999).minor_units(), -2000);
+    }
+
+    #[test]
+    fn test_rounding_modes() {
+        let price: Dense<USD> = Dense::from(10.125);
+        assert_eq!(price.round(Rounding::HalfEven).minor_units(), 1012);
+        assert_eq!(price.round(Rounding::HalfUp).minor_units(), 1013);
+        assert_eq!(price.round(Rounding::Floor).minor_units(), 1012);
+        assert_eq!(price.round(Rounding::Ceiling).minor_units(), 1013);
+        assert_eq!(
+            Discrete::<USD>::round_from_with(-10.125, Rounding::Floor).minor_units(),
+            -1013
+        );
+    }
+
+    #[test]
+    fn test_minor_units_per_currency() {
+        assert_eq!(USD::minor_units(), 2);
+        assert_eq!(Discrete::<JPY>::round_from(1234.5).minor_units(), 1234);
+        assert_eq!(Discrete::<BHD>::round_from(1.2345).minor_units(), 1234);
+        assert_eq!(Discrete::<BTC>::round_from(0.5).minor_units(), 50_000_000);
+        let eth = Discrete::<ETH>::round_from(1_000_000.000000000000000001);
+        assert_eq!(eth.minor_units(), 1_000_000_000_000_000_000_000_000);
+        assert_eq!(Discrete::<JPY>::new(1500).to_f64(), 1500.0);
+    }
+
+    #[test]
+    fn test_discrete_sum_is_exact() {
+        let total: Discrete<USD> = (0..10).map(|_| Discrete::round_from(0.1)).sum();
+        assert_eq!(total, Discrete::new(100));
+        let mut cash: Discrete<USD> = Discrete::new(100);
+        cash -= Discrete::round_from(0.3);
+        cash += Discrete::round_from(0.2);
+        assert_eq!(cash, Discrete::round_from(0.9));
+    }
+
+    #[test]
+    fn test_currency_markers() {
+        assert_eq!(EUR::symbol(), "EUR");
+        assert_eq!(JPY::name(), "Japanese Yen");
+        let money: Dense<CHF> = Dense::from(5.0);
+        assert_eq!(format!("{}", money), "5 CHF");
     }
 
     #[test]
@@ -390,6 +696,8 @@ mod tests {
     #[test]
     fn test_discrete_display() {
         let money: Discrete<USD> = Discrete::from(10.0); // 1000 cents = 10 USD
-        assert_eq!(format!("{}", money), "1000 cents in USD");
+        assert_eq!(format!("{}", money), "10.00 USD");
+        let money: Discrete<JPY> = Discrete::new(-1500);
+        assert_eq!(format!("{}", money), "-1500 JPY");
     }
 }
@@ -0,0 +1,160 @@
+use anyhow::Result;
+
+use super::{Currency, Discrete};
+
+/// Where the minor units that don't divide evenly end up when allocating.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Remainder {
+    /// One unit each to the parts that lost the biggest fraction of a unit.
+    #[default]
+    LargestFraction,
+    /// One unit each, starting from the first part.
+    First,
+    /// One unit each, starting from the last part.
+    Last,
+}
+
+impl<C: Currency> Discrete<C> {
+    /// Splits the amount in proportion to `ratios` so that the parts add up to
+    /// exactly the original amount. Each part is the floor of its exact share,
+    /// the leftover minor units are handed out according to `remainder`.
+    pub fn allocate(&self, ratios: &[f64], remainder: Remainder) -> Result<Vec<Self>> {
+        if ratios.iter().any(|r| !r.is_finite() || *r < 0.0) {
+            return Err(anyhow::anyhow!(
+                "Ratios must be finite and non-negative: {:?}",
+                ratios
+            ));
+        }
+        let total_ratio: f64 = ratios.iter().sum();
+        if total_ratio <= 0.0 {
+            return Err(anyhow::anyhow!("Ratios must not all be zero"));
+        }
+
+        // work on the magnitude so negative amounts split symmetrically
+        let total = self.units.unsigned_abs();
+        let shares: Vec<f64> = ratios
+            .iter()
+            .map(|r| total as f64 * r / total_ratio)
+            .collect();
+        let mut parts: Vec<u128> = shares.iter().map(|s| s.floor() as u128).collect();
+        let mut leftover = total as i128 - parts.iter().sum::<u128>() as i128;
+
+        // indices by the fraction of a unit each part lost, biggest first, ties by position
+        let mut order: Vec<usize> = (0..parts.len()).collect();
+        order.sort_by(|&a, &b| {
+            let fa = shares[a] - parts[a] as f64;
+            let fb = shares[b] - parts[b] as f64;
+            fb.total_cmp(&fa)
+        });
+
+        // floating point can overshoot a floor by a unit, take those back first
+        for &i in order.iter().rev() {
+            if leftover >= 0 {
+                break;
+            }
+            if parts[i] > 0 {
+                parts[i] -= 1;
+                leftover += 1;
+            }
+        }
+
+        // zero ratios never receive remainder units
+        let eligible: Vec<usize> = match remainder {
+            Remainder::LargestFraction => order,
+            Remainder::First => (0..parts.len()).collect(),
+            Remainder::Last => (0..parts.len()).rev().collect(),
+        }
+        .into_iter()
+        .filter(|&i| ratios[i] > 0.0)
+        .collect();
+        for &i in eligible.iter().cycle().take(leftover as usize) {
+            parts[i] += 1;
+        }
+
+        let sign = self.units.signum();
+        Ok(parts
+            .into_iter()
+            .map(|units| Self::new(sign * units as i128))
+            .collect())
+    }
+
+    /// Splits the amount into `n` parts that differ by at most one minor unit.
+    pub fn split(&self, n: usize) -> Result<Vec<Self>> {
+        self.allocate(&vec![1.0; n], Remainder::First)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::safe_money::USD;
+
+    fn cents(parts: &[Discrete<USD>]) -> Vec<i128> {
+        parts.iter().map(|p| p.minor_units()).collect()
+    }
+
+    #[test]
+    fn test_split_evenly() {
+        let money: Discrete<USD> = Discrete::new(100);
+        assert_eq!(cents(&money.split(3).unwrap()), vec![34, 33, 33]);
+        assert_eq!(cents(&money.split(4).unwrap()), vec![25, 25, 25, 25]);
+    }
+
+    #[test]
+    fn test_allocate_by_weights() {
+        let money: Discrete<USD> = Discrete::new(1_000);
+        let parts = money
+            .allocate(&[0.5, 0.3, 0.2], Remainder::LargestFraction)
+            .unwrap();
+        assert_eq!(cents(&parts), vec![500, 300, 200]);
+
+        let money: Discrete<USD> = Discrete::new(7);
+        let parts = money
+            .allocate(&[0.2, 0.8], Remainder::LargestFraction)
+            .unwrap();
+        assert_eq!(cents(&parts), vec![1, 6]);
+    }
+
+    #[test]
+    fn test_remainder_placement() {
+        let money: Discrete<USD> = Discrete::new(2);
+        let ratios = [1.0, 1.0, 1.0];
+        let first = money.allocate(&ratios, Remainder::First).unwrap();
+        let last = money.allocate(&ratios, Remainder::Last).unwrap();
+        assert_eq!(cents(&first), vec![1, 1, 0]);
+        assert_eq!(cents(&last), vec![0, 1, 1]);
+    }
+
+    #[test]
+    fn test_parts_always_sum_to_total() {
+        let ratios = [0.15, 0.2, 0.1, 0.3, 0.1, 0.1, 0.025, 0.025];
+        for total in [-12_345, -1, 0, 1, 7, 99_999, 1_000_000_007] {
+            let money: Discrete<USD> = Discrete::new(total);
+            for remainder in [
+                Remainder::LargestFraction,
+                Remainder::First,
+                Remainder::Last,
+            ] {
+                let parts = money.allocate(&ratios, remainder).unwrap();
+                assert_eq!(parts.iter().copied().sum::<Discrete<USD>>(), money);
+            }
+        }
+    }
+
+    #[test]
+    fn test_zero_ratio_gets_nothing() {
+        let money: Discrete<USD> = Discrete::new(10);
+        let parts = money.allocate(&[0.0, 1.0, 1.0], Remainder::First).unwrap();
+        assert_eq!(cents(&parts), vec![0, 5, 5]);
+        let parts = money.allocate(&[0.0, 1.0, 2.0], Remainder::First).unwrap();
+        assert_eq!(cents(&parts), vec![0, 4, 6]);
+    }
+
+    #[test]
+    fn test_invalid_ratios() {
+        let money: Discrete<USD> = Discrete::new(10);
+        assert!(money.allocate(&[], Remainder::First).is_err());
+        assert!(money.allocate(&[0.0, 0.0], Remainder::First).is_err());
+        assert!(money.allocate(&[1.0, -1.0], Remainder::First).is_err());
+    }
+}
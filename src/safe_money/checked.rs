@@ -0,0 +1,148 @@
+use std::fmt;
+
+use super::rounding::{self, Rounding};
+use super::{Currency, Dense, Discrete};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum MoneyError {
+    /// The result does not fit in the minor unit count.
+    Overflow,
+    /// An amount that is NaN or infinite, e.g. from a bad quote.
+    NotFinite,
+    DivisionByZero,
+}
+
+impl fmt::Display for MoneyError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MoneyError::Overflow => write!(f, "money amount overflowed"),
+            MoneyError::NotFinite => write!(f, "money amount is not a finite number"),
+            MoneyError::DivisionByZero => write!(f, "money amount divided by zero"),
+        }
+    }
+}
+
+impl std::error::Error for MoneyError {}
+
+impl<C: Currency> Discrete<C> {
+    pub fn checked_add(self, other: Self) -> Result<Self, MoneyError> {
+        self.units
+            .checked_add(other.units)
+            .map(Self::new)
+            .ok_or(MoneyError::Overflow)
+    }
+
+    pub fn checked_sub(self, other: Self) -> Result<Self, MoneyError> {
+        self.units
+            .checked_sub(other.units)
+            .map(Self::new)
+            .ok_or(MoneyError::Overflow)
+    }
+
+    pub fn checked_mul_scalar(self, scalar: i64) -> Result<Self, MoneyError> {
+        self.units
+            .checked_mul(scalar as i128)
+            .map(Self::new)
+            .ok_or(MoneyError::Overflow)
+    }
+
+    /// Truncates toward zero like `/`.
+    pub fn checked_div_scalar(self, scalar: i64) -> Result<Self, MoneyError> {
+        if scalar == 0 {
+            return Err(MoneyError::DivisionByZero);
+        }
+        self.units
+            .checked_div(scalar as i128)
+            .map(Self::new)
+            .ok_or(MoneyError::Overflow)
+    }
+
+    pub fn saturating_add(self, other: Self) -> Self {
+        Self::new(self.units.saturating_add(other.units))
+    }
+
+    pub fn saturating_sub(self, other: Self) -> Self {
+        Self::new(self.units.saturating_sub(other.units))
+    }
+
+    pub fn saturating_mul_scalar(self, scalar: i64) -> Self {
+        Self::new(self.units.saturating_mul(scalar as i128))
+    }
+
+    /// Like `round_from_with`, but fails instead of saturating or returning zero.
+    pub fn try_round_from_with(amount: f64, rounding: Rounding) -> Result<Self, MoneyError> {
+        if !amount.is_finite() {
+            return Err(MoneyError::NotFinite);
+        }
+        match rounding::round_scaled(amount, C::minor_units(), rounding) {
+            i128::MAX | i128::MIN => Err(MoneyError::Overflow),
+            units => Ok(Self::new(units)),
+        }
+    }
+
+    pub fn try_round_from(amount: f64) -> Result<Self, MoneyError> {
+        Self::try_round_from_with(amount, Rounding::default())
+    }
+
+    pub fn try_from_dense(money: Dense<C>) -> Result<Self, MoneyError> {
+        Self::try_round_from(money.amount)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::safe_money::{ETH, USD};
+
+    #[test]
+    fn test_checked_ops() {
+        let money: Discrete<USD> = Discrete::new(1_000);
+        assert_eq!(money.checked_add(money), Ok(Discrete::new(2_000)));
+        assert_eq!(money.checked_sub(money), Ok(Discrete::zero()));
+        assert_eq!(money.checked_mul_scalar(-3), Ok(Discrete::new(-3_000)));
+        assert_eq!(money.checked_div_scalar(3), Ok(Discrete::new(333)));
+        assert_eq!(money.checked_div_scalar(0), Err(MoneyError::DivisionByZero));
+    }
+
+    #[test]
+    fn test_checked_overflow() {
+        let max: Discrete<USD> = Discrete::new(i128::MAX);
+        let min: Discrete<USD> = Discrete::new(i128::MIN);
+        let one = Discrete::new(1);
+        assert_eq!(max.checked_add(one), Err(MoneyError::Overflow));
+        assert_eq!(min.checked_sub(one), Err(MoneyError::Overflow));
+        assert_eq!(max.checked_mul_scalar(2), Err(MoneyError::Overflow));
+        assert_eq!(min.checked_div_scalar(-1), Err(MoneyError::Overflow));
+    }
+
+    #[test]
+    fn test_saturating_ops() {
+        let max: Discrete<USD> = Discrete::new(i128::MAX);
+        let min: Discrete<USD> = Discrete::new(i128::MIN);
+        let one = Discrete::new(1);
+        assert_eq!(max.saturating_add(one), max);
+        assert_eq!(min.saturating_sub(one), min);
+        assert_eq!(max.saturating_mul_scalar(-2), min);
+        assert_eq!(one.saturating_add(one), Discrete::new(2));
+    }
+
+    #[test]
+    fn test_try_round_from() {
+        assert_eq!(
+            Discrete::<USD>::try_round_from(12.345),
+            Ok(Discrete::new(1234))
+        );
+        assert_eq!(
+            Discrete::<USD>::try_round_from(f64::NAN),
+            Err(MoneyError::NotFinite)
+        );
+        assert_eq!(
+            Discrete::<USD>::try_round_from(f64::NEG_INFINITY),
+            Err(MoneyError::NotFinite)
+        );
+        assert_eq!(
+            Discrete::<ETH>::try_round_from(1e25),
+            Err(MoneyError::Overflow)
+        );
+    }
+}
@@ -0,0 +1,542 @@
+use std::fmt;
+use std::str::FromStr;
+
+use super::rounding::{round_digits, Rounding};
+use super::{Currency, Dense, Discrete, USD};
+
+// signs that stand for a currency, longest first so "HK$" wins over "$"
+const SIGNS: [(&str, &str); 11] = [
+    ("US$", "USD"),
+    ("CA$", "CAD"),
+    ("HK$", "HKD"),
+    ("C$", "CAD"),
+    ("A$", "AUD"),
+    ("$", "USD"),
+    ("€", "EUR"),
+    ("£", "GBP"),
+    ("¥", "JPY"),
+    ("₿", "BTC"),
+    ("Ξ", "ETH"),
+];
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ParseMoneyError(String);
+
+impl fmt::Display for ParseMoneyError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "invalid money amount: {}", self.0)
+    }
+}
+
+impl std::error::Error for ParseMoneyError {}
+
+fn parse_error(input: &str, reason: &str) -> ParseMoneyError {
+    ParseMoneyError(format!("{:?} ({})", input, reason))
+}
+
+/// An amount read from text, before it is tied to a currency type.
+#[derive(Debug, PartialEq)]
+struct ParsedAmount {
+    negative: bool,
+    // currency code given by a sign or code in the text
+    currency: Option<String>,
+    whole: String,
+    fraction: String,
+}
+
+/// Reads amounts such as `$1,234.56`, `1234.56 USD`, `-€10,00`, `(1.234,56 €)`
+/// or `CHF 1'234.50`.
+///
+/// A currency may be given once, as an ISO code or a sign, before or after the
+/// number. Negatives are written with a leading or trailing minus or with
+/// parentheses. When both `.` and `,` appear the last one is the decimal
+/// separator; a lone `.` or `,` followed by exactly three digits is read as a
+/// thousands separator unless only a zero precedes it, as in `0.125`, and any
+/// other lone `.` or `,` as the decimal separator.
+fn parse_amount(input: &str) -> Result<ParsedAmount, ParseMoneyError> {
+    let mut rest = input.trim();
+    let mut negative = false;
+    let mut currency: Option<String> = None;
+
+    if rest.starts_with('(') && rest.ends_with(')') {
+        negative = true;
+        rest = &rest[1..rest.len() - 1];
+    }
+
+    // peel minus signs and currency markers off both ends
+    loop {
+        let trimmed = rest.trim_matches(|c: char| c.is_whitespace());
+        let before = trimmed.len();
+        rest = trimmed;
+
+        for minus in ["-", "\u{2212}"] {
+            if let Some(stripped) = rest.strip_prefix(minus).or(rest.strip_suffix(minus)) {
+                if negative {
+                    return Err(parse_error(input, "more than one negative sign"));
+                }
+                negative = true;
+                rest = stripped;
+            }
+        }
+        if let Some(stripped) = rest.strip_prefix('+') {
+            rest = stripped;
+        }
+
+        let marker = SIGNS
+            .iter()
+            .find_map(|(sign, code)| {
+                rest.strip_prefix(sign)
+                    .or(rest.strip_suffix(sign))
+                    .map(|stripped| (stripped, code.to_string()))
+            })
+            .or_else(|| strip_code(rest));
+        if let Some((stripped, code)) = marker {
+            if currency.as_ref().is_some_and(|c| *c != code) {
+                return Err(parse_error(input, "conflicting currencies"));
+            }
+            currency = Some(code);
+            rest = stripped;
+        }
+
+        if rest.len() == before {
+            break;
+        }
+    }
+
+    let digits: String = rest
+        .chars()
+        .filter(|c| !matches!(c, '\'' | ' ' | '\u{a0}' | '\u{202f}'))
+        .collect();
+    if digits.is_empty()
+        || !digits
+            .chars()
+            .all(|c| c.is_ascii_digit() || c == '.' || c == ',')
+    {
+        return Err(parse_error(input, "not a number"));
+    }
+
+    let decimal = match (digits.rfind('.'), digits.rfind(',')) {
+        (Some(dot), Some(comma)) => Some(dot.max(comma)),
+        (Some(only), None) | (None, Some(only)) => {
+            let separator = digits.as_bytes()[only] as char;
+            let count = digits.matches(separator).count();
+            let digits_after = digits.len() - only - 1;
+            // "$1,234" and "1.234 €" alike
+            let grouping = digits_after == 3 && only > 0 && !digits.starts_with('0');
+            if count > 1 || grouping {
+                None
+            } else {
+                Some(only)
+            }
+        }
+        (None, None) => None,
+    };
+    let (whole, fraction) = match decimal {
+        Some(at) => (&digits[..at], &digits[at + 1..]),
+        None => (digits.as_str(), ""),
+    };
+    if fraction.contains(['.', ',']) {
+        return Err(parse_error(input, "misplaced separator"));
+    }
+    // what's left in the whole part must be one thousands separator between groups of three
+    let separators: Vec<char> = whole.chars().filter(|c| !c.is_ascii_digit()).collect();
+    if let Some(&separator) = separators.first() {
+        let groups: Vec<&str> = whole.split(separator).collect();
+        let grouped = separators.iter().all(|&c| c == separator)
+            && decimal.is_none_or(|at| digits.as_bytes()[at] as char != separator)
+            && (1..=3).contains(&groups[0].len())
+            && groups[1..].iter().all(|group| group.len() == 3);
+        if !grouped {
+            return Err(parse_error(input, "misplaced separator"));
+        }
+    }
+    let whole: String = whole.chars().filter(|c| c.is_ascii_digit()).collect();
+    if whole.is_empty() && fraction.is_empty() {
+        return Err(parse_error(input, "not a number"));
+    }
+
+    Ok(ParsedAmount {
+        negative,
+        currency,
+        whole,
+        fraction: fraction.to_string(),
+    })
+}
+
+// a three letter ISO code at either end, e.g. "USD 10" or "10 USD"
+fn strip_code(s: &str) -> Option<(&str, String)> {
+    let is_code = |code: &str| code.len() == 3 && code.chars().all(|c| c.is_ascii_uppercase());
+    if s.len() >= 3 && s.is_char_boundary(3) && is_code(&s[..3]) {
+        return Some((&s[3..], s[..3].to_string()));
+    }
+    let at = s.len().checked_sub(3)?;
+    if s.is_char_boundary(at) && is_code(&s[at..]) {
+        return Some((&s[..at], s[at..].to_string()));
+    }
+    None
+}
+
+impl ParsedAmount {
+    fn check_currency<C: Currency>(&self, input: &str) -> Result<(), ParseMoneyError> {
+        match &self.currency {
+            Some(code) if code != C::symbol() => Err(parse_error(
+                input,
+                &format!("expected {}, found {}", C::symbol(), code),
+            )),
+            _ => Ok(()),
+        }
+    }
+
+    fn to_f64(&self) -> f64 {
+        let whole = if self.whole.is_empty() {
+            "0"
+        } else {
+            &self.whole
+        };
+        let magnitude: f64 = format!("{}.{}0", whole, self.fraction)
+            .parse()
+            .unwrap_or(0.0);
+        if self.negative {
+            -magnitude
+        } else {
+            magnitude
+        }
+    }
+}
+
+impl<C: Currency> FromStr for Dense<C> {
+    type Err = ParseMoneyError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let parsed = parse_amount(s)?;
+        parsed.check_currency::<C>(s)?;
+        Ok(Dense::from(parsed.to_f64()))
+    }
+}
+
+/// Extra decimals beyond the currency's minor unit are rounded half to even.
+impl<C: Currency> FromStr for Discrete<C> {
+    type Err = ParseMoneyError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let parsed = parse_amount(s)?;
+        parsed.check_currency::<C>(s)?;
+        let digits: u128 = format!("{}{}", parsed.whole, parsed.fraction)
+            .parse()
+            .map_err(|_| parse_error(s, "too many digits"))?;
+        let shift = C::minor_units() as i64 - parsed.fraction.len() as i64;
+        let units = round_digits(digits, shift, parsed.negative, Rounding::default())
+            .and_then(|units| i128::try_from(units).ok())
+            .ok_or_else(|| parse_error(s, "amount out of range"))?;
+        Ok(Discrete::new(if parsed.negative { -units } else { units }))
+    }
+}
+
+impl FromStr for USD {
+    type Err = ParseMoneyError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let money: Dense<USD> = s.parse()?;
+        Ok(USD::new(money.amount()))
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SymbolStyle {
+    /// The currency sign, e.g. `$` or `€`.
+    Sign,
+    /// The ISO code, e.g. `USD`.
+    Code,
+    None,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SymbolPosition {
+    Before,
+    After,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NegativeStyle {
+    /// `-$1.00`
+    Minus,
+    /// `$-1.00`
+    MinusAfterSymbol,
+    /// `($1.00)`
+    Parentheses,
+}
+
+/// How an amount is written out: symbol, separators and negatives.
+#[derive(Debug, Clone, PartialEq)]
+pub struct MoneyFormat {
+    pub symbol: SymbolStyle,
+    pub position: SymbolPosition,
+    // space between the symbol and the number
+    pub spaced: bool,
+    pub thousands_separator: Option<char>,
+    pub decimal_separator: char,
+    pub negative: NegativeStyle,
+}
+
+impl Default for MoneyFormat {
+    fn default() -> Self {
+        Self::us()
+    }
+}
+
+impl MoneyFormat {
+    /// `-$1,234.56`
+    pub fn us() -> Self {
+        Self {
+            symbol: SymbolStyle::Sign,
+            position: SymbolPosition::Before,
+            spaced: false,
+            thousands_separator: Some(','),
+            decimal_separator: '.',
+            negative: NegativeStyle::Minus,
+        }
+    }
+
+    /// `($1,234.56)`
+    pub fn accounting() -> Self {
+        Self {
+            negative: NegativeStyle::Parentheses,
+            ..Self::us()
+        }
+    }
+
+    /// `-1.234,56 €`
+    pub fn european() -> Self {
+        Self {
+            symbol: SymbolStyle::Sign,
+            position: SymbolPosition::After,
+            spaced: true,
+            thousands_separator: Some('.'),
+            decimal_separator: ',',
+            negative: NegativeStyle::Minus,
+        }
+    }
+
+    /// `-1234.56 USD`, what `Display` uses.
+    pub fn code() -> Self {
+        Self {
+            symbol: SymbolStyle::Code,
+            position: SymbolPosition::After,
+            spaced: true,
+            thousands_separator: None,
+            decimal_separator: '.',
+            negative: NegativeStyle::Minus,
+        }
+    }
+
+    /// Conventions for a BCP 47 locale such as `en-US` or `de-DE`.
+    pub fn for_locale(locale: &str) -> Option<Self> {
+        match locale {
+            "en-US" | "en-GB" | "en-CA" | "en-AU" | "ja-JP" | "zh-HK" => Some(Self::us()),
+            "de-DE" | "es-ES" | "it-IT" | "nl-NL" => Some(Self::european()),
+            "fr-FR" | "fr-CA" => Some(Self {
+                thousands_separator: Some('\u{202f}'),
+                ..Self::european()
+            }),
+            "de-CH" => Some(Self {
+                symbol: SymbolStyle::Code,
+                position: SymbolPosition::Before,
+                spaced: true,
+                thousands_separator: Some('\''),
+                decimal_separator: '.',
+                negative: NegativeStyle::Minus,
+            }),
+            _ => None,
+        }
+    }
+
+    /// Writes `units` minor units of `C`.
+    pub fn format_units<C: Currency>(&self, units: i128) -> String {
+        let scale = 10u128.pow(C::minor_units());
+        let magnitude = units.unsigned_abs();
+        let whole = (magnitude / scale).to_string();
+
+        let mut number = String::new();
+        for (i, digit) in whole.chars().enumerate() {
+            if i > 0 && (whole.len() - i).is_multiple_of(3) {
+                if let Some(separator) = self.thousands_separator {
+                    number.push(separator);
+                }
+            }
+            number.push(digit);
+        }
+        if C::minor_units() > 0 {
+            number.push(self.decimal_separator);
+            number.push_str(&format!(
+                "{:0width$}",
+                magnitude % scale,
+                width = C::minor_units() as usize
+            ));
+        }
+
+        let symbol = match self.symbol {
+            SymbolStyle::Sign => C::sign(),
+            SymbolStyle::Code => C::symbol(),
+            SymbolStyle::None => "",
+        };
+        let gap = if self.spaced && !symbol.is_empty() {
+            " "
+        } else {
+            ""
+        };
+        let negative = units < 0;
+        let inner_minus = if negative && self.negative == NegativeStyle::MinusAfterSymbol {
+            "-"
+        } else {
+            ""
+        };
+        let body = match self.position {
+            SymbolPosition::Before => format!("{}{}{}{}", symbol, gap, inner_minus, number),
+            SymbolPosition::After => format!("{}{}{}{}", inner_minus, number, gap, symbol),
+        };
+        match (negative, self.negative) {
+            (true, NegativeStyle::Minus) => format!("-{}", body),
+            (true, NegativeStyle::Parentheses) => format!("({})", body),
+            _ => body,
+        }
+    }
+}
+
+impl<C: Currency> Discrete<C> {
+    pub fn format(&self, format: &MoneyFormat) -> String {
+        format.format_units::<C>(self.units)
+    }
+}
+
+impl<C: Currency> Dense<C> {
+    /// Rounds to the currency's minor unit, half to even, and formats the result.
+    pub fn format(&self, format: &MoneyFormat) -> String {
+        self.round(Rounding::default()).format(format)
+    }
+}
+
+impl USD {
+    pub fn format(&self, format: &MoneyFormat) -> String {
+        Dense::<USD>::from(self.amount).format(format)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::safe_money::{EUR, JPY};
+
+    #[test]
+    fn test_parse_us_forms() {
+        let money: Discrete<USD> = "$1,234.56".parse().unwrap();
+        assert_eq!(money.minor_units(), 123_456);
+        let money: Discrete<USD> = "1234.56 USD".parse().unwrap();
+        assert_eq!(money.minor_units(), 123_456);
+        let money: Discrete<USD> = "USD 1,234".parse().unwrap();
+        assert_eq!(money.minor_units(), 123_400);
+        let money: Discrete<USD> = "($12.50)".parse().unwrap();
+        assert_eq!(money.minor_units(), -1250);
+        let money: Discrete<USD> = "$-0.5".parse().unwrap();
+        assert_eq!(money.minor_units(), -50);
+        let money: Discrete<USD> = ".25".parse().unwrap();
+        assert_eq!(money.minor_units(), 25);
+    }
+
+    #[test]
+    fn test_parse_european_forms() {
+        let money: Discrete<EUR> = "-€10,00".parse().unwrap();
+        assert_eq!(money.minor_units(), -1000);
+        let money: Discrete<EUR> = "1.234,56 €".parse().unwrap();
+        assert_eq!(money.minor_units(), 123_456);
+        let money: Discrete<EUR> = "1\u{202f}234,5 EUR".parse().unwrap();
+        assert_eq!(money.minor_units(), 123_450);
+        let money: Dense<EUR> = "12,5€".parse().unwrap();
+        assert_eq!(money.amount(), 12.5);
+        let money: Discrete<EUR> = "1.234 €".parse().unwrap();
+        assert_eq!(money.minor_units(), 123_400);
+        let money: Discrete<EUR> = "€1.234".parse().unwrap();
+        assert_eq!(money.minor_units(), 123_400);
+        let money: Discrete<EUR> = "0,125 €".parse().unwrap();
+        assert_eq!(money.minor_units(), 12);
+    }
+
+    #[test]
+    fn test_parse_rounds_extra_decimals() {
+        let money: Discrete<USD> = "0.125".parse().unwrap();
+        assert_eq!(money.minor_units(), 12);
+        let money: Discrete<JPY> = "¥1,234.5".parse().unwrap();
+        assert_eq!(money.minor_units(), 1234);
+    }
+
+    #[test]
+    fn test_parse_rejects_bad_input() {
+        assert!("".parse::<Discrete<USD>>().is_err());
+        assert!("abc".parse::<Discrete<USD>>().is_err());
+        assert!("€10".parse::<Discrete<USD>>().is_err());
+        assert!("10 EUR".parse::<Dense<USD>>().is_err());
+        assert!("$10 EUR".parse::<Dense<EUR>>().is_err());
+        assert!("--10".parse::<Discrete<USD>>().is_err());
+        assert!("1.2.3,4,5".parse::<Discrete<USD>>().is_err());
+        assert!("12,34,567.00".parse::<Discrete<USD>>().is_err());
+    }
+
+    #[test]
+    fn test_parse_usd() {
+        let price: USD = "$1,000.25".parse().unwrap();
+        assert_eq!(price.amount, 1000.25);
+    }
+
+    #[test]
+    fn test_format_styles() {
+        let money: Discrete<USD> = Discrete::new(-123_456);
+        assert_eq!(money.format(&MoneyFormat::us()), "-$1,234.56");
+        assert_eq!(money.format(&MoneyFormat::accounting()), "($1,234.56)");
+        assert_eq!(money.format(&MoneyFormat::code()), "-1234.56 USD");
+        let format = MoneyFormat {
+            negative: NegativeStyle::MinusAfterSymbol,
+            ..MoneyFormat::us()
+        };
+        assert_eq!(money.format(&format), "$-1,234.56");
+
+        let money: Discrete<EUR> = Discrete::new(123_456_789);
+        assert_eq!(money.format(&MoneyFormat::european()), "1.234.567,89 €");
+        let money: Discrete<JPY> = Discrete::new(1_500);
+        assert_eq!(money.format(&MoneyFormat::us()), "¥1,500");
+    }
+
+    #[test]
+    fn test_format_locales() {
+        let money: Discrete<EUR> = Discrete::new(123_456);
+        let fr = MoneyFormat::for_locale("fr-FR").unwrap();
+        assert_eq!(money.format(&fr), "1\u{202f}234,56 €");
+        let money: Discrete<crate::safe_money::CHF> = Discrete::new(123_450);
+        let ch = MoneyFormat::for_locale("de-CH").unwrap();
+        assert_eq!(money.format(&ch), "CHF 1'234.50");
+        assert!(MoneyFormat::for_locale("xx-XX").is_none());
+    }
+
+    #[test]
+    fn test_format_round_trips() {
+        let formats = [
+            MoneyFormat::us(),
+            MoneyFormat::accounting(),
+            MoneyFormat::european(),
+            MoneyFormat::code(),
+            MoneyFormat::for_locale("fr-FR").unwrap(),
+        ];
+        for units in [-123_456_789, -5, 0, 7, 100_000, 123_456_789] {
+            let money: Discrete<EUR> = Discrete::new(units);
+            for format in &formats {
+                let text = money.format(format);
+                assert_eq!(text.parse::<Discrete<EUR>>().unwrap(), money, "{}", text);
+            }
+        }
+    }
+
+    #[test]
+    fn test_format_dense_and_usd() {
+        let price: Dense<USD> = Dense::from(1234.565);
+        assert_eq!(price.format(&MoneyFormat::us()), "$1,234.56");
+        assert_eq!(USD::new(99.999).format(&MoneyFormat::us()), "$100.00");
+    }
+}
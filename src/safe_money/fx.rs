@@ -0,0 +1,139 @@
+use std::collections::HashMap;
+use std::marker::PhantomData;
+
+use anyhow::Result;
+
+use super::{Currency, Dense};
+
+/// Table of exchange rates between currencies, keyed by currency code.
+///
+/// A rate for `(from, to)` is the number of `to` units one unit of `from` buys,
+/// so `set_rate("EUR", "USD", 1.08)` reads as 1 EUR = 1.08 USD. Lookups fall
+/// back to the inverse pair and then to a cross rate through one intermediate
+/// currency.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct FxRates {
+    pub(super) rates: HashMap<(String, String), f64>,
+}
+
+// quotes some exchanges give in minor units: (code, major currency, minor units per major)
+const MINOR_UNIT_QUOTES: [(&str, &str, f64); 2] = [("GBp", "GBP", 100.0), ("GBX", "GBP", 100.0)];
+
+impl FxRates {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_rate(mut self, from: &str, to: &str, rate: f64) -> Self {
+        self.set_rate(from, to, rate);
+        self
+    }
+
+    pub fn set_rate(&mut self, from: &str, to: &str, rate: f64) {
+        self.rates.insert((from.to_string(), to.to_string()), rate);
+    }
+
+    /// The currency a quote code is denominated in, e.g. "GBP" for pence ("GBp").
+    pub fn major_currency(code: &str) -> &str {
+        normalize(code).0
+    }
+
+    /// How many units of `to` one unit of `from` is worth.
+    pub fn rate(&self, from: &str, to: &str) -> Result<f64> {
+        let (from, from_scale) = normalize(from);
+        let (to, to_scale) = normalize(to);
+        Ok(self.major_rate(from, to)? * to_scale / from_scale)
+    }
+
+    fn major_rate(&self, from: &str, to: &str) -> Result<f64> {
+        if from == to {
+            return Ok(1.0);
+        }
+        if let Some(rate) = self.direct(from, to) {
+            return Ok(rate);
+        }
+        // cross through any currency quoted against both sides
+        self.rates
+            .keys()
+            .flat_map(|(a, b)| [a, b])
+            .filter(|via| via.as_str() != from && via.as_str() != to)
+            .find_map(|via| Some(self.direct(from, via)? * self.direct(via, to)?))
+            .ok_or_else(|| anyhow::anyhow!("No FX rate from {} to {}", from, to))
+    }
+
+    fn direct(&self, from: &str, to: &str) -> Option<f64> {
+        self.rates
+            .get(&(from.to_string(), to.to_string()))
+            .copied()
+            .or_else(|| {
+                self.rates
+                    .get(&(to.to_string(), from.to_string()))
+                    .map(|rate| 1.0 / rate)
+            })
+    }
+
+    /// Converts `amount` units of `from` into `to`.
+    pub fn convert_amount(&self, amount: f64, from: &str, to: &str) -> Result<f64> {
+        Ok(amount * self.rate(from, to)?)
+    }
+
+    pub fn convert<F: Currency, T: Currency>(&self, money: Dense<F>) -> Result<Dense<T>> {
+        Ok(Dense {
+            amount: self.convert_amount(money.amount, F::symbol(), T::symbol())?,
+            _currency: PhantomData,
+        })
+    }
+}
+
+// maps minor unit quote codes onto their currency, with the number of minor units per major
+fn normalize(code: &str) -> (&str, f64) {
+    MINOR_UNIT_QUOTES
+        .iter()
+        .find(|(minor, _, _)| *minor == code)
+        .map_or((code, 1.0), |(_, major, scale)| (*major, *scale))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::safe_money::{EUR, GBP, JPY, USD};
+
+    fn rates() -> FxRates {
+        FxRates::new()
+            .with_rate("EUR", "USD", 1.25)
+            .with_rate("USD", "JPY", 150.0)
+    }
+
+    #[test]
+    fn test_direct_and_inverse_rates() {
+        let fx = rates();
+        assert_eq!(fx.rate("EUR", "USD").unwrap(), 1.25);
+        assert_eq!(fx.rate("USD", "EUR").unwrap(), 0.8);
+        assert_eq!(fx.rate("JPY", "JPY").unwrap(), 1.0);
+    }
+
+    #[test]
+    fn test_cross_rate() {
+        let fx = rates();
+        assert_eq!(fx.rate("EUR", "JPY").unwrap(), 187.5);
+        assert!(fx.rate("EUR", "GBP").is_err());
+    }
+
+    #[test]
+    fn test_convert_dense() {
+        let fx = rates();
+        let price: Dense<EUR> = Dense::from(10.0);
+        let converted: Dense<USD> = fx.convert(price).unwrap();
+        assert_eq!(converted.amount(), 12.5);
+        let yen: Dense<JPY> = fx.convert(converted).unwrap();
+        assert_eq!(yen.amount(), 1875.0);
+        assert!(fx.convert::<EUR, GBP>(Dense::from(1.0)).is_err());
+    }
+
+    #[test]
+    fn test_pence_quotes() {
+        let fx = FxRates::new().with_rate("GBP", "USD", 1.25);
+        assert_eq!(fx.convert_amount(200.0, "GBp", "USD").unwrap(), 2.5);
+        assert_eq!(fx.convert_amount(1.0, "GBP", "GBp").unwrap(), 100.0);
+    }
+}
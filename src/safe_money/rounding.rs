@@ -0,0 +1,128 @@
+/// How an amount that falls between two minor units is rounded.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Rounding {
+    /// To the nearest unit, halves to the even neighbour (banker's rounding).
+    #[default]
+    HalfEven,
+    /// To the nearest unit, halves away from zero.
+    HalfUp,
+    /// Toward negative infinity.
+    Floor,
+    /// Toward positive infinity.
+    Ceiling,
+    /// Drops the fraction, what a plain `as` cast does.
+    TowardZero,
+}
+
+/// Rounds `amount * 10^exponent` to an integer.
+///
+/// The rounding is done on the shortest decimal that reads back as `amount`,
+/// so `1.005` rounds like the decimal 1.005 and not like its binary
+/// approximation 1.00499999999999989... Non-finite amounts round to zero and
+/// amounts out of range saturate.
+pub(super) fn round_scaled(amount: f64, exponent: u32, mode: Rounding) -> i128 {
+    if !amount.is_finite() {
+        return 0;
+    }
+    let negative = amount.is_sign_negative();
+    let magnitude = match round_magnitude(amount.abs(), exponent, mode, negative) {
+        Some(magnitude) => magnitude,
+        None => return if negative { i128::MIN } else { i128::MAX },
+    };
+    let magnitude = i128::try_from(magnitude).unwrap_or(i128::MAX);
+    if negative {
+        -magnitude
+    } else {
+        magnitude
+    }
+}
+
+fn round_magnitude(amount: f64, exponent: u32, mode: Rounding, negative: bool) -> Option<u128> {
+    // e.g. "1.005e0" -> digits 1005, shifted to 1005 * 10^(0 - 3 + exponent)
+    let repr = format!("{:e}", amount);
+    let (mantissa, power) = repr.split_once('e')?;
+    let power: i64 = power.parse().ok()?;
+    let (whole, fraction) = mantissa.split_once('.').unwrap_or((mantissa, ""));
+    let digits: u128 = format!("{}{}", whole, fraction).parse().ok()?;
+    let shift = power - fraction.len() as i64 + exponent as i64;
+    round_digits(digits, shift, negative, mode)
+}
+
+/// Rounds the magnitude `digits * 10^shift` to an integer, `None` if it overflows.
+pub(super) fn round_digits(
+    digits: u128,
+    shift: i64,
+    negative: bool,
+    mode: Rounding,
+) -> Option<u128> {
+    if shift >= 0 {
+        return 10u128.checked_pow(shift as u32)?.checked_mul(digits);
+    }
+
+    // past 10^38 the divisor overflows, and the value is below a half
+    let divisor = 10u128.checked_pow((-shift) as u32);
+    let (quotient, remainder, half) = match divisor {
+        Some(divisor) => (digits / divisor, digits % divisor, divisor / 2),
+        None => (0, digits, u128::MAX),
+    };
+    let round_up = match mode {
+        Rounding::TowardZero => false,
+        Rounding::Floor => negative && remainder > 0,
+        Rounding::Ceiling => !negative && remainder > 0,
+        Rounding::HalfUp => remainder >= half && remainder > 0,
+        Rounding::HalfEven => remainder > half || (remainder == half && quotient % 2 == 1),
+    };
+    Some(quotient + round_up as u128)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_half_even() {
+        assert_eq!(round_scaled(0.125, 2, Rounding::HalfEven), 12);
+        assert_eq!(round_scaled(0.135, 2, Rounding::HalfEven), 14);
+        assert_eq!(round_scaled(-0.125, 2, Rounding::HalfEven), -12);
+        assert_eq!(round_scaled(2.5, 0, Rounding::HalfEven), 2);
+        assert_eq!(round_scaled(3.5, 0, Rounding::HalfEven), 4);
+    }
+
+    #[test]
+    fn test_half_up() {
+        assert_eq!(round_scaled(1.005, 2, Rounding::HalfUp), 101);
+        assert_eq!(round_scaled(-1.005, 2, Rounding::HalfUp), -101);
+        assert_eq!(round_scaled(1.004, 2, Rounding::HalfUp), 100);
+    }
+
+    #[test]
+    fn test_floor_and_ceiling() {
+        // 0.29 * 100 is 28.999999999999996 in binary
+        assert_eq!(round_scaled(0.29, 2, Rounding::Floor), 29);
+        assert_eq!(round_scaled(19.999, 2, Rounding::Floor), 1999);
+        assert_eq!(round_scaled(19.999, 2, Rounding::Ceiling), 2000);
+        assert_eq!(round_scaled(-19.999, 2, Rounding::Floor), -2000);
+        assert_eq!(round_scaled(-19.999, 2, Rounding::Ceiling), -1999);
+        assert_eq!(round_scaled(-19.999, 2, Rounding::TowardZero), -1999);
+    }
+
+    #[test]
+    fn test_exponents() {
+        assert_eq!(round_scaled(1234.5, 0, Rounding::HalfEven), 1234);
+        assert_eq!(round_scaled(1.2345, 3, Rounding::HalfUp), 1235);
+        assert_eq!(round_scaled(0.00000001, 8, Rounding::HalfEven), 1);
+        assert_eq!(
+            round_scaled(1.5, 18, Rounding::HalfEven),
+            1_500_000_000_000_000_000
+        );
+        assert_eq!(round_scaled(1e-30, 2, Rounding::Ceiling), 1);
+        assert_eq!(round_scaled(1e-30, 2, Rounding::HalfUp), 0);
+    }
+
+    #[test]
+    fn test_out_of_range() {
+        assert_eq!(round_scaled(f64::NAN, 2, Rounding::HalfEven), 0);
+        assert_eq!(round_scaled(1e300, 2, Rounding::HalfEven), i128::MAX);
+        assert_eq!(round_scaled(-1e300, 2, Rounding::HalfEven), i128::MIN);
+    }
+}
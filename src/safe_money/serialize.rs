@@ -0,0 +1,118 @@
+use serde::de::Error as _;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+use super::format::MoneyFormat;
+use super::{Currency, Dense, Discrete, FxRates, USD};
+
+/// Written as an exact decimal string with the currency code, `"1234.56 USD"`,
+/// so no precision is lost and a snapshot can't be read back in another currency.
+impl<C: Currency> Serialize for Discrete<C> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&self.format(&MoneyFormat::code()))
+    }
+}
+
+impl<'de, C: Currency> Deserialize<'de> for Discrete<C> {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        String::deserialize(deserializer)?
+            .parse()
+            .map_err(D::Error::custom)
+    }
+}
+
+/// Written as the bare amount, the currency is part of the type.
+impl<C: Currency> Serialize for Dense<C> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_f64(self.amount)
+    }
+}
+
+impl<'de, C: Currency> Deserialize<'de> for Dense<C> {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        Ok(Dense::from(f64::deserialize(deserializer)?))
+    }
+}
+
+impl Serialize for USD {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_f64(self.amount)
+    }
+}
+
+impl<'de> Deserialize<'de> for USD {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        Ok(USD::new(f64::deserialize(deserializer)?))
+    }
+}
+
+// one row of the rate table, maps keyed by a pair don't fit JSON or TOML
+#[derive(Serialize, Deserialize)]
+struct Rate {
+    from: String,
+    to: String,
+    rate: f64,
+}
+
+/// Written as a list of `{from, to, rate}` entries sorted by pair.
+impl Serialize for FxRates {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut rates: Vec<Rate> = self
+            .rates
+            .iter()
+            .map(|((from, to), rate)| Rate {
+                from: from.clone(),
+                to: to.clone(),
+                rate: *rate,
+            })
+            .collect();
+        rates.sort_by(|a, b| (&a.from, &a.to).cmp(&(&b.from, &b.to)));
+        rates.serialize(serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for FxRates {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        Ok(Vec::<Rate>::deserialize(deserializer)?
+            .into_iter()
+            .fold(FxRates::new(), |fx, r| fx.with_rate(&r.from, &r.to, r.rate)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::safe_money::{ETH, EUR};
+
+    #[test]
+    fn test_discrete_round_trip() {
+        let money: Discrete<USD> = Discrete::new(-123_456);
+        let json = serde_json::to_string(&money).unwrap();
+        assert_eq!(json, "\"-1234.56 USD\"");
+        assert_eq!(serde_json::from_str::<Discrete<USD>>(&json).unwrap(), money);
+
+        // 18 decimals survive where an f64 would not
+        let wei: Discrete<ETH> = Discrete::new(1_000_000_000_000_000_001);
+        let json = serde_json::to_string(&wei).unwrap();
+        assert_eq!(serde_json::from_str::<Discrete<ETH>>(&json).unwrap(), wei);
+    }
+
+    #[test]
+    fn test_discrete_rejects_other_currency() {
+        assert!(serde_json::from_str::<Discrete<EUR>>("\"12.00 USD\"").is_err());
+        assert!(serde_json::from_str::<Discrete<USD>>("12.0").is_err());
+    }
+
+    #[test]
+    fn test_fx_rates_round_trip() {
+        let fx = FxRates::new()
+            .with_rate("USD", "JPY", 150.0)
+            .with_rate("EUR", "USD", 1.25);
+        let json = serde_json::to_string(&fx).unwrap();
+        assert_eq!(
+            json,
+            r#"[{"from":"EUR","to":"USD","rate":1.25},{"from":"USD","to":"JPY","rate":150.0}]"#
+        );
+        let back: FxRates = serde_json::from_str(&json).unwrap();
+        assert_eq!(back.rate("EUR", "JPY").unwrap(), 187.5);
+    }
+}
@@ -0,0 +1,292 @@
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::Path;
+use std::sync::Arc;
+
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+
+use crate::assets::Position;
+use crate::costs::TradingCosts;
+use crate::harvest::Harvest;
+use crate::ledger::Ledger;
+use crate::portfolio::{Portfolio, RebalanceType};
+use crate::price_source::{CoinGeckoSource, YahooSource};
+use crate::rebalance::TradeLimits;
+use crate::safe_money::{Discrete, FxRates, USD};
+use crate::tax::{Lot, LotMethod, Realized, TaxPolicy};
+
+/// Saved state of a portfolio: holdings with their last prices, cash, target
+/// weights, rebalance settings and the FX rates used to value the holdings.
+///
+/// Snapshots are written as JSON or TOML, `save` and `load` pick the format
+/// from the file extension.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct PortfolioSnapshot {
+    pub cash: Discrete<USD>,
+    pub rebalance_type: RebalanceType,
+    pub rebalance_threshold: Option<f64>,
+    /// Unix timestamp of the last rebalance.
+    #[serde(default)]
+    pub last_rebalanced: Option<i64>,
+    #[serde(default)]
+    pub drift_penalty: Option<f64>,
+    #[serde(default)]
+    pub limits: TradeLimits,
+    /// Costs of trading, free when the cost model isn't a `TradingCosts`.
+    #[serde(default)]
+    pub costs: TradingCosts,
+    #[serde(default)]
+    pub target_weights: BTreeMap<String, f64>,
+    #[serde(default)]
+    pub positions: Vec<Position>,
+    #[serde(default)]
+    pub fx_rates: FxRates,
+    #[serde(default)]
+    pub lot_method: LotMethod,
+    #[serde(default)]
+    pub tax_policy: Option<TaxPolicy>,
+    #[serde(default)]
+    pub harvest: Harvest,
+    /// Tax lots by ticker.
+    #[serde(default)]
+    pub lots: BTreeMap<String, Vec<Lot>>,
+    #[serde(default)]
+    pub realized: Vec<Realized>,
+    #[serde(default)]
+    pub ledger: Ledger,
+}
+
+impl PortfolioSnapshot {
+    pub fn to_json(&self) -> Result<String> {
+        Ok(serde_json::to_string_pretty(self)?)
+    }
+
+    pub fn from_json(json: &str) -> Result<Self> {
+        Ok(serde_json::from_str(json)?)
+    }
+
+    pub fn to_toml(&self) -> Result<String> {
+        Ok(toml::to_string(self)?)
+    }
+
+    pub fn from_toml(toml: &str) -> Result<Self> {
+        Ok(toml::from_str(toml)?)
+    }
+
+    /// Writes TOML to `.toml` paths and JSON to anything else.
+    pub fn save(&self, path: impl AsRef<Path>) -> Result<()> {
+        let path = path.as_ref();
+        let contents = if is_toml(path) {
+            self.to_toml()?
+        } else {
+            self.to_json()?
+        };
+        fs::write(path, contents).with_context(|| format!("Failed to write {}", path.display()))
+    }
+
+    pub fn load(path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref();
+        let contents = fs::read_to_string(path)
+            .with_context(|| format!("Failed to read {}", path.display()))?;
+        let snapshot = if is_toml(path) {
+            Self::from_toml(&contents)
+        } else {
+            Self::from_json(&contents)
+        };
+        snapshot.with_context(|| format!("Invalid snapshot {}", path.display()))
+    }
+}
+
+fn is_toml(path: &Path) -> bool {
+    path.extension().is_some_and(|ext| ext == "toml")
+}
+
+impl Portfolio {
+    pub fn snapshot(&self) -> PortfolioSnapshot {
+        PortfolioSnapshot {
+            cash: self.cash,
+            rebalance_type: self.rebalance_type.clone(),
+            rebalance_threshold: self.rebalance_threshold,
+            last_rebalanced: self.last_rebalanced,
+            drift_penalty: self.drift_penalty,
+            limits: self.limits,
+            costs: self.cost_model.trading_costs().unwrap_or_default(),
+            target_weights: self.target_weights.clone().into_iter().collect(),
+            positions: self.positions.clone(),
+            fx_rates: self.fx_rates.clone(),
+            lot_method: self.lot_method.clone(),
+            tax_policy: self.tax_policy,
+            harvest: self.harvest.clone(),
+            lots: self.lots.clone(),
+            realized: self.realized.clone(),
+            ledger: self.ledger.clone(),
+        }
+    }
+
+    /// Restores a portfolio at the saved prices, without fetching anything.
+    /// Prices come from yahoo and CoinGecko on the next `update_prices`, swap
+    /// `stock_source` and `crypto_source` to use something else. Trades cost the
+    /// saved `costs`. Snapshots saved without a ledger start one from
+    /// the saved holdings and cash, each holding as a single lot, and drop any
+    /// saved lots and realized gains, whose ids point into the missing ledger.
+    pub fn from_snapshot(snapshot: PortfolioSnapshot) -> Result<Portfolio> {
+        let mut portfolio = Portfolio {
+            positions: snapshot.positions,
+            target_weights: snapshot.target_weights.into_iter().collect(),
+            actual_weights: Default::default(),
+            rebalance_type: snapshot.rebalance_type,
+            rebalance_threshold: snapshot.rebalance_threshold,
+            last_rebalanced: snapshot.last_rebalanced,
+            drift_penalty: snapshot.drift_penalty,
+            limits: snapshot.limits,
+            cash: snapshot.cash,
+            fx_rates: snapshot.fx_rates,
+            stock_source: Arc::new(YahooSource::new()),
+            crypto_source: Arc::new(CoinGeckoSource::default()),
+            cost_model: Arc::new(snapshot.costs),
+            ledger: snapshot.ledger,
+            lot_method: snapshot.lot_method,
+            tax_policy: snapshot.tax_policy,
+            harvest: snapshot.harvest,
+            lots: snapshot.lots,
+            realized: snapshot.realized,
+            clock: None,
+        };
+        if portfolio.ledger.is_empty() {
+            portfolio.lots.clear();
+            portfolio.realized.clear();
+            let positions = std::mem::take(&mut portfolio.positions);
+            let cash = std::mem::take(&mut portfolio.cash);
+            portfolio.open(positions, cash)?;
+        }
+        Ok(portfolio)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assets::{Asset, QuantityRule};
+    use crate::costs::Impact;
+    use crate::portfolio::{Band, Bands};
+    use crate::price_source::InMemorySource;
+
+    async fn portfolio() -> Portfolio {
+        let source = InMemorySource::new()
+            .with_price("AAA", 100.0)
+            .with_price("SAP.DE", 200.0)
+            .with_currency("SAP.DE", "EUR")
+            .with_price("EURUSD=X", 1.1)
+            .with_price("bitcoin", 60_000.0);
+        Portfolio::builder()
+            .price_source(Arc::new(source))
+            .add_asset("AAA", 3.0)
+            .add_asset("SAP.DE", 1.5)
+            .add_crypto("bitcoin", "BTC", 0.25)
+            .quantity_rule("AAA", QuantityRule::WholeShares)
+            .quantity_rule("BTC", QuantityRule::Decimals(8))
+            .target_weight("AAA", 0.6)
+            .target_weight("SAP.DE", 0.4)
+            .cash(Discrete::new(12_345))
+            .rebalance_type(RebalanceType::ThresholdAndFrequency(
+                Bands::relative(0.25).with_override("AAA", Band::Absolute(0.05)),
+                30,
+            ))
+            .rebalance_threshold(Some(10.0))
+            .cost_model(Arc::new(TradingCosts {
+                commission_bps: 10.0,
+                impact: Impact::SquareRoot {
+                    bps: 5.0,
+                    per: 1_000_000.0,
+                },
+                ..Default::default()
+            }))
+            .build()
+            .await
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_json_round_trip() {
+        let mut portfolio = portfolio().await;
+        portfolio.last_rebalanced = Some(1_700_000_000);
+        let snapshot = portfolio.snapshot();
+        let json = snapshot.to_json().unwrap();
+        assert!(json.contains("\"cash\": \"123.45 USD\""));
+        assert_eq!(PortfolioSnapshot::from_json(&json).unwrap(), snapshot);
+    }
+
+    #[tokio::test]
+    async fn test_toml_round_trip() {
+        let snapshot = portfolio().await.snapshot();
+        let toml = snapshot.to_toml().unwrap();
+        assert!(toml.contains("cash = \"123.45 USD\""));
+        assert_eq!(PortfolioSnapshot::from_toml(&toml).unwrap(), snapshot);
+    }
+
+    #[tokio::test]
+    async fn test_restored_portfolio_has_same_value() {
+        let original = portfolio().await;
+        let dir = tempfile::tempdir().unwrap();
+        for file in ["portfolio.json", "portfolio.toml"] {
+            let path = dir.path().join(file);
+            original.snapshot().save(&path).unwrap();
+            let restored =
+                Portfolio::from_snapshot(PortfolioSnapshot::load(&path).unwrap()).unwrap();
+            assert_eq!(
+                restored.get_portfolio_value().unwrap(),
+                original.get_portfolio_value().unwrap()
+            );
+            assert_eq!(restored.target_weights, original.target_weights);
+            assert_eq!(restored.rebalance_type, original.rebalance_type);
+            assert_eq!(restored.ledger, original.ledger);
+            assert_eq!(
+                restored.cost_model.trading_costs(),
+                original.cost_model.trading_costs()
+            );
+            assert_eq!(restored.replay().unwrap().positions, original.positions);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_snapshot_without_ledger_opens_one_lot_per_holding() {
+        let original = portfolio().await;
+        let mut snapshot = original.snapshot();
+        snapshot.ledger = Ledger::new();
+        let json = snapshot.to_json().unwrap();
+        let restored =
+            Portfolio::from_snapshot(PortfolioSnapshot::from_json(&json).unwrap()).unwrap();
+        for position in &original.positions {
+            let ticker = position.ticker();
+            let lots = restored.lots(&ticker);
+            assert_eq!(lots.len(), 1, "{}", ticker);
+            assert_eq!(lots[0].quantity, position.amount_held());
+        }
+        assert_eq!(restored.replay().unwrap().lots, restored.lots);
+    }
+
+    #[test]
+    fn test_minimal_snapshot() {
+        let snapshot = PortfolioSnapshot::from_toml(
+            r#"
+            cash = "1000.00 USD"
+            rebalance_type = "None"
+
+            [[positions]]
+            class = "stock"
+            ticker = "AAA"
+            amount_held = 2.0
+            last_price = 50.0
+            currency = "USD"
+            "#,
+        )
+        .unwrap();
+        let portfolio = Portfolio::from_snapshot(snapshot).unwrap();
+        assert_eq!(
+            portfolio.get_portfolio_value().unwrap(),
+            Discrete::new(110_000)
+        );
+        assert!(PortfolioSnapshot::from_toml("cash = \"1000.00 EUR\"").is_err());
+    }
+}
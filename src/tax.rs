@@ -0,0 +1,437 @@
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+
+use crate::portfolio::Portfolio;
+use crate::safe_money::{Dense, Discrete, USD};
+
+/// Holdings sold more than this long after they were bought are long-term.
+pub const LONG_TERM_SECONDS: i64 = 365 * 86_400;
+
+/// Quantity of an asset bought at one time and price.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Lot {
+    /// Index of the ledger entry that bought it.
+    pub id: usize,
+    /// Unix timestamp.
+    pub acquired: i64,
+    pub quantity: f64,
+    /// Dollars paid per unit, costs included.
+    pub cost_per_unit: f64,
+}
+
+/// Part of a holding brought into a portfolio as a lot of its own. Without a
+/// cost or date it costs the price, and was acquired at the time, it is opened at.
+#[derive(Debug, Clone, Copy, PartialEq, Default, Serialize, Deserialize)]
+pub struct OpeningLot {
+    pub quantity: f64,
+    /// Dollars paid per unit.
+    pub cost_per_unit: Option<f64>,
+    /// Unix timestamp.
+    pub acquired: Option<i64>,
+}
+
+/// Which lots a sale takes its quantity from.
+#[derive(Debug, Clone, PartialEq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum LotMethod {
+    /// Oldest lots first.
+    #[default]
+    Fifo,
+    /// Newest lots first.
+    Lifo,
+    /// Lots that cost the most first, so the gains are the smallest.
+    Hifo,
+    /// The lots with these ids, in this order.
+    SpecificId(Vec<usize>),
+    /// Lots sold at a loss first, the biggest loss first, then lots held long
+    /// enough for long-term gains, then the rest, each by the smallest gain.
+    TaxEfficient,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Term {
+    Short,
+    Long,
+}
+
+/// The part of a sale that came out of one lot.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Realized {
+    pub ticker: String,
+    pub lot: usize,
+    pub acquired: i64,
+    pub sold: i64,
+    pub quantity: f64,
+    /// Cash received for this part, after costs.
+    pub proceeds: Discrete<USD>,
+    pub cost_basis: Discrete<USD>,
+}
+
+impl Realized {
+    pub fn gain(&self) -> Result<Discrete<USD>> {
+        Ok(self.proceeds.checked_sub(self.cost_basis)?)
+    }
+
+    pub fn term(&self) -> Term {
+        term(self.acquired, self.sold)
+    }
+}
+
+/// Tax-aware rebalancing: sales pick lots with `LotMethod::TaxEfficient`, plans
+/// estimate the tax on the gains they realize, and sells that would realize
+/// short-term gains can wait.
+#[derive(Debug, Clone, Copy, PartialEq, Default, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct TaxPolicy {
+    /// Tax rate on short-term gains, 0.35 for 35%.
+    pub short_term_rate: f64,
+    pub long_term_rate: f64,
+    /// Sells are cut down to the lots that realize no short-term gain while the
+    /// position is no further than this from its target weight.
+    pub defer_short_term: Option<f64>,
+}
+
+impl TaxPolicy {
+    /// Whether the rates are between 0 and 1 and the tolerance non-negative.
+    pub fn is_valid(&self) -> bool {
+        let rates = [self.short_term_rate, self.long_term_rate];
+        rates.iter().all(|rate| (0.0..=1.0).contains(rate))
+            && self
+                .defer_short_term
+                .is_none_or(|tolerance| tolerance.is_finite() && tolerance >= 0.0)
+    }
+
+    /// Tax on `gains`, negative when losses would offset other gains.
+    pub fn tax_on(&self, gains: &Gains) -> Result<Discrete<USD>> {
+        let tax = self.short_term_rate * gains.short_term.to_f64()
+            + self.long_term_rate * gains.long_term.to_f64();
+        Ok(Discrete::try_from_dense(Dense::from(tax))?)
+    }
+}
+
+/// Realized gains by holding period, losses negative.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct Gains {
+    pub short_term: Discrete<USD>,
+    pub long_term: Discrete<USD>,
+}
+
+impl Gains {
+    pub fn zero() -> Gains {
+        Gains {
+            short_term: Discrete::zero(),
+            long_term: Discrete::zero(),
+        }
+    }
+
+    pub fn add(&mut self, term: Term, gain: Discrete<USD>) -> Result<()> {
+        match term {
+            Term::Short => self.short_term = self.short_term.checked_add(gain)?,
+            Term::Long => self.long_term = self.long_term.checked_add(gain)?,
+        }
+        Ok(())
+    }
+
+    pub fn total(&self) -> Result<Discrete<USD>> {
+        Ok(self.short_term.checked_add(self.long_term)?)
+    }
+}
+
+impl Portfolio {
+    /// Lots of `ticker` still held, in the order they were bought.
+    pub fn lots(&self, ticker: &str) -> &[Lot] {
+        self.lots.get(ticker).map_or(&[], |lots| lots.as_slice())
+    }
+
+    /// Gains realized by every sale so far.
+    pub fn realized_gains(&self) -> Result<Gains> {
+        let mut gains = Gains::zero();
+        for realized in &self.realized {
+            gains.add(realized.term(), realized.gain()?)?;
+        }
+        Ok(gains)
+    }
+
+    /// Lots sales take their quantity from: `TaxEfficient` under a `tax_policy`,
+    /// `lot_method` otherwise.
+    pub fn sale_method(&self) -> LotMethod {
+        match self.tax_policy {
+            Some(_) => LotMethod::TaxEfficient,
+            None => self.lot_method.clone(),
+        }
+    }
+
+    /// Gains a sale of `quantity` of `ticker` for `proceeds` would realize at `now`.
+    pub fn estimate_gains(
+        &self,
+        ticker: &str,
+        quantity: f64,
+        proceeds: Discrete<USD>,
+        now: i64,
+    ) -> Result<Gains> {
+        let mut lots = self.lots(ticker).to_vec();
+        let mut gains = Gains::zero();
+        for (lot, proceeds) in relieve(&mut lots, quantity, proceeds, &self.sale_method(), now)? {
+            let term = term(lot.acquired, now);
+            gains.add(term, proceeds.checked_sub(cost_basis(&lot)?)?)?;
+        }
+        Ok(gains)
+    }
+
+    /// Quantity of `ticker` that sells at `price` (in dollars) without realizing
+    /// a short-term gain.
+    pub fn short_term_free(&self, ticker: &str, price: f64, now: i64) -> f64 {
+        self.lots(ticker)
+            .iter()
+            .filter(|lot| term(lot.acquired, now) == Term::Long || lot.cost_per_unit >= price)
+            .map(|lot| lot.quantity)
+            .sum()
+    }
+}
+
+fn term(acquired: i64, sold: i64) -> Term {
+    if sold - acquired > LONG_TERM_SECONDS {
+        Term::Long
+    } else {
+        Term::Short
+    }
+}
+
+/// Takes `quantity` out of `lots` in the order of `method` for a sale at `sold`,
+/// and splits the `proceeds` of the sale over the lots it came from. Nothing
+/// changes when the lots can't cover the quantity or a lot is named twice.
+pub(crate) fn relieve(
+    lots: &mut Vec<Lot>,
+    quantity: f64,
+    proceeds: Discrete<USD>,
+    method: &LotMethod,
+    sold: i64,
+) -> Result<Vec<(Lot, Discrete<USD>)>> {
+    let order: Vec<usize> = match method {
+        LotMethod::Fifo => (0..lots.len()).collect(),
+        LotMethod::Lifo => (0..lots.len()).rev().collect(),
+        LotMethod::Hifo => {
+            let mut order: Vec<usize> = (0..lots.len()).collect();
+            order.sort_by(|&a, &b| lots[b].cost_per_unit.total_cmp(&lots[a].cost_per_unit));
+            order
+        }
+        LotMethod::SpecificId(ids) => {
+            let mut order = Vec::new();
+            for (n, id) in ids.iter().enumerate() {
+                if ids[..n].contains(id) {
+                    return Err(anyhow::anyhow!("Lot {} is named twice", id));
+                }
+                let i = lots
+                    .iter()
+                    .position(|lot| lot.id == *id)
+                    .ok_or_else(|| anyhow::anyhow!("No lot {}", id))?;
+                order.push(i);
+            }
+            order
+        }
+        LotMethod::TaxEfficient => {
+            let price = proceeds.to_f64() / quantity;
+            // losses, then long-term gains, then short-term gains
+            let rank = |lot: &Lot| {
+                let gain = price - lot.cost_per_unit;
+                let class = match (gain < 0.0, term(lot.acquired, sold)) {
+                    (true, _) => 0,
+                    (false, Term::Long) => 1,
+                    (false, Term::Short) => 2,
+                };
+                (class, gain)
+            };
+            let mut order: Vec<usize> = (0..lots.len()).collect();
+            order.sort_by(|&a, &b| {
+                let (a, b) = (rank(&lots[a]), rank(&lots[b]));
+                a.0.cmp(&b.0).then(a.1.total_cmp(&b.1))
+            });
+            order
+        }
+    };
+
+    // parts come out of a copy as they are taken, so nothing is taken twice and
+    // the lots are only changed once the sale is covered
+    let mut remaining = lots.clone();
+    let mut taken = Vec::new();
+    let mut left = quantity;
+    for i in order {
+        if left <= 1e-9 {
+            break;
+        }
+        let part = remaining[i].quantity.min(left);
+        if part <= 0.0 {
+            continue;
+        }
+        remaining[i].quantity -= part;
+        left -= part;
+        taken.push(Lot {
+            quantity: part,
+            ..remaining[i].clone()
+        });
+    }
+    if left > 1e-9 {
+        return Err(anyhow::anyhow!(
+            "Lots cover {} of the {} sold",
+            quantity - left,
+            quantity
+        ));
+    }
+
+    // the last part gets what rounding leaves, so the parts add up to the proceeds
+    let mut parts = Vec::new();
+    let mut proceeds_left = proceeds;
+    let count = taken.len();
+    for (n, lot) in taken.into_iter().enumerate() {
+        let share = if n + 1 == count {
+            proceeds_left
+        } else {
+            Discrete::try_from_dense(proceeds.to_dense() * (lot.quantity / quantity))?
+        };
+        proceeds_left = proceeds_left.checked_sub(share)?;
+        parts.push((lot, share));
+    }
+    remaining.retain(|lot| lot.quantity > 1e-9);
+    *lots = remaining;
+    Ok(parts)
+}
+
+/// What `quantity` of `lot` cost, to the cent.
+pub(crate) fn cost_basis(lot: &Lot) -> Result<Discrete<USD>> {
+    Ok(Discrete::try_from_dense(Dense::from(
+        lot.cost_per_unit * lot.quantity,
+    ))?)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use super::*;
+    use crate::assets::Asset;
+    use crate::price_source::InMemorySource;
+
+    const YEAR: i64 = LONG_TERM_SECONDS;
+
+    // 10 AAA bought at 100, 150 and 120, then priced at 200 a year and 50 days
+    // after the first buy
+    async fn portfolio() -> Portfolio {
+        let source = InMemorySource::new().with_price("AAA", 100.0);
+        let mut portfolio = Portfolio::builder()
+            .price_source(Arc::new(source))
+            .add_asset("AAA", 0.0)
+            .cash(Discrete::new(1_000_000))
+            .build()
+            .await
+            .unwrap();
+        for (day, price) in [(0, 100.0), (100, 150.0), (200, 120.0)] {
+            portfolio.clock = Some(day * 86_400);
+            portfolio.set_price("AAA", price).unwrap();
+            portfolio.paper_buy(10.0, "AAA").unwrap();
+        }
+        portfolio.clock = Some(YEAR + 50 * 86_400);
+        portfolio.set_price("AAA", 200.0).unwrap();
+        portfolio
+    }
+
+    fn costs(portfolio: &Portfolio) -> Vec<f64> {
+        portfolio
+            .lots("AAA")
+            .iter()
+            .map(|lot| lot.cost_per_unit)
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn test_fifo_sells_the_oldest_lots() {
+        let mut portfolio = portfolio().await;
+        portfolio.paper_sell(15.0, "AAA").unwrap();
+        assert_eq!(costs(&portfolio), [150.0, 120.0]);
+        assert_eq!(portfolio.lots("AAA")[0].quantity, 5.0);
+
+        let gains = portfolio.realized_gains().unwrap();
+        // 10 at 100 held over a year, 5 at 150 not
+        assert_eq!(gains.long_term, Discrete::new(100_000));
+        assert_eq!(gains.short_term, Discrete::new(25_000));
+    }
+
+    #[tokio::test]
+    async fn test_lifo_and_hifo() {
+        let mut portfolio = portfolio().await;
+        portfolio.lot_method = LotMethod::Lifo;
+        portfolio.paper_sell(10.0, "AAA").unwrap();
+        assert_eq!(costs(&portfolio), [100.0, 150.0]);
+
+        let mut portfolio = self::portfolio().await;
+        portfolio.lot_method = LotMethod::Hifo;
+        portfolio.paper_sell(10.0, "AAA").unwrap();
+        assert_eq!(costs(&portfolio), [100.0, 120.0]);
+        let gains = portfolio.realized_gains().unwrap();
+        assert_eq!(gains.short_term, Discrete::new(50_000));
+        assert_eq!(gains.long_term, Discrete::zero());
+    }
+
+    #[tokio::test]
+    async fn test_tax_efficient_sells_losses_then_long_term() {
+        let mut portfolio = portfolio().await;
+        // a loss on the lot at 150, a long-term gain on the one at 100
+        portfolio.set_price("AAA", 130.0).unwrap();
+        portfolio.lot_method = LotMethod::TaxEfficient;
+        portfolio.paper_sell(15.0, "AAA").unwrap();
+        assert_eq!(costs(&portfolio), [100.0, 120.0]);
+        assert_eq!(portfolio.lots("AAA")[0].quantity, 5.0);
+
+        let gains = portfolio.realized_gains().unwrap();
+        assert_eq!(gains.short_term, Discrete::new(-20_000));
+        assert_eq!(gains.long_term, Discrete::new(15_000));
+    }
+
+    #[tokio::test]
+    async fn test_specific_lots() {
+        let mut portfolio = portfolio().await;
+        let ids: Vec<usize> = portfolio.lots("AAA").iter().map(|lot| lot.id).collect();
+        portfolio
+            .paper_sell_lots(12.0, "AAA", LotMethod::SpecificId(vec![ids[2], ids[0]]))
+            .unwrap();
+        assert_eq!(costs(&portfolio), [100.0, 150.0]);
+        assert_eq!(portfolio.lots("AAA")[0].quantity, 8.0);
+        assert_eq!(portfolio.realized.len(), 2);
+
+        // lots that don't cover the sale leave everything as it was
+        let held = portfolio.position("AAA").unwrap().amount_held();
+        assert!(portfolio
+            .paper_sell_lots(9.0, "AAA", LotMethod::SpecificId(vec![ids[0]]))
+            .is_err());
+        assert_eq!(portfolio.position("AAA").unwrap().amount_held(), held);
+        assert_eq!(portfolio.realized.len(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_a_lot_named_twice_is_refused() {
+        let mut portfolio = portfolio().await;
+        let lots = portfolio.lots("AAA").to_vec();
+        let id = lots[0].id;
+        assert!(portfolio
+            .paper_sell_lots(
+                lots[0].quantity + 1.0,
+                "AAA",
+                LotMethod::SpecificId(vec![id, id])
+            )
+            .is_err());
+        assert_eq!(portfolio.lots("AAA"), lots.as_slice());
+        assert!(portfolio.realized.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_lots_replay() {
+        let mut portfolio = portfolio().await;
+        portfolio.lot_method = LotMethod::Hifo;
+        portfolio.paper_sell(12.0, "AAA").unwrap();
+        // the method used is part of the sale, not the setting at replay time
+        portfolio.lot_method = LotMethod::Fifo;
+        let replayed = portfolio.replay().unwrap();
+        assert_eq!(replayed.lots, portfolio.lots);
+        assert_eq!(replayed.realized, portfolio.realized);
+    }
+}